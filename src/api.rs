@@ -1,9 +1,12 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::ops::Range;
+
 use serde::{Deserialize, Serialize};
 
-use crate::data::{AGGREGATE_BUCKET, AggregateTagEvent, Compress, Cookie, Partial, ProductInfo, UserAction, UserProfile};
+use crate::data::{AGGREGATE_BUCKET, AggregateDimension, AggregateTagEvent, Compress, Cookie, CubeKey, DistinctSketch, Granularity, Partial, PartialFields, ProductInfo, TopDimension, TopKey, TopMetric, UserAction, UserProfile};
 use crate::data::time::TimeRange;
-use crate::database::Compressor;
-use crate::endpoints::GetAggregateApiRequest;
+use crate::endpoints::{GetAggregateApiRequest, GetTopApiRequest};
 
 pub const MAX_TAGS: usize = 200;
 
@@ -34,27 +37,181 @@ pub struct AddAggregateRequest {
 }
 
 pub struct GetAggregateRequest {
+	/// Range of response buckets, in units of `granularity`
 	pub time_range: TimeRange,
+	pub granularity: Granularity,
 	pub action: UserAction,
 	pub origin: Option<u16>,
 	pub brand_id: Option<u16>,
 	pub category_id: Option<u16>,
+	/// Dimensions to split every bucket by, empty for a plain query
+	pub group_by: Vec<AggregateDimension>,
+	/// Set when one of the filters names a value that was never ingested, so nothing can match
+	pub has_unknown_value: bool,
+	/// Whether distinct users are queried, the sketches are merged only then
+	pub distinct_users: bool,
+}
+
+impl GetAggregateRequest {
+	pub fn bucket_count(&self) -> usize {
+		(self.time_range.end - self.time_range.start).max(0) as usize
+	}
+	
+	/// Range of stored tier buckets that make up the given response bucket
+	pub fn tier_range(&self, bucket: i64) -> Range<i64> {
+		let tier_buckets = self.granularity.tier_buckets();
+		bucket * tier_buckets..(bucket + 1) * tier_buckets
+	}
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct AggregateBucket {
 	pub sum: u64,
 	pub count: u64,
+	pub min: Option<i32>,
+	pub max: Option<i32>,
+	pub distinct_users: DistinctSketch,
+}
+
+impl AggregateBucket {
+	/// Counts the tag, its user is added to the sketch of the full cell only
+	pub fn add(&mut self, tag: &AggregateTagEvent) {
+		self.count += 1;
+		self.sum += tag.price as u64;
+		self.min = Some(self.min.map_or(tag.price, |x| x.min(tag.price)));
+		self.max = Some(self.max.map_or(tag.price, |x| x.max(tag.price)));
+	}
+	
+	pub fn merge(&mut self, other: &AggregateBucket) {
+		self.count += other.count;
+		self.sum += other.sum;
+		self.min = self.min.into_iter().chain(other.min).min();
+		self.max = self.max.into_iter().chain(other.max).max();
+		self.distinct_users.merge(&other.distinct_users);
+	}
+	
+	pub fn avg(&self) -> Option<f64> {
+		match self.count {
+			0 => None,
+			count => Some(self.sum as f64 / count as f64),
+		}
+	}
 }
 
 pub struct GetAggregateResponse {
 	pub aggregates: Vec<AggregateBucket>,
 }
 
+impl GetAggregateResponse {
+	pub fn empty(request: &GetAggregateRequest) -> Self {
+		Self {
+			aggregates: (0..request.bucket_count()).map(|_| AggregateBucket::default()).collect(),
+		}
+	}
+}
+
+pub struct AggregateGroup {
+	pub key: CubeKey,
+	pub bucket: AggregateBucket,
+}
+
+impl AggregateGroup {
+	/// Groups ordered by key, so responses are stable between calls
+	pub fn sorted(groups: HashMap<CubeKey, AggregateBucket>) -> Vec<AggregateGroup> {
+		let mut groups: Vec<AggregateGroup> = groups.into_iter()
+			.map(|(key, bucket)| AggregateGroup { key, bucket })
+			.collect();
+		groups.sort_by_key(|group| group.key.encode());
+		groups
+	}
+}
+
+pub struct GetGroupedAggregateResponse {
+	/// Groups with any events, for every bucket of the requested range
+	pub groups: Vec<Vec<AggregateGroup>>,
+}
+
+/// Totals of a single ranked value of a `/top` query
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TopCounter {
+	pub count: u64,
+	pub sum: u64,
+}
+
+impl TopCounter {
+	pub fn add(&mut self, tag: &AggregateTagEvent) {
+		self.count += 1;
+		self.sum += tag.price as u64;
+	}
+	
+	pub fn merge(&mut self, other: &TopCounter) {
+		self.count += other.count;
+		self.sum += other.sum;
+	}
+	
+	pub fn value(&self, metric: TopMetric) -> u64 {
+		match metric {
+			TopMetric::Count => self.count,
+			TopMetric::Sum => self.sum,
+		}
+	}
+}
+
+impl From<&AggregateBucket> for TopCounter {
+	fn from(value: &AggregateBucket) -> Self {
+		Self {
+			count: value.count,
+			sum: value.sum,
+		}
+	}
+}
+
+pub struct GetTopRequest {
+	/// Range of minutes
+	pub time_range: TimeRange,
+	pub action: UserAction,
+	pub dimension: TopDimension,
+	pub metric: TopMetric,
+	pub n: usize,
+}
+
+impl TryFrom<&GetTopApiRequest> for GetTopRequest {
+	type Error = anyhow::Error;
+	
+	fn try_from(value: &GetTopApiRequest) -> anyhow::Result<Self> {
+		Ok(Self {
+			time_range: TimeRange::new(value.time_range.as_str())
+				.map(|t| TimeRange { start: t.start / AGGREGATE_BUCKET, end: t.end / AGGREGATE_BUCKET })?,
+			action: UserAction::try_from(value.action.as_str())?,
+			dimension: TopDimension::try_from(value.dimension.as_str())?,
+			metric: TopMetric::try_from(value.metric.as_deref().unwrap_or("count"))?,
+			n: value.n.unwrap_or(10),
+		})
+	}
+}
+
+pub struct GetTopResponse {
+	/// At most `n` values, highest metric first
+	pub top: Vec<(TopKey, TopCounter)>,
+}
+
+impl GetTopResponse {
+	/// Ranks the values by the requested metric, ties broken by id so responses are stable
+	pub fn ranked(counters: HashMap<u64, TopCounter>, request: &GetTopRequest) -> Self {
+		let mut top: Vec<(TopKey, TopCounter)> = counters.into_iter()
+			.map(|(id, counter)| (TopKey { dimension: request.dimension, id }, counter))
+			.collect();
+		top.sort_by_key(|(key, counter)| (Reverse(counter.value(request.metric)), key.id));
+		top.truncate(request.n);
+		Self { top }
+	}
+}
+
 pub struct GetAggregateRequestCompressedData {
 	pub origin_id: Option<u16>,
 	pub brand_id: Option<u16>,
 	pub category_id: Option<u16>,
+	pub has_unknown_value: bool,
 }
 
 #[derive(Clone)]
@@ -64,6 +221,14 @@ pub struct PartialGetAggregateRequestCompressedData {
 	pub category_id: Partial<Option<String>, Option<u16>>,
 }
 
+impl PartialFields for PartialGetAggregateRequestCompressedData {
+	fn same_fields(&self) -> Vec<bool> {
+		[&self.origin_id, &self.brand_id, &self.category_id].into_iter()
+			.filter_map(|field| field.is_same_value())
+			.collect()
+	}
+}
+
 impl From<GetAggregateApiRequest> for PartialGetAggregateRequestCompressedData {
 	fn from(value: GetAggregateApiRequest) -> Self {
 		Self {
@@ -79,16 +244,26 @@ impl Compress for GetAggregateRequest {
 	type CompressedData = GetAggregateRequestCompressedData;
 	type PartialCompressedData = PartialGetAggregateRequestCompressedData;
 	
-	async fn compress<T: Compressor<Self>>(value: &Self::From, compressor: &T) -> anyhow::Result<Self> {
-		let compressed = compressor.compress(value).await;
+	fn from_compressed(value: &Self::From, compressed: Self::CompressedData) -> anyhow::Result<Self> {
+		let granularity = Granularity::try_from(value.bucket.as_deref().unwrap_or("1m"))?;
+		let mut group_by = vec![];
+		for dimension in value.group_by.iter().flat_map(|x| x.split(',')).filter(|x| !x.is_empty()) {
+			let dimension = AggregateDimension::try_from(dimension)?;
+			if !group_by.contains(&dimension) {
+				group_by.push(dimension);
+			}
+		}
 		Ok(Self {
 			time_range: TimeRange::new(value.time_range.as_str())
-				.map(|t| TimeRange { start: t.start / AGGREGATE_BUCKET, end: t.end / AGGREGATE_BUCKET })
-				.unwrap(),
-			action: UserAction::try_from(value.action.as_str()).unwrap(),
+				.map(|t| TimeRange { start: t.start / granularity.millis(), end: t.end / granularity.millis() })?,
+			granularity,
+			action: UserAction::try_from(value.action.as_str())?,
 			origin: compressed.origin_id,
 			brand_id: compressed.brand_id,
 			category_id: compressed.category_id,
+			group_by,
+			has_unknown_value: compressed.has_unknown_value,
+			distinct_users: false,
 		})
 	}
 }
\ No newline at end of file
@@ -1,7 +1,7 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-use crate::database::{Compressor, Decompressor};
+use crate::database::{Compressor, DbResult, Decompressor};
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub enum Partial<T, V> {
@@ -55,12 +55,42 @@ impl<T, V> Partial<T, V> {
 	}
 }
 
+impl<T, V> Partial<Option<T>, Option<V>> {
+	/// Like `is_same`, `None` for an optional field without a value
+	pub fn is_same_value(&self) -> Option<bool> {
+		match &self {
+			Partial::Same(Some(_)) => Some(true),
+			Partial::Changed(Some(_)) => Some(false),
+			_ => None,
+		}
+	}
+}
+
+/// Dictionary fields of a partially (de)compressed value
+pub trait PartialFields {
+	/// Whether each field is still `Same`, optional fields without a value are left out
+	fn same_fields(&self) -> Vec<bool>;
+}
+
 pub trait Compress where Self: Sized, Self::PartialCompressedData: From<Self::From>, Self::From: Clone {
 	type From;
 	type CompressedData;
 	type PartialCompressedData;
 	
-	async fn compress<T: Compressor<Self>>(value: &Self::From, compressor: &T) -> Result<Self>;
+	/// Builds the value once all of its dictionary fields are compressed
+	fn from_compressed(value: &Self::From, compressed: Self::CompressedData) -> Result<Self>;
+	
+	async fn compress<T: Compressor<Self>>(value: &Self::From, compressor: &T) -> Result<Self> {
+		Self::from_compressed(value, compressor.compress(value).await?)
+	}
+	
+	async fn compress_batch<T: Compressor<Self>>(values: &[Self::From], compressor: &T) -> Vec<Result<Self>> {
+		compressor.compress_batch(values).await
+			.into_iter()
+			.zip(values)
+			.map(|(compressed, value)| Self::from_compressed(value, compressed?))
+			.collect()
+	}
 }
 
 pub trait Decompress where Self: Sized, Self::PartialDecompressedData: From<Self>, Self: Clone {
@@ -69,5 +99,5 @@ pub trait Decompress where Self: Sized, Self::PartialDecompressedData: From<Self
 	type PartialDecompressedData;
 	type AdditionalData;
 	
-	async fn decompress<T: Decompressor<Self>>(&self, decompressor: &T, additional_data: Self::AdditionalData) -> Self::Type;
+	async fn decompress<T: Decompressor<Self>>(&self, decompressor: &T, additional_data: Self::AdditionalData) -> DbResult<Self::Type>;
 }
@@ -0,0 +1,560 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::api::MAX_TAGS;
+use crate::database::AggregateRetention;
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Storage behind the API
+#[derive(Deserialize, Serialize, ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+	/// Everything held in memory of this process
+	Local,
+	/// Aerospike, with the dictionaries cached in memory
+	CachedAerospike,
+	/// Aerospike only
+	Aerospike,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+	pub bind_address: SocketAddr,
+	/// Number of actix workers, one per physical core when not set
+	pub workers: Option<usize>,
+}
+
+impl Default for ServerConfig {
+	fn default() -> Self {
+		Self {
+			bind_address: SocketAddr::from(([10, 112, 103, 101], 8083)),
+			workers: None,
+		}
+	}
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AerospikeSets {
+	pub tags: String,
+	pub minute: String,
+	pub hour: String,
+	pub day: String,
+	pub mappings: String,
+}
+
+impl Default for AerospikeSets {
+	fn default() -> Self {
+		Self {
+			tags: String::from("tags"),
+			minute: String::from("minute_tags"),
+			hour: String::from("hour_tags"),
+			day: String::from("day_tags"),
+			mappings: String::from("mappings"),
+		}
+	}
+}
+
+/// Policy of every Aerospike operation, unset values keep the client defaults
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AerospikePolicy {
+	pub timeout_ms: Option<u64>,
+	pub max_retries: Option<usize>,
+	pub sleep_between_retries_ms: Option<u64>,
+	/// Time to live of written records, the namespace default when not set
+	pub expiration_secs: Option<u32>,
+	pub durable_delete: bool,
+}
+
+impl AerospikePolicy {
+	pub fn timeout(&self) -> Option<Duration> {
+		self.timeout_ms.map(Duration::from_millis)
+	}
+	
+	pub fn sleep_between_retries(&self) -> Option<Duration> {
+		self.sleep_between_retries_ms.map(Duration::from_millis)
+	}
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AerospikeConfig {
+	/// Comma separated `host:port` seeds
+	pub hosts: String,
+	pub namespace: String,
+	/// Records every minute, hour and day bucket of the aggregates is split into, so no single
+	/// record gets every write or outgrows the record size limit. Changing it hides the
+	/// aggregates written before.
+	pub tier_shards: u32,
+	pub sets: AerospikeSets,
+	pub policy: AerospikePolicy,
+}
+
+impl Default for AerospikeConfig {
+	fn default() -> Self {
+		Self {
+			hosts: String::from("127.0.0.1:3000"),
+			namespace: String::from("test"),
+			tier_shards: 16,
+			sets: AerospikeSets::default(),
+			policy: AerospikePolicy::default(),
+		}
+	}
+}
+
+/// What `CachedDB` does with a write once its shard of the write-behind queue is full
+#[derive(Deserialize, ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+	/// Wait for the writer to make room
+	Block,
+	/// Give up on the oldest queued write
+	DropOldest,
+	/// Fail the request with 503
+	Reject,
+}
+
+/// Write-behind pipeline of `CachedDB`, every shard is a bounded queue drained by its own writer
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriteQueueConfig {
+	/// The writes of a cookie always go through the same shard, so they stay in order
+	pub shards: usize,
+	/// Writes held by every shard
+	pub capacity: usize,
+	/// Writes taken from a shard at once, the user events of a cookie among them are sent together
+	pub batch_size: usize,
+	/// Retries of a write that failed with a transient error, with the backoff doubling each time
+	pub max_retries: u32,
+	pub initial_backoff_ms: u64,
+	pub max_backoff_ms: u64,
+	pub overflow: OverflowPolicy,
+}
+
+impl Default for WriteQueueConfig {
+	fn default() -> Self {
+		Self {
+			shards: 4,
+			capacity: 10000,
+			batch_size: 128,
+			max_retries: 5,
+			initial_backoff_ms: 50,
+			max_backoff_ms: 5000,
+			overflow: OverflowPolicy::Block,
+		}
+	}
+}
+
+impl WriteQueueConfig {
+	pub fn initial_backoff(&self) -> Duration {
+		Duration::from_millis(self.initial_backoff_ms)
+	}
+	
+	pub fn max_backoff(&self) -> Duration {
+		Duration::from_millis(self.max_backoff_ms)
+	}
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+	/// Serve the profiles of the cookies read often from memory, only correct while every tag
+	/// of a cookie goes through this instance
+	pub local_profiles: bool,
+	/// Reads of a cookie after which its profile is kept in memory
+	pub min_reads: u32,
+	/// Cookies tracked at most, the least recently read ones are dropped first
+	pub max_profiles: usize,
+	/// A cookie not read for this long is dropped
+	pub profile_ttl_secs: u64,
+	/// A cached profile is read from the remote database again once it is this old, however
+	/// often it is read
+	pub profile_max_age_secs: u64,
+}
+
+impl Default for CacheConfig {
+	fn default() -> Self {
+		Self {
+			local_profiles: false,
+			min_reads: 2,
+			max_profiles: 100000,
+			profile_ttl_secs: 300,
+			profile_max_age_secs: 60,
+		}
+	}
+}
+
+impl CacheConfig {
+	pub fn profile_ttl(&self) -> Duration {
+		Duration::from_secs(self.profile_ttl_secs)
+	}
+	
+	pub fn profile_max_age(&self) -> Duration {
+		Duration::from_secs(self.profile_max_age_secs)
+	}
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+	/// Time the requests in flight get to finish once the server stops accepting new ones
+	pub grace_period_secs: u64,
+	/// Time the write-behind queue gets to drain, the events still queued after it are lost
+	pub drain_timeout_secs: u64,
+	/// Print how many events were lost and exit with status 3 if there were any
+	pub report_lost_events: bool,
+}
+
+impl Default for ShutdownConfig {
+	fn default() -> Self {
+		Self {
+			grace_period_secs: 30,
+			drain_timeout_secs: 30,
+			report_lost_events: false,
+		}
+	}
+}
+
+impl ShutdownConfig {
+	pub fn drain_timeout(&self) -> Duration {
+		Duration::from_secs(self.drain_timeout_secs)
+	}
+}
+
+/// Binary snapshot of the whole `LocalDB` of the local backend, restored on boot
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+	pub path: PathBuf,
+	/// Time between two snapshots, a crash loses what was written since the last one
+	pub interval_secs: u64,
+}
+
+impl Default for SnapshotConfig {
+	fn default() -> Self {
+		Self {
+			path: PathBuf::from("local.snapshot"),
+			interval_secs: 300,
+		}
+	}
+}
+
+impl SnapshotConfig {
+	pub fn interval(&self) -> Duration {
+		Duration::from_secs(self.interval_secs)
+	}
+}
+
+/// When the write-ahead log is synced to disk
+#[derive(Deserialize, ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FsyncPolicy {
+	/// Before a record is acknowledged, together with the records appended meanwhile, nothing
+	/// acknowledged is lost
+	Always,
+	/// At most every `fsync_interval_ms`, a machine crash loses the records of the last interval
+	Periodic,
+	/// Left to the operating system, only a process crash loses nothing
+	Never,
+}
+
+/// Write-ahead log of the local backend, replayed on boot after the snapshot
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct WalConfig {
+	pub enabled: bool,
+	/// Directory of the segment files
+	pub dir: PathBuf,
+	/// Size a segment grows to before the log moves on to a new one
+	pub segment_bytes: u64,
+	pub fsync: FsyncPolicy,
+	pub fsync_interval_ms: u64,
+}
+
+impl Default for WalConfig {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			dir: PathBuf::from("wal"),
+			segment_bytes: 64 * 1024 * 1024,
+			fsync: FsyncPolicy::Periodic,
+			fsync_interval_ms: 1000,
+		}
+	}
+}
+
+impl WalConfig {
+	pub fn fsync_interval(&self) -> Duration {
+		Duration::from_millis(self.fsync_interval_ms)
+	}
+}
+
+/// Number of buckets kept in every aggregate tier of `LocalDB`
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+	pub minutes: usize,
+	pub hours: usize,
+	pub days: usize,
+	/// Minutes an aggregate event may lie past both the newest minute held and the clock
+	pub max_skew_minutes: usize,
+}
+
+impl Default for RetentionConfig {
+	fn default() -> Self {
+		let retention = AggregateRetention::default();
+		Self {
+			minutes: retention.minutes,
+			hours: retention.hours,
+			days: retention.days,
+			max_skew_minutes: retention.max_skew_minutes,
+		}
+	}
+}
+
+impl From<RetentionConfig> for AggregateRetention {
+	fn from(value: RetentionConfig) -> Self {
+		Self {
+			minutes: value.minutes,
+			hours: value.hours,
+			days: value.days,
+			max_skew_minutes: value.max_skew_minutes,
+		}
+	}
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub server: ServerConfig,
+	pub backend: Backend,
+	pub aerospike: AerospikeConfig,
+	pub write_queue: WriteQueueConfig,
+	pub cache: CacheConfig,
+	pub shutdown: ShutdownConfig,
+	/// Tags of every action kept in a user profile
+	pub max_tags: usize,
+	pub retention: RetentionConfig,
+	/// Where the cached backend keeps its dictionaries between restarts, the local backend keeps
+	/// them in its snapshot
+	pub mappings_snapshot: PathBuf,
+	pub snapshot: SnapshotConfig,
+	pub wal: WalConfig,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			server: ServerConfig::default(),
+			backend: Backend::CachedAerospike,
+			aerospike: AerospikeConfig::default(),
+			write_queue: WriteQueueConfig::default(),
+			cache: CacheConfig::default(),
+			shutdown: ShutdownConfig::default(),
+			max_tags: MAX_TAGS,
+			retention: RetentionConfig::default(),
+			mappings_snapshot: PathBuf::from("mappings.json"),
+			snapshot: SnapshotConfig::default(),
+			wal: WalConfig::default(),
+		}
+	}
+}
+
+/// Command line flags, each one also settable through its environment variable.
+/// Both take precedence over the configuration file.
+#[derive(Parser, Debug, Default)]
+#[command(about = "Collects user tags and serves profiles and aggregates")]
+pub struct Args {
+	/// TOML configuration file, `config.toml` is read when present
+	#[arg(long, env = "RTB_CONFIG")]
+	pub config: Option<PathBuf>,
+	#[arg(long, env = "RTB_BIND_ADDRESS")]
+	pub bind_address: Option<SocketAddr>,
+	#[arg(long, env = "RTB_WORKERS")]
+	pub workers: Option<usize>,
+	#[arg(long, env = "RTB_BACKEND")]
+	pub backend: Option<Backend>,
+	#[arg(long, env = "AEROSPIKE_HOSTS")]
+	pub aerospike_hosts: Option<String>,
+	#[arg(long, env = "AEROSPIKE_NAMESPACE")]
+	pub aerospike_namespace: Option<String>,
+	#[arg(long, env = "RTB_WRITE_QUEUE_OVERFLOW")]
+	pub write_queue_overflow: Option<OverflowPolicy>,
+	#[arg(long, env = "RTB_LOCAL_PROFILES")]
+	pub local_profiles: Option<bool>,
+	#[arg(long, env = "RTB_MAX_TAGS")]
+	pub max_tags: Option<usize>,
+	#[arg(long, env = "RTB_RETENTION_MINUTES")]
+	pub retention_minutes: Option<usize>,
+	#[arg(long, env = "RTB_RETENTION_HOURS")]
+	pub retention_hours: Option<usize>,
+	#[arg(long, env = "RTB_RETENTION_DAYS")]
+	pub retention_days: Option<usize>,
+	#[arg(long, env = "RTB_RETENTION_MAX_SKEW_MINUTES")]
+	pub retention_max_skew_minutes: Option<usize>,
+	#[arg(long, env = "RTB_MAPPINGS_SNAPSHOT")]
+	pub mappings_snapshot: Option<PathBuf>,
+	#[arg(long, env = "RTB_SNAPSHOT_PATH")]
+	pub snapshot_path: Option<PathBuf>,
+	#[arg(long, env = "RTB_SNAPSHOT_INTERVAL_SECS")]
+	pub snapshot_interval_secs: Option<u64>,
+	#[arg(long, env = "RTB_WAL_DIR")]
+	pub wal_dir: Option<PathBuf>,
+	#[arg(long, env = "RTB_WAL_FSYNC")]
+	pub wal_fsync: Option<FsyncPolicy>,
+	#[arg(long, env = "RTB_DRAIN_TIMEOUT_SECS")]
+	pub drain_timeout_secs: Option<u64>,
+	/// Print how many events were lost on shutdown and exit with status 3 if there were any
+	#[arg(long, env = "RTB_REPORT_LOST_EVENTS")]
+	pub report_lost_events: bool,
+}
+
+impl Config {
+	/// Defaults, overridden by the configuration file, then by the environment and the flags
+	pub fn load(args: Args) -> Result<Self> {
+		let mut config = match &args.config {
+			Some(path) => Self::from_file(path)?,
+			None if Path::new(DEFAULT_CONFIG_PATH).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+			None => Self::default(),
+		};
+		config.apply(args);
+		config.validate()?;
+		Ok(config)
+	}
+	
+	pub fn from_file(path: &Path) -> Result<Self> {
+		let content = std::fs::read_to_string(path)
+			.with_context(|| format!("cannot read configuration file {}", path.display()))?;
+		Self::from_toml(&content)
+			.with_context(|| format!("invalid configuration file {}", path.display()))
+	}
+	
+	pub fn from_toml(content: &str) -> Result<Self> {
+		Ok(toml::from_str(content)?)
+	}
+	
+	fn apply(&mut self, args: Args) {
+		if let Some(bind_address) = args.bind_address {
+			self.server.bind_address = bind_address;
+		}
+		if let Some(workers) = args.workers {
+			self.server.workers = Some(workers);
+		}
+		if let Some(backend) = args.backend {
+			self.backend = backend;
+		}
+		if let Some(hosts) = args.aerospike_hosts {
+			self.aerospike.hosts = hosts;
+		}
+		if let Some(namespace) = args.aerospike_namespace {
+			self.aerospike.namespace = namespace;
+		}
+		if let Some(overflow) = args.write_queue_overflow {
+			self.write_queue.overflow = overflow;
+		}
+		if let Some(local_profiles) = args.local_profiles {
+			self.cache.local_profiles = local_profiles;
+		}
+		if let Some(max_tags) = args.max_tags {
+			self.max_tags = max_tags;
+		}
+		if let Some(minutes) = args.retention_minutes {
+			self.retention.minutes = minutes;
+		}
+		if let Some(hours) = args.retention_hours {
+			self.retention.hours = hours;
+		}
+		if let Some(days) = args.retention_days {
+			self.retention.days = days;
+		}
+		if let Some(max_skew_minutes) = args.retention_max_skew_minutes {
+			self.retention.max_skew_minutes = max_skew_minutes;
+		}
+		if let Some(mappings_snapshot) = args.mappings_snapshot {
+			self.mappings_snapshot = mappings_snapshot;
+		}
+		if let Some(path) = args.snapshot_path {
+			self.snapshot.path = path;
+		}
+		if let Some(interval_secs) = args.snapshot_interval_secs {
+			self.snapshot.interval_secs = interval_secs;
+		}
+		if let Some(dir) = args.wal_dir {
+			self.wal.dir = dir;
+		}
+		if let Some(fsync) = args.wal_fsync {
+			self.wal.fsync = fsync;
+		}
+		if let Some(drain_timeout_secs) = args.drain_timeout_secs {
+			self.shutdown.drain_timeout_secs = drain_timeout_secs;
+		}
+		if args.report_lost_events {
+			self.shutdown.report_lost_events = true;
+		}
+	}
+	
+	pub fn validate(&self) -> Result<()> {
+		if self.server.workers == Some(0) {
+			bail!("server.workers has to be at least 1");
+		}
+		if self.max_tags == 0 {
+			bail!("max_tags has to be at least 1");
+		}
+		for (name, value) in [("minutes", self.retention.minutes), ("hours", self.retention.hours), ("days", self.retention.days), ("max_skew_minutes", self.retention.max_skew_minutes)] {
+			if value == 0 {
+				bail!("retention.{} has to be at least 1", name);
+			}
+		}
+		for (name, value) in [("shards", self.write_queue.shards), ("capacity", self.write_queue.capacity), ("batch_size", self.write_queue.batch_size)] {
+			if value == 0 {
+				bail!("write_queue.{} has to be at least 1", name);
+			}
+		}
+		if self.write_queue.initial_backoff_ms > self.write_queue.max_backoff_ms {
+			bail!("write_queue.initial_backoff_ms cannot exceed write_queue.max_backoff_ms");
+		}
+		if self.snapshot.interval_secs == 0 {
+			bail!("snapshot.interval_secs has to be at least 1");
+		}
+		if self.mappings_snapshot == self.snapshot.path {
+			bail!("mappings_snapshot and snapshot.path have to be different files");
+		}
+		if self.wal.segment_bytes == 0 {
+			bail!("wal.segment_bytes has to be at least 1");
+		}
+		if self.wal.fsync == FsyncPolicy::Periodic && self.wal.fsync_interval_ms == 0 {
+			bail!("wal.fsync_interval_ms has to be at least 1 with the periodic policy");
+		}
+		if self.backend == Backend::Local {
+			return Ok(());
+		}
+		
+		if self.aerospike.hosts.split(',').any(|host| host.trim().is_empty()) {
+			bail!("aerospike.hosts has an empty entry in {:?}", self.aerospike.hosts);
+		}
+		if self.aerospike.namespace.is_empty() {
+			bail!("aerospike.namespace cannot be empty");
+		}
+		if self.aerospike.tier_shards == 0 {
+			bail!("aerospike.tier_shards has to be at least 1");
+		}
+		let sets = &self.aerospike.sets;
+		let names = [&sets.tags, &sets.minute, &sets.hour, &sets.day, &sets.mappings];
+		if names.iter().any(|name| name.is_empty()) {
+			bail!("aerospike.sets cannot have an empty name");
+		}
+		if names.iter().collect::<HashSet<_>>().len() != names.len() {
+			bail!("aerospike.sets have to be distinct");
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,199 @@
+use strum_macros::{EnumString, IntoStaticStr};
+
+use crate::api::GetAggregateRequest;
+use crate::data::{AggregateTagEvent, Decompress, Partial, PartialFields, UserAction};
+use crate::database::{DbResult, Decompressor};
+
+/// Dimensions of the cube an `/aggregates` query can filter or group by
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumString, IntoStaticStr)]
+pub enum AggregateDimension {
+	#[strum(serialize = "origin")]
+	Origin,
+	#[strum(serialize = "brand_id")]
+	BrandId,
+	#[strum(serialize = "category_id")]
+	CategoryId,
+}
+
+impl AggregateDimension {
+	pub const ALL: [AggregateDimension; 3] = [AggregateDimension::Origin, AggregateDimension::BrandId, AggregateDimension::CategoryId];
+}
+
+/// A single cell of the pre-aggregated counter cube. Every event is counted in all cells
+/// it belongs to, so a query with any combination of filters is a single cell lookup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CubeKey {
+	pub action: UserAction,
+	pub origin_id: Option<u16>,
+	pub brand_id: Option<u16>,
+	pub category_id: Option<u16>,
+}
+
+impl CubeKey {
+	const DIMENSION_BITS: u32 = 17;
+	const PRESENT_BIT: i64 = 1 << 16;
+	const DIMENSION_MASK: i64 = (1 << Self::DIMENSION_BITS) - 1;
+	
+	pub fn of_request(request: &GetAggregateRequest) -> Self {
+		Self {
+			action: request.action,
+			origin_id: request.origin,
+			brand_id: request.brand_id,
+			category_id: request.category_id,
+		}
+	}
+	
+	pub fn get(&self, dimension: AggregateDimension) -> Option<u16> {
+		match dimension {
+			AggregateDimension::Origin => self.origin_id,
+			AggregateDimension::BrandId => self.brand_id,
+			AggregateDimension::CategoryId => self.category_id,
+		}
+	}
+	
+	/// Whether this cell holds one group of a grouped query: it has the request's action and
+	/// filters, a value for every grouped dimension and no value for the remaining ones
+	pub fn is_group_of(&self, request: &GetAggregateRequest) -> bool {
+		let filter = Self::of_request(request);
+		self.action == filter.action && AggregateDimension::ALL.iter().all(|dimension| {
+			match request.group_by.contains(dimension) {
+				true => self.get(*dimension).is_some() && filter.get(*dimension).is_none_or(|x| self.get(*dimension) == Some(x)),
+				false => self.get(*dimension) == filter.get(*dimension),
+			}
+		})
+	}
+	
+	/// Whether this cell has a value for every dimension. Only these cells keep distinct users,
+	/// the sketch of any other cell is the merge of the full cells it covers.
+	pub fn is_full(&self) -> bool {
+		AggregateDimension::ALL.iter().all(|dimension| self.get(*dimension).is_some())
+	}
+	
+	/// Whether every event counted in `cell` is counted in this cell too
+	pub fn covers(&self, cell: &CubeKey) -> bool {
+		self.action == cell.action && AggregateDimension::ALL.iter()
+			.all(|dimension| self.get(*dimension).is_none_or(|x| cell.get(*dimension) == Some(x)))
+	}
+	
+	/// The group of a grouped query this full cell is counted in, if it matches the filters
+	pub fn group_of(&self, request: &GetAggregateRequest) -> Option<CubeKey> {
+		let filter = Self::of_request(request);
+		let value = |dimension: AggregateDimension| {
+			(request.group_by.contains(&dimension) || filter.get(dimension).is_some()).then_some(self.get(dimension)).flatten()
+		};
+		filter.covers(self).then(|| CubeKey {
+			action: self.action,
+			origin_id: value(AggregateDimension::Origin),
+			brand_id: value(AggregateDimension::BrandId),
+			category_id: value(AggregateDimension::CategoryId),
+		})
+	}
+	
+	/// Whether this cell counts a single value of the dimension, with every other dimension left out
+	pub fn has_only(&self, dimension: AggregateDimension) -> bool {
+		AggregateDimension::ALL.iter().all(|x| self.get(*x).is_some() == (*x == dimension))
+	}
+	
+	/// All 8 cells (every subset of origin, brand and category) the tag has to be counted in,
+	/// the full cell last
+	pub fn combinations(tag: &AggregateTagEvent) -> impl Iterator<Item = CubeKey> + '_ {
+		(0..8u8).map(move |mask| CubeKey {
+			action: tag.action,
+			origin_id: (mask & 1 != 0).then_some(tag.origin_id),
+			brand_id: (mask & 2 != 0).then_some(tag.brand_id),
+			category_id: (mask & 4 != 0).then_some(tag.category_id),
+		})
+	}
+	
+	/// Packs the key into an integer usable as an Aerospike map key
+	pub fn encode(&self) -> i64 {
+		fn encode_dimension(value: Option<u16>) -> i64 {
+			value.map(|x| CubeKey::PRESENT_BIT | x as i64).unwrap_or(0)
+		}
+		
+		((self.action as i64) << (3 * Self::DIMENSION_BITS))
+			| (encode_dimension(self.origin_id) << (2 * Self::DIMENSION_BITS))
+			| (encode_dimension(self.brand_id) << Self::DIMENSION_BITS)
+			| encode_dimension(self.category_id)
+	}
+	
+	pub fn decode(value: i64) -> Self {
+		fn decode_dimension(value: i64) -> Option<u16> {
+			let value = value & CubeKey::DIMENSION_MASK;
+			(value & CubeKey::PRESENT_BIT != 0).then_some(value as u16)
+		}
+		
+		Self {
+			action: match value >> (3 * Self::DIMENSION_BITS) {
+				0 => UserAction::VIEW,
+				_ => UserAction::BUY,
+			},
+			origin_id: decode_dimension(value >> (2 * Self::DIMENSION_BITS)),
+			brand_id: decode_dimension(value >> Self::DIMENSION_BITS),
+			category_id: decode_dimension(value),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CubeKeyDecompressedData {
+	pub origin_id: Option<String>,
+	pub brand_id: Option<String>,
+	pub category_id: Option<String>,
+}
+
+impl CubeKeyDecompressedData {
+	pub fn get(&self, dimension: AggregateDimension) -> Option<&String> {
+		match dimension {
+			AggregateDimension::Origin => self.origin_id.as_ref(),
+			AggregateDimension::BrandId => self.brand_id.as_ref(),
+			AggregateDimension::CategoryId => self.category_id.as_ref(),
+		}
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct PartialCubeKeyDecompressedData {
+	pub origin_id: Partial<Option<String>, Option<u16>>,
+	pub brand_id: Partial<Option<String>, Option<u16>>,
+	pub category_id: Partial<Option<String>, Option<u16>>,
+}
+
+impl PartialFields for PartialCubeKeyDecompressedData {
+	fn same_fields(&self) -> Vec<bool> {
+		[&self.origin_id, &self.brand_id, &self.category_id].into_iter()
+			.filter_map(|field| field.is_same_value())
+			.collect()
+	}
+}
+
+impl From<CubeKeyDecompressedData> for PartialCubeKeyDecompressedData {
+	fn from(value: CubeKeyDecompressedData) -> Self {
+		Self {
+			origin_id: Partial::Same(value.origin_id),
+			brand_id: Partial::Same(value.brand_id),
+			category_id: Partial::Same(value.category_id),
+		}
+	}
+}
+
+impl From<CubeKey> for PartialCubeKeyDecompressedData {
+	fn from(value: CubeKey) -> Self {
+		Self {
+			origin_id: Partial::Changed(value.origin_id),
+			brand_id: Partial::Changed(value.brand_id),
+			category_id: Partial::Changed(value.category_id),
+		}
+	}
+}
+
+impl Decompress for CubeKey {
+	type Type = CubeKeyDecompressedData;
+	type DecompressedData = CubeKeyDecompressedData;
+	type PartialDecompressedData = PartialCubeKeyDecompressedData;
+	type AdditionalData = ();
+	
+	async fn decompress<T: Decompressor<Self>>(&self, decompressor: &T, _additional_data: Self::AdditionalData) -> DbResult<Self::Type> {
+		decompressor.decompress(self).await
+	}
+}
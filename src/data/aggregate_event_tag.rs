@@ -1,25 +1,122 @@
+use std::ops::Range;
+
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use strum_macros::{EnumString, IntoStaticStr};
 use crate::api::ApiUserTag;
 
 use crate::data::common::UserAction;
-use crate::data::{Compress, Partial, time};
-use crate::database::Compressor;
+use crate::data::{Compress, Cookie, cookie_fingerprint, Partial, PartialFields, time, UserTagEvent};
 
 pub const AGGREGATE_BUCKET: i64 = 60000;
 
+/// Resolutions at which aggregates are stored, each one rolled up during ingestion
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggregateTier {
+	Minute,
+	Hour,
+	Day,
+}
+
+impl AggregateTier {
+	pub const ALL: [AggregateTier; 3] = [AggregateTier::Minute, AggregateTier::Hour, AggregateTier::Day];
+	
+	pub fn minutes(&self) -> i64 {
+		match self {
+			AggregateTier::Minute => 1,
+			AggregateTier::Hour => 60,
+			AggregateTier::Day => 24 * 60,
+		}
+	}
+	
+	/// Index of the tier bucket containing the given minute
+	pub fn of_minute(&self, minute: i64) -> i64 {
+		minute.div_euclid(self.minutes())
+	}
+	
+	/// Splits a range of minutes into as few whole tier buckets as possible, largest first
+	pub fn cover(minutes: Range<i64>) -> Vec<(AggregateTier, i64)> {
+		let mut buckets = vec![];
+		let mut minute = minutes.start;
+		while minute < minutes.end {
+			let tier = AggregateTier::ALL.into_iter()
+				.rev()
+				.find(|tier| minute.rem_euclid(tier.minutes()) == 0 && minute + tier.minutes() <= minutes.end)
+				.unwrap_or(AggregateTier::Minute);
+			buckets.push((tier, tier.of_minute(minute)));
+			minute += tier.minutes();
+		}
+		buckets
+	}
+}
+
+/// Bucket size of an `/aggregates` response
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumString, IntoStaticStr)]
+pub enum Granularity {
+	#[strum(serialize = "1m")]
+	Minute,
+	#[strum(serialize = "5m")]
+	FiveMinutes,
+	#[strum(serialize = "1h")]
+	Hour,
+	#[strum(serialize = "1d")]
+	Day,
+}
+
+impl Granularity {
+	pub fn millis(&self) -> i64 {
+		self.tier().minutes() * self.tier_buckets() * AGGREGATE_BUCKET
+	}
+	
+	/// Stored tier the buckets are rolled up from
+	pub fn tier(&self) -> AggregateTier {
+		match self {
+			Granularity::Minute | Granularity::FiveMinutes => AggregateTier::Minute,
+			Granularity::Hour => AggregateTier::Hour,
+			Granularity::Day => AggregateTier::Day,
+		}
+	}
+	
+	/// Number of tier buckets merged into one response bucket
+	pub fn tier_buckets(&self) -> i64 {
+		match self {
+			Granularity::FiveMinutes => 5,
+			_ => 1,
+		}
+	}
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct AggregateTagEvent {
+	pub product_id: u64,
 	pub origin_id: u16,
 	pub brand_id: u16,
 	pub category_id: u16,
 	pub timestamp: i64,
 	pub price: i32,
 	pub action: UserAction,
+	pub cookie_fingerprint: u64,
+}
+
+impl AggregateTagEvent {
+	/// Aggregate view of an already compressed tag, no dictionary lookups needed
+	pub fn of_user_tag(tag: &UserTagEvent, action: UserAction, cookie: &Cookie) -> Self {
+		Self {
+			product_id: tag.product_id,
+			origin_id: tag.origin_id,
+			brand_id: tag.brand_id,
+			category_id: tag.category_id,
+			timestamp: tag.time,
+			price: tag.price,
+			action,
+			cookie_fingerprint: cookie_fingerprint(&cookie.0),
+		}
+	}
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct AggregateTagEventCompressedData {
+	pub product_id: u64,
 	pub origin_id: u16,
 	pub brand_id: u16,
 	pub category_id: u16,
@@ -27,14 +124,27 @@ pub struct AggregateTagEventCompressedData {
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct PartialAggregateTagEventCompressedData {
+	pub product_id: Partial<String, u64>,
 	pub origin_id: Partial<String, u16>,
 	pub brand_id: Partial<String, u16>,
 	pub category_id: Partial<String, u16>,
 }
 
+impl PartialFields for PartialAggregateTagEventCompressedData {
+	fn same_fields(&self) -> Vec<bool> {
+		vec![
+			self.product_id.is_same(),
+			self.origin_id.is_same(),
+			self.brand_id.is_same(),
+			self.category_id.is_same(),
+		]
+	}
+}
+
 impl From<ApiUserTag> for PartialAggregateTagEventCompressedData {
 	fn from(value: ApiUserTag) -> Self {
 		Self {
+			product_id: Partial::Same(value.product_info.product_id),
 			origin_id: Partial::Same(value.origin),
 			brand_id: Partial::Same(value.product_info.brand_id),
 			category_id: Partial::Same(value.product_info.category_id),
@@ -47,15 +157,16 @@ impl Compress for AggregateTagEvent {
 	type CompressedData = AggregateTagEventCompressedData;
 	type PartialCompressedData = PartialAggregateTagEventCompressedData;
 	
-	async fn compress<T: Compressor<Self>>(value: &Self::From, compressor: &T) -> Result<Self> {
-		let compressed_tag = compressor.compress(value).await;
+	fn from_compressed(value: &Self::From, compressed_tag: Self::CompressedData) -> Result<Self> {
 		Ok(Self {
+			product_id: compressed_tag.product_id,
 			origin_id: compressed_tag.origin_id,
 			brand_id: compressed_tag.brand_id,
 			category_id: compressed_tag.category_id,
 			timestamp: time::parse_timestamp(value.time.as_str())?,
 			price: value.product_info.price,
 			action: UserAction::try_from(value.action.as_str())?,
+			cookie_fingerprint: cookie_fingerprint(value.cookie.as_str()),
 		})
 	}
 }
\ No newline at end of file
@@ -0,0 +1,141 @@
+use anyhow::{bail, Context, Result};
+
+use crate::data::{AggregateTagEvent, Device, UserAction, UserTagEvent};
+
+/// Fixed-width little-endian encoding, prefixed with a version byte so the layout can change
+/// without misreading older entries
+pub trait BinaryCodec: Sized {
+	const VERSION: u8;
+	/// Length of an encoded value, version byte included
+	const SIZE: usize;
+	
+	fn encode(&self) -> Vec<u8>;
+	fn decode(bytes: &[u8]) -> Result<Self>;
+}
+
+struct ByteReader<'a> {
+	bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+	/// Checks the version byte and the length of the whole value
+	fn new<T: BinaryCodec>(bytes: &'a [u8]) -> Result<Self> {
+		match bytes.first() {
+			Some(version) if *version == T::VERSION => {},
+			Some(version) => bail!("unsupported encoding version {}, expected {}", version, T::VERSION),
+			None => bail!("empty encoded value"),
+		}
+		if bytes.len() != T::SIZE {
+			bail!("encoded value has {} bytes, expected {}", bytes.len(), T::SIZE);
+		}
+		Ok(Self { bytes: &bytes[1..] })
+	}
+	
+	fn take<const N: usize>(&mut self) -> [u8; N] {
+		let (value, rest) = self.bytes.split_at(N);
+		self.bytes = rest;
+		value.try_into().unwrap()
+	}
+	
+	fn u8(&mut self) -> u8 {
+		u8::from_le_bytes(self.take())
+	}
+	
+	fn u16(&mut self) -> u16 {
+		u16::from_le_bytes(self.take())
+	}
+	
+	fn i32(&mut self) -> i32 {
+		i32::from_le_bytes(self.take())
+	}
+	
+	fn u64(&mut self) -> u64 {
+		u64::from_le_bytes(self.take())
+	}
+	
+	fn i64(&mut self) -> i64 {
+		i64::from_le_bytes(self.take())
+	}
+}
+
+fn decode_device(value: u8) -> Result<Device> {
+	[Device::PC, Device::MOBILE, Device::TV].into_iter()
+		.find(|device| *device as u8 == value)
+		.with_context(|| format!("unknown device {}", value))
+}
+
+pub fn decode_action(value: u8) -> Result<UserAction> {
+	[UserAction::VIEW, UserAction::BUY].into_iter()
+		.find(|action| *action as u8 == value)
+		.with_context(|| format!("unknown action {}", value))
+}
+
+/// version: u8, product_id: u64, brand_id: u16, category_id: u16, country_id: u8, origin_id: u16,
+/// time: i64, price: i32, device: u8
+impl BinaryCodec for UserTagEvent {
+	const VERSION: u8 = 1;
+	const SIZE: usize = 29;
+	
+	fn encode(&self) -> Vec<u8> {
+		let mut bytes = Vec::with_capacity(Self::SIZE);
+		bytes.push(Self::VERSION);
+		bytes.extend_from_slice(&self.product_id.to_le_bytes());
+		bytes.extend_from_slice(&self.brand_id.to_le_bytes());
+		bytes.extend_from_slice(&self.category_id.to_le_bytes());
+		bytes.extend_from_slice(&self.country_id.to_le_bytes());
+		bytes.extend_from_slice(&self.origin_id.to_le_bytes());
+		bytes.extend_from_slice(&self.time.to_le_bytes());
+		bytes.extend_from_slice(&self.price.to_le_bytes());
+		bytes.push(self.device as u8);
+		bytes
+	}
+	
+	fn decode(bytes: &[u8]) -> Result<Self> {
+		let mut reader = ByteReader::new::<Self>(bytes)?;
+		Ok(Self {
+			product_id: reader.u64(),
+			brand_id: reader.u16(),
+			category_id: reader.u16(),
+			country_id: reader.u8(),
+			origin_id: reader.u16(),
+			time: reader.i64(),
+			price: reader.i32(),
+			device: decode_device(reader.u8())?,
+		})
+	}
+}
+
+/// version: u8, product_id: u64, origin_id: u16, brand_id: u16, category_id: u16, timestamp: i64,
+/// price: i32, action: u8, cookie_fingerprint: u64
+impl BinaryCodec for AggregateTagEvent {
+	const VERSION: u8 = 1;
+	const SIZE: usize = 36;
+	
+	fn encode(&self) -> Vec<u8> {
+		let mut bytes = Vec::with_capacity(Self::SIZE);
+		bytes.push(Self::VERSION);
+		bytes.extend_from_slice(&self.product_id.to_le_bytes());
+		bytes.extend_from_slice(&self.origin_id.to_le_bytes());
+		bytes.extend_from_slice(&self.brand_id.to_le_bytes());
+		bytes.extend_from_slice(&self.category_id.to_le_bytes());
+		bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+		bytes.extend_from_slice(&self.price.to_le_bytes());
+		bytes.push(self.action as u8);
+		bytes.extend_from_slice(&self.cookie_fingerprint.to_le_bytes());
+		bytes
+	}
+	
+	fn decode(bytes: &[u8]) -> Result<Self> {
+		let mut reader = ByteReader::new::<Self>(bytes)?;
+		Ok(Self {
+			product_id: reader.u64(),
+			origin_id: reader.u16(),
+			brand_id: reader.u16(),
+			category_id: reader.u16(),
+			timestamp: reader.i64(),
+			price: reader.i32(),
+			action: decode_action(reader.u8())?,
+			cookie_fingerprint: reader.u64(),
+		})
+	}
+}
@@ -13,6 +13,7 @@ pub struct ProductInfo {
 pub struct Cookie(pub String);
 
 #[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, EnumString, IntoStaticStr)]
 pub enum Device {
 	PC,
@@ -21,14 +22,15 @@ pub enum Device {
 }
 
 #[repr(u8)]
-#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, EnumString, IntoStaticStr)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash, EnumString, IntoStaticStr)]
 pub enum UserAction {
 	VIEW,
 	BUY,
 }
 
-impl Into<String> for Cookie {
-	fn into(self) -> String {
-		self.0
+impl From<Cookie> for String {
+	fn from(value: Cookie) -> Self {
+		value.0
 	}
 }
@@ -0,0 +1,75 @@
+use std::fmt::{Display, Formatter};
+
+use serde::Serialize;
+use strum_macros::IntoStaticStr;
+
+/// String dictionaries used to compress tags, each one limited by the width of its id field
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, IntoStaticStr)]
+pub enum Dictionary {
+	#[strum(serialize = "product_id")]
+	#[serde(rename = "product_id")]
+	ProductId,
+	#[strum(serialize = "origin_id")]
+	#[serde(rename = "origin_id")]
+	OriginId,
+	#[strum(serialize = "brand_id")]
+	#[serde(rename = "brand_id")]
+	BrandId,
+	#[strum(serialize = "country")]
+	#[serde(rename = "country")]
+	Country,
+	#[strum(serialize = "category_id")]
+	#[serde(rename = "category_id")]
+	CategoryId,
+}
+
+impl Dictionary {
+	pub const ALL: [Dictionary; 5] = [Dictionary::ProductId, Dictionary::OriginId, Dictionary::BrandId, Dictionary::Country, Dictionary::CategoryId];
+	
+	/// Number of distinct values the compressed field can hold
+	pub fn capacity(&self) -> u64 {
+		match self {
+			Dictionary::ProductId => u64::MAX,
+			Dictionary::Country => u8::MAX as u64 + 1,
+			Dictionary::OriginId | Dictionary::BrandId | Dictionary::CategoryId => u16::MAX as u64 + 1,
+		}
+	}
+	
+	/// Fails when the id does not fit into the compressed field
+	pub fn check(&self, id: u64) -> Result<u64, IdSpaceOverflow> {
+		match id < self.capacity() {
+			true => Ok(id),
+			false => Err(IdSpaceOverflow { dictionary: *self }),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct IdSpaceOverflow {
+	pub dictionary: Dictionary,
+}
+
+impl Display for IdSpaceOverflow {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(f, "dictionary {} is full, it holds at most {} values", Into::<&'static str>::into(self.dictionary), self.dictionary.capacity())
+	}
+}
+
+impl std::error::Error for IdSpaceOverflow {}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct DictionaryFill {
+	pub dictionary: Dictionary,
+	pub size: u64,
+	pub capacity: u64,
+}
+
+impl DictionaryFill {
+	pub fn new(dictionary: Dictionary, size: u64) -> Self {
+		Self {
+			dictionary,
+			size,
+			capacity: dictionary.capacity(),
+		}
+	}
+}
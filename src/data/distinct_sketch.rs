@@ -0,0 +1,84 @@
+/// K-minimum-values sketch estimating the number of distinct fingerprints it has seen.
+/// Keeps only the `capacity` smallest fingerprints, so it stays small and can be merged.
+/// A merge keeps the smaller capacity of the two, which is still the sketch of the union.
+#[derive(Clone, Debug)]
+pub struct DistinctSketch {
+	fingerprints: Vec<u64>,
+	capacity: usize,
+}
+
+impl Default for DistinctSketch {
+	fn default() -> Self {
+		Self {
+			fingerprints: vec![],
+			capacity: Self::CAPACITY,
+		}
+	}
+}
+
+impl DistinctSketch {
+	pub const CAPACITY: usize = 128;
+	/// Fingerprints are 63 bit so they fit in a non-negative Aerospike integer
+	pub const FINGERPRINT_BITS: u32 = 63;
+	
+	pub fn from_fingerprints(fingerprints: Vec<u64>) -> Self {
+		Self::with_capacity(fingerprints, Self::CAPACITY)
+	}
+	
+	/// Sketch of a store keeping fewer than `CAPACITY` of the smallest fingerprints
+	pub fn with_capacity(mut fingerprints: Vec<u64>, capacity: usize) -> Self {
+		fingerprints.sort_unstable();
+		fingerprints.dedup();
+		fingerprints.truncate(capacity);
+		Self { fingerprints, capacity }
+	}
+	
+	/// The smallest fingerprints seen, sorted
+	pub fn fingerprints(&self) -> &[u64] {
+		&self.fingerprints
+	}
+	
+	pub fn insert(&mut self, fingerprint: u64) {
+		if self.fingerprints.len() == self.capacity && fingerprint >= *self.fingerprints.last().unwrap() {
+			return;
+		}
+		if let Err(position) = self.fingerprints.binary_search(&fingerprint) {
+			self.fingerprints.insert(position, fingerprint);
+			self.fingerprints.truncate(self.capacity);
+		}
+	}
+	
+	pub fn merge(&mut self, other: &DistinctSketch) {
+		if other.capacity < self.capacity {
+			self.capacity = other.capacity;
+			self.fingerprints.truncate(self.capacity);
+		}
+		for fingerprint in other.fingerprints.iter() {
+			self.insert(*fingerprint);
+		}
+	}
+	
+	pub fn estimate(&self) -> u64 {
+		if self.fingerprints.len() < self.capacity {
+			return self.fingerprints.len() as u64;
+		}
+		let kth = *self.fingerprints.last().unwrap() as f64 + 1.0;
+		let space = (1u64 << Self::FINGERPRINT_BITS) as f64;
+		((self.capacity - 1) as f64 * space / kth).round() as u64
+	}
+}
+
+/// Stable 63 bit hash of a cookie (FNV-1a followed by a splitmix64 finalizer), identical on every node
+pub fn cookie_fingerprint(cookie: &str) -> u64 {
+	let mut hash: u64 = 0xcbf29ce484222325;
+	for byte in cookie.bytes() {
+		hash ^= byte as u64;
+		hash = hash.wrapping_mul(0x100000001b3);
+	}
+	hash ^= hash >> 30;
+	hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+	hash ^= hash >> 27;
+	hash = hash.wrapping_mul(0x94d049bb133111eb);
+	hash ^= hash >> 31;
+	hash >> (64 - DistinctSketch::FINGERPRINT_BITS)
+}
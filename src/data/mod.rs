@@ -1,11 +1,21 @@
+mod aggregate_cube;
 mod aggregate_event_tag;
+mod codec;
 mod common;
+mod dictionary;
+mod distinct_sketch;
 pub mod time;
+mod top;
 mod user_event_tag;
 mod user_profile;
 
+pub use aggregate_cube::*;
 pub use aggregate_event_tag::*;
+pub use codec::*;
 pub use common::*;
+pub use dictionary::*;
+pub use distinct_sketch::*;
+pub use top::*;
 pub use user_event_tag::*;
 pub use crate::compression::*;
 pub use user_profile::*;
\ No newline at end of file
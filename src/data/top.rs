@@ -0,0 +1,87 @@
+use strum_macros::{EnumString, IntoStaticStr};
+
+use crate::data::{AggregateDimension, Decompress, Dictionary, Partial, PartialFields};
+use crate::database::{DbResult, Decompressor};
+
+/// Dimension a `/top` query ranks the values of
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, EnumString, IntoStaticStr)]
+pub enum TopDimension {
+	#[strum(serialize = "product_id")]
+	ProductId,
+	#[strum(serialize = "origin")]
+	Origin,
+	#[strum(serialize = "brand_id")]
+	BrandId,
+	#[strum(serialize = "category_id")]
+	CategoryId,
+}
+
+impl TopDimension {
+	/// Cube dimension holding the counters, `None` for products which are counted separately
+	pub fn cube_dimension(&self) -> Option<AggregateDimension> {
+		match self {
+			TopDimension::ProductId => None,
+			TopDimension::Origin => Some(AggregateDimension::Origin),
+			TopDimension::BrandId => Some(AggregateDimension::BrandId),
+			TopDimension::CategoryId => Some(AggregateDimension::CategoryId),
+		}
+	}
+	
+	/// Dictionary the ranked ids are decompressed with
+	pub fn dictionary(&self) -> Dictionary {
+		match self {
+			TopDimension::ProductId => Dictionary::ProductId,
+			TopDimension::Origin => Dictionary::OriginId,
+			TopDimension::BrandId => Dictionary::BrandId,
+			TopDimension::CategoryId => Dictionary::CategoryId,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumString, IntoStaticStr)]
+#[strum(ascii_case_insensitive)]
+pub enum TopMetric {
+	#[strum(serialize = "count")]
+	Count,
+	#[strum(serialize = "sum")]
+	Sum,
+}
+
+/// Compressed value of a ranked dimension
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TopKey {
+	pub dimension: TopDimension,
+	pub id: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct PartialTopKeyDecompressedData {
+	pub dimension: TopDimension,
+	pub value: Partial<String, u64>,
+}
+
+impl PartialFields for PartialTopKeyDecompressedData {
+	fn same_fields(&self) -> Vec<bool> {
+		vec![self.value.is_same()]
+	}
+}
+
+impl From<TopKey> for PartialTopKeyDecompressedData {
+	fn from(value: TopKey) -> Self {
+		Self {
+			dimension: value.dimension,
+			value: Partial::Changed(value.id),
+		}
+	}
+}
+
+impl Decompress for TopKey {
+	type Type = Option<String>;
+	type DecompressedData = Option<String>;
+	type PartialDecompressedData = PartialTopKeyDecompressedData;
+	type AdditionalData = ();
+	
+	async fn decompress<T: Decompressor<Self>>(&self, decompressor: &T, _additional_data: Self::AdditionalData) -> DbResult<Self::Type> {
+		decompressor.decompress(self).await
+	}
+}
@@ -4,8 +4,8 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
 use crate::api::ApiUserTag;
-use crate::data::{Compress, Cookie, Decompress, Device, Partial, ProductInfo, time, UserAction};
-use crate::database::{Compressor, Decompressor};
+use crate::data::{Compress, Cookie, Decompress, Device, Partial, PartialFields, ProductInfo, time, UserAction};
+use crate::database::{DbResult, Decompressor};
 
 #[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub struct UserTagEvent {
@@ -37,6 +37,18 @@ pub struct PartialUserTagEventCompressedData {
 	pub origin_id: Partial<String, u16>,
 }
 
+impl PartialFields for PartialUserTagEventCompressedData {
+	fn same_fields(&self) -> Vec<bool> {
+		vec![
+			self.product_id.is_same(),
+			self.brand_id.is_same(),
+			self.category_id.is_same(),
+			self.country_id.is_same(),
+			self.origin_id.is_same(),
+		]
+	}
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct UserTagEventDecompressedData {
 	pub product_id: String,
@@ -75,8 +87,7 @@ impl Compress for UserTagEvent {
 	type CompressedData = UserTagEventCompressedData;
 	type PartialCompressedData = PartialUserTagEventCompressedData;
 	
-	async fn compress<T: Compressor<UserTagEvent>>(value: &ApiUserTag, compressor: &T) -> Result<UserTagEvent> {
-		let compressed_data = compressor.compress(value).await;
+	fn from_compressed(value: &ApiUserTag, compressed_data: UserTagEventCompressedData) -> Result<UserTagEvent> {
 		Ok(UserTagEvent {
 			product_id: compressed_data.product_id,
 			brand_id: compressed_data.brand_id,
@@ -96,9 +107,9 @@ impl Decompress for UserTagEvent {
 	type PartialDecompressedData = PartialUserTagEventCompressedData;
 	type AdditionalData = (Cookie, UserAction);
 	
-	async fn decompress<T: Decompressor<Self>>(&self, decompressor: &T, additional_data: Self::AdditionalData) -> Self::Type {
-		let decompressed_data = decompressor.decompress(self).await;
-		ApiUserTag {
+	async fn decompress<T: Decompressor<Self>>(&self, decompressor: &T, additional_data: Self::AdditionalData) -> DbResult<Self::Type> {
+		let decompressed_data = decompressor.decompress(self).await?;
+		Ok(ApiUserTag {
 			product_info: ProductInfo {
 				product_id: decompressed_data.product_id,
 				brand_id: decompressed_data.brand_id,
@@ -111,7 +122,7 @@ impl Decompress for UserTagEvent {
 			device: Into::<&'static str>::into(self.device).to_owned(),
 			action: Into::<&'static str>::into(additional_data.1).to_owned(),
 			origin: decompressed_data.origin_id,
-		}
+		})
 	}
 }
 
@@ -123,6 +134,6 @@ impl Ord for UserTagEvent {
 
 impl PartialOrd for UserTagEvent {
 	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-		self.time.partial_cmp(&other.time)
+		Some(self.cmp(other))
 	}
 }
\ No newline at end of file
@@ -1,7 +1,45 @@
-use crate::data::UserTagEvent;
+use crate::data::{UserAction, UserTagEvent};
+use crate::data::time::TimeRange;
 
+/// Last `max_tags` tags of every action, each list sorted by time from the oldest
 #[derive(Default, Clone)]
 pub struct UserProfile {
 	pub view_events: Vec<UserTagEvent>,
 	pub buy_events: Vec<UserTagEvent>,
-}
\ No newline at end of file
+}
+
+impl UserProfile {
+	pub fn events_mut(&mut self, action: UserAction) -> &mut Vec<UserTagEvent> {
+		match action {
+			UserAction::VIEW => &mut self.view_events,
+			UserAction::BUY => &mut self.buy_events,
+		}
+	}
+	
+	/// Inserts the tag in time order and evicts the oldest one once there are more than `max_tags`
+	pub fn add(&mut self, tag: UserTagEvent, action: UserAction, max_tags: usize) {
+		let events = self.events_mut(action);
+		let position = events.partition_point(|event| event.time <= tag.time);
+		events.insert(position, tag);
+		if events.len() > max_tags {
+			events.remove(0);
+		}
+	}
+	
+	/// Adds the tags the profile does not hold an equal one of yet, for tags that may be in it
+	/// already and cannot be told apart otherwise
+	pub fn add_missing(&mut self, tags: impl IntoIterator<Item = (UserTagEvent, UserAction)>, max_tags: usize) {
+		for (tag, action) in tags {
+			if !self.events_mut(action).contains(&tag) {
+				self.add(tag, action, max_tags);
+			}
+		}
+	}
+}
+
+/// Tags of a list sorted by time which fall into the time range
+pub fn tags_within<'a>(tags: &'a [UserTagEvent], time_range: &TimeRange) -> &'a [UserTagEvent] {
+	let start = tags.partition_point(|tag| tag.time < time_range.start);
+	let end = tags.partition_point(|tag| tag.time < time_range.end);
+	&tags[start..end.max(start)]
+}
@@ -1,30 +1,43 @@
-use std::env;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
-use aerospike::{as_key, as_val, Client, ClientPolicy, Expiration, Key, MapReturnType, Record, Value, WritePolicy};
+use aerospike::{as_key, as_val, BatchPolicy, BatchRead, Bins, Client, ClientPolicy, ErrorKind, Expiration, GenerationPolicy, Key, MapReturnType, ReadPolicy, Record, ResultCode, Value, WritePolicy};
 use aerospike::operations::{lists, MapOrder, maps, Operation};
-use aerospike::operations::cdt_context::ctx_map_key_create;
+use aerospike::operations::cdt_context::{CdtContext, ctx_map_key_create};
+use aerospike::operations::maps::{MapPolicy, MapWriteMode};
 use aerospike::operations::lists::{ListOrderType, ListPolicy, ListReturnType, ListWriteFlags};
 use aerospike::Value::{Int, List};
 
 use crate::api::*;
+use crate::config::{AerospikeConfig, AerospikeSets};
 use crate::data::*;
-use crate::database::{Compressor, Database, Decompressor, Synced, SyncedDB};
+use crate::database::{Compressor, Database, DbError, DbResult, Decompressor, Synced, SyncedDB};
 
 /*
-namespace aero {
+namespace aero (configured namespace and set names, defaults below) {
 	set tags {
 		record {
 			key: cookie
 			bins:
-				view_tags: unordered_list
-				buy_tags: unordered_list
+				view_tags: ordered_list [-time, blob (BinaryCodec)], newest first, at most max_tags
+				buy_tags: ordered_list [-time, blob (BinaryCodec)], newest first, at most max_tags
+				view: legacy unordered_list of blob or json string, oldest first, trimmed to max_tags on read
+				buy: legacy unordered_list of blob or json string, oldest first, trimmed to max_tags on read
 		}
 	}
-	set minute_tags {
+	set minute_tags / hour_tags / day_tags {
 		record {
-			key: none
+			key: "minute:shard" / "hour:shard" / "day:shard", every bucket split into tier_shards records,
+				a cell goes to the shard of its cube_key hash, a product to the shard of its key hash
 			bins:
-				tags: map minute->unordered_list
+				sum: map cube_key->int
+				count: map cube_key->int
+				min: map cube_key->map price->1 (only the smallest price kept)
+				max: map cube_key->map -price->1 (only the largest price kept)
+				users: map full cube_key->map cookie_fingerprint->1 (smallest fingerprints of a DistinctSketch,
+					128 of them in a minute, 64 in an hour and 32 in a day)
+				product_sum: map product_id << 1 | action->int
+				product_count: map product_id << 1 | action->int
 		}
 	}
 	set mappings {
@@ -41,24 +54,37 @@ namespace aero {
 }
 */
 
+/// Ids assigned to dictionary values by a single `resolve_mappings` round trip
+type MappedIds<'a> = HashMap<(Dictionary, &'a String), Result<u64, IdSpaceOverflow>>;
+
 pub struct AerospikeDB {
-	client: Client,
+	/// Shared with the blocking threads the batch reads run on
+	client: Arc<Client>,
+	namespace: String,
+	sets: AerospikeSets,
+	tier_shards: i64,
+	max_tags: usize,
+	batch_policy: BatchPolicy,
 	write_policy: WritePolicy,
 	insert_unique_list_policy: ListPolicy,
-	list_policy: ListPolicy,
+	map_policy: MapPolicy,
 }
 
 impl AerospikeDB {
-	const NAMESPACE: &'static str = "test";
 	// tags
-	const TAG_SET: &'static str = "tags";
-	const VIEW_BIN: &'static str = "view";
-	const BUY_BIN: &'static str = "buy";
+	const VIEW_BIN: &'static str = "view_tags";
+	const BUY_BIN: &'static str = "buy_tags";
+	const LEGACY_VIEW_BIN: &'static str = "view";
+	const LEGACY_BUY_BIN: &'static str = "buy";
 	// minute tags
-	const MINUTE_SET: &'static str = "minute_tags";
-	const TAG_BIN: &'static str = "tags";
+	const SUM_BIN: &'static str = "sum";
+	const COUNT_BIN: &'static str = "count";
+	const MIN_BIN: &'static str = "min";
+	const MAX_BIN: &'static str = "max";
+	const USERS_BIN: &'static str = "users";
+	const PRODUCT_SUM_BIN: &'static str = "product_sum";
+	const PRODUCT_COUNT_BIN: &'static str = "product_count";
 	// mappings
-	const MAPPINGS_SET: &'static str = "mappings";
 	const ORIGIN_ID_BIN: &'static str = "origin_id";
 	const BRAND_ID_BIN: &'static str = "brand_id";
 	const CATEGORY_ID_BIN: &'static str = "category_id";
@@ -66,238 +92,853 @@ impl AerospikeDB {
 	const COUNTRY_BIN: &'static str = "country";
 	
 	const EMPTY_KEY: &'static str = "empty";
+	/// Aggregate events of a minute counted by a single operate, each one takes about 50 operations
+	const EVENTS_PER_OPERATE: usize = 32;
 	
-	fn operate(&self, key: &Key, ops: &[Operation]) -> Record {
+	fn operate(&self, key: &Key, ops: &[Operation]) -> DbResult<Record> {
+		Ok(self.client.operate(&self.write_policy, key, ops)?)
+	}
+	
+	/// Same as `operate`, but a missing record is reported as `None` instead of an error
+	fn try_operate(&self, key: &Key, ops: &[Operation]) -> DbResult<Option<Record>> {
 		match self.client.operate(&self.write_policy, key, ops) {
-			Ok(record) => record,
-			Err(err) => panic!("Operation failed {:?}:\n{}", key, err),
+			Ok(record) => Ok(Some(record)),
+			Err(aerospike::Error(ErrorKind::ServerError(ResultCode::KeyNotFoundError), _)) => Ok(None),
+			Err(err) => Err(err.into()),
+		}
+	}
+	
+	/// Reads the bins of every record in a single batch, on a blocking thread so the runtime
+	/// keeps serving requests. Records come back in the order of the keys, `None` when missing.
+	async fn batch_get(&self, keys: Vec<Key>, bins: &[&str]) -> DbResult<Vec<Option<Record>>> {
+		if keys.is_empty() {
+			return Ok(vec![]);
+		}
+		let client = self.client.clone();
+		let policy = self.batch_policy.clone();
+		let bins = Bins::Some(bins.iter().map(|bin| bin.to_string()).collect());
+		tokio::task::spawn_blocking(move || {
+			let reads = keys.into_iter().map(|key| BatchRead::new(key, &bins)).collect();
+			Ok(client.batch_get(&policy, reads)?.into_iter().map(|read| read.record).collect())
+		}).await.map_err(|err| DbError::Protocol(format!("batch read did not finish: {}", err)))?
+	}
+	
+	fn tag_key(&self, cookie: &Cookie) -> Key {
+		as_key!(self.namespace.as_str(), self.sets.tags.as_str(), &cookie.0)
+	}
+	
+	fn tier_key(&self, tier: AggregateTier, index: i64, shard: i64) -> Key {
+		let set = match tier {
+			AggregateTier::Minute => &self.sets.minute,
+			AggregateTier::Hour => &self.sets.hour,
+			AggregateTier::Day => &self.sets.day,
+		};
+		as_key!(self.namespace.as_str(), set.as_str(), format!("{}:{}", index, shard))
+	}
+	
+	/// Keys of every shard of the tier bucket
+	fn tier_keys(&self, tier: AggregateTier, index: i64) -> impl Iterator<Item = Key> + '_ {
+		(0..self.tier_shards).map(move |shard| self.tier_key(tier, index, shard))
+	}
+	
+	/// Shard of the tier bucket holding a cube cell or product key
+	fn tier_shard(&self, key: i64) -> i64 {
+		// cube keys and product keys are far from uniform in their low bits
+		((key as u64).wrapping_mul(0x9e3779b97f4a7c15) >> 32) as i64 % self.tier_shards
+	}
+	
+	/// Fingerprints of the distinct users kept by every full cell of a tier record, the
+	/// coarser tiers hold far more cells so they keep less of them
+	fn users_capacity(tier: AggregateTier) -> usize {
+		match tier {
+			AggregateTier::Minute => DistinctSketch::CAPACITY,
+			AggregateTier::Hour => DistinctSketch::CAPACITY / 2,
+			AggregateTier::Day => DistinctSketch::CAPACITY / 4,
 		}
 	}
 	
-	fn add_or_get_mapping<V>(&self, value: &Partial<String, V>, bin: &'static str, operations: &mut Vec<(&str, Value)>) {
-		if let Partial::Same(value) = value {
+	fn mappings_key(&self) -> Key {
+		as_key!(self.namespace.as_str(), self.sets.mappings.as_str(), Self::EMPTY_KEY)
+	}
+	
+	fn mapping_bin(dictionary: Dictionary) -> &'static str {
+		match dictionary {
+			Dictionary::ProductId => Self::PRODUCT_ID_BIN,
+			Dictionary::OriginId => Self::ORIGIN_ID_BIN,
+			Dictionary::BrandId => Self::BRAND_ID_BIN,
+			Dictionary::Country => Self::COUNTRY_BIN,
+			Dictionary::CategoryId => Self::CATEGORY_ID_BIN,
+		}
+	}
+	
+	fn product_key(action: UserAction, product_id: u64) -> i64 {
+		((product_id << 1) | action as u64) as i64
+	}
+	
+	fn get_mapping_if_present(&self, value: &Partial<Option<String>, Option<u16>>, bin: &'static str, operations: &mut Vec<(&str, Value)>) {
+		if let Partial::Same(Some(value)) = value {
 			operations.push((bin, as_val!(value)));
 		}
 	}
 	
-	fn prepare_operations<'a>(&self, operations_definitions: &'a mut Vec<(&'a str, Value)>) -> Vec<Operation<'a>> {
-		let mut operations: Vec<Operation<'a>> = vec![];
-		for (bin, value) in operations_definitions.iter() {
-			operations.push(lists::append(&self.insert_unique_list_policy, bin, value));
-			operations.push(lists::get_by_value(bin, value, ListReturnType::Index))
+	fn get_reverse_mapping<'a, V: Copy + Into<u64>>(&self, value: &Partial<String, V>, bin: &'a str, operations: &mut Vec<Operation<'a>>) {
+		if let Partial::Changed(id) = value {
+			operations.push(lists::get_by_index(bin, Into::<u64>::into(*id) as i64, ListReturnType::Values));
 		}
-		operations
+	}
+	
+	fn get_optional_reverse_mapping<'a>(&self, value: &Partial<Option<String>, Option<u16>>, bin: &'a str, operations: &mut Vec<Operation<'a>>) {
+		if let Partial::Changed(Some(id)) = value {
+			operations.push(lists::get_by_index(bin, *id as i64, ListReturnType::Values));
+		}
+	}
+	
+	/// Appends every distinct value to its mapping list and looks up its id, all in a single
+	/// `operate`. Values appended past the width of their field stay in the mapping list, but
+	/// are never handed out.
+	fn resolve_mappings<'a>(&self, values: impl Iterator<Item = (Dictionary, &'a String)>) -> DbResult<MappedIds<'a>> {
+		let key = self.mappings_key();
+		let mut distinct: Vec<(Dictionary, &String)> = vec![];
+		let mut seen: HashSet<(Dictionary, &String)> = HashSet::new();
+		for value in values {
+			if seen.insert(value) {
+				distinct.push(value);
+			}
+		}
+		if distinct.is_empty() {
+			return Ok(HashMap::new());
+		}
+		
+		let values: Vec<Value> = distinct.iter().map(|(_, value)| as_val!(*value)).collect();
+		let mut operations: Vec<Operation> = distinct.iter()
+			.zip(values.iter())
+			.flat_map(|((dictionary, _), value)| {
+				let bin = Self::mapping_bin(*dictionary);
+				[
+					lists::append(&self.insert_unique_list_policy, bin, value),
+					lists::get_by_value(bin, value, ListReturnType::Index),
+				]
+			})
+			.collect();
+		// values appended past the capacity are removed within the same atomic operate, so they
+		// are reported as an overflow and never stored; their results follow all the indexes
+		for dictionary in Dictionary::ALL.into_iter().filter(|x| distinct.iter().any(|(dictionary, _)| dictionary == x)) {
+			if let Ok(capacity) = i64::try_from(dictionary.capacity()) {
+				operations.push(lists::remove_by_index_range(Self::mapping_bin(dictionary), capacity, ListReturnType::None));
+			}
+		}
+		let result = self.operate(&key, &operations)?;
+		
+		let mut positions: HashMap<Dictionary, usize> = HashMap::new();
+		distinct.into_iter()
+			.map(|(dictionary, value)| {
+				let position = positions.entry(dictionary).or_default();
+				let id = retrieve_value_from_mapping_result(Self::mapping_bin(dictionary), *position, &result)?;
+				*position += 1;
+				Ok(((dictionary, value), dictionary.check(id as u64)))
+			})
+			.collect()
 	}
 }
 
 
-fn retrieve_value_from_mapping_result(bin: &str, result: &Record) -> i64 {
-	if let List(results_for_bin) = result.bins.get(bin).expect(format!("No bin named {} found", bin).as_str()) {
-		let get_result_value = results_for_bin.get(1).expect(format!("Aerospike fucked up and didn't return index of key for bin {}", bin).as_str());
-		if let List(get_result_list) = get_result_value {
-			let result_value = get_result_list.get(0).expect(format!("Aerospike fucked up and didn't return index of key for bin {}", bin).as_str());
-			if let Int(out) = result_value {
-				return *out;
-			}
-			unreachable!("Aerospike got a mindfuck and returned sth else than an int of return value of a get_by_value (expecting an int index)")
+/// Index returned for the `position`-th value of the bin. Results of all operations on a bin are
+/// returned together: append result, index, append result, index, ...
+pub(crate) fn retrieve_value_from_mapping_result(bin: &str, position: usize, result: &Record) -> DbResult<i64> {
+	let results_for_bin = match result.bins.get(bin) {
+		Some(List(results_for_bin)) => results_for_bin,
+		Some(other) => return Err(DbError::Protocol(format!("expected a list of operation results for bin {}, got {:?}", bin, other))),
+		None => return Err(DbError::Protocol(format!("no operation results for bin {}", bin))),
+	};
+	match results_for_bin.get(2 * position + 1) {
+		Some(List(indexes)) => match indexes.first() {
+			Some(Int(index)) => Ok(*index),
+			other => Err(DbError::Protocol(format!("expected the index of a mapped value in bin {}, got {:?}", bin, other))),
+		},
+		other => Err(DbError::Protocol(format!("expected a list of indexes for bin {}, got {:?}", bin, other))),
+	}
+}
+
+fn unknown_value<V>(dictionary: Dictionary, value: &Partial<String, V>) -> Option<(Dictionary, &String)> {
+	match value {
+		Partial::Same(x) => Some((dictionary, x)),
+		Partial::Changed(_) => None,
+	}
+}
+
+/// Id of a value, either already known or assigned by `resolve_mappings`
+fn resolve_id<V: Copy + Into<u64>>(ids: &MappedIds, dictionary: Dictionary, value: &Partial<String, V>) -> DbResult<u64> {
+	match value {
+		Partial::Same(x) => Ok(ids[&(dictionary, x)]?),
+		Partial::Changed(id) => Ok(Into::<u64>::into(*id)),
+	}
+}
+
+/// Index of a value looked up with `get_by_value`, `None` when the value was never mapped
+fn retrieve_optional_value_from_mapping_result(bin: &str, result: Option<&Record>) -> Option<i64> {
+	match result.and_then(|record| record.bins.get(bin)) {
+		Some(List(indexes)) => match indexes.first() {
+			Some(Int(index)) => Some(*index),
+			_ => None,
+		},
+		_ => None,
+	}
+}
+
+fn retrieve_string_from_mapping_result<V>(bin: &str, value: Partial<String, V>, result: Option<&Record>) -> DbResult<String> {
+	match value {
+		Partial::Same(x) => Ok(x),
+		Partial::Changed(_) => match result.and_then(|record| record.bins.get(bin)) {
+			Some(Value::String(out)) => Ok(out.clone()),
+			Some(Value::Nil) | None => Err(DbError::NotFound(format!("no mapping found in bin {}", bin))),
+			Some(other) => Err(DbError::Protocol(format!("expected a mapped string for bin {}, got {:?}", bin, other))),
+		},
+	}
+}
+
+fn retrieve_int_from_result(bin: &str, result: &Record) -> i64 {
+	match result.bins.get(bin) {
+		Some(Int(value)) => *value,
+		_ => 0,
+	}
+}
+
+/// Entries of a map value with integer keys
+fn int_map_entries(value: Option<&Value>) -> Vec<(i64, &Value)> {
+	let entries: Vec<(&Value, &Value)> = match value {
+		Some(Value::HashMap(map)) => map.iter().collect(),
+		Some(Value::OrderedMap(map)) => map.iter().map(|(key, value)| (key, value)).collect(),
+		_ => vec![],
+	};
+	entries.into_iter()
+		.filter_map(|(key, value)| match key {
+			Int(key) => Some((*key, value)),
+			_ => None,
+		})
+		.collect()
+}
+
+/// Counters of a single cube cell stored in a tier record, with the distinct users of the full
+/// cells it covers, `users_capacity` of them kept by every full cell
+fn retrieve_cell_from_result(result: &Record, cell: &CubeKey, users_capacity: usize) -> AggregateBucket {
+	let value = |bin: &str| int_map_entries(result.bins.get(bin)).into_iter()
+		.find(|(key, _)| *key == cell.encode())
+		.map(|(_, value)| value);
+	let int_value = |bin: &str| match value(bin) {
+		Some(Int(value)) => *value,
+		_ => 0,
+	};
+	let keys = |bin: &str| -> Vec<i64> {
+		int_map_entries(value(bin)).into_iter().map(|(key, _)| key).collect()
+	};
+	
+	AggregateBucket {
+		sum: int_value(AerospikeDB::SUM_BIN) as u64,
+		count: int_value(AerospikeDB::COUNT_BIN) as u64,
+		min: keys(AerospikeDB::MIN_BIN).into_iter().min().map(|x| x as i32),
+		max: keys(AerospikeDB::MAX_BIN).into_iter().min().map(|x| -x as i32),
+		distinct_users: DistinctSketch::with_capacity(int_map_entries(result.bins.get(AerospikeDB::USERS_BIN)).into_iter()
+			.filter(|(key, _)| cell.covers(&CubeKey::decode(*key)))
+			.flat_map(|(_, users)| int_map_entries(Some(users)))
+			.map(|(fingerprint, _)| fingerprint as u64)
+			.collect(), users_capacity),
+	}
+}
+
+/// Every cube cell stored in a tier record, `users_capacity` distinct users kept by the full ones
+fn retrieve_cells_from_result(result: &Record, users_capacity: usize) -> Vec<(CubeKey, AggregateBucket)> {
+	let bin_map = |bin: &str| -> HashMap<i64, &Value> {
+		int_map_entries(result.bins.get(bin)).into_iter().collect()
+	};
+	let int_value = |value: Option<&&Value>| match value {
+		Some(Int(value)) => *value,
+		_ => 0,
+	};
+	let keys = |value: Option<&&Value>| -> Vec<i64> {
+		int_map_entries(value.copied()).into_iter().map(|(key, _)| key).collect()
+	};
+	
+	let sums = bin_map(AerospikeDB::SUM_BIN);
+	let counts = bin_map(AerospikeDB::COUNT_BIN);
+	let mins = bin_map(AerospikeDB::MIN_BIN);
+	let maxs = bin_map(AerospikeDB::MAX_BIN);
+	let users = bin_map(AerospikeDB::USERS_BIN);
+	
+	counts.iter()
+		.map(|(cell, count)| (CubeKey::decode(*cell), AggregateBucket {
+			sum: int_value(sums.get(cell)) as u64,
+			count: int_value(Some(count)) as u64,
+			min: keys(mins.get(cell)).into_iter().min().map(|x| x as i32),
+			max: keys(maxs.get(cell)).into_iter().min().map(|x| -x as i32),
+			distinct_users: DistinctSketch::with_capacity(keys(users.get(cell)).into_iter().map(|x| x as u64).collect(), users_capacity),
+		}))
+		.collect()
+}
+
+/// Counters of every value of the requested dimension stored in a tier record
+fn retrieve_top_from_result(request: &GetTopRequest, result: &Record) -> Vec<(u64, TopCounter)> {
+	match request.dimension.cube_dimension() {
+		Some(dimension) => retrieve_cells_from_result(result, DistinctSketch::CAPACITY).into_iter()
+			.filter(|(key, _)| key.action == request.action && key.has_only(dimension))
+			.filter_map(|(key, cell)| key.get(dimension).map(|id| (id as u64, TopCounter::from(&cell))))
+			.collect(),
+		None => {
+			let sums: HashMap<i64, &Value> = int_map_entries(result.bins.get(AerospikeDB::PRODUCT_SUM_BIN)).into_iter().collect();
+			int_map_entries(result.bins.get(AerospikeDB::PRODUCT_COUNT_BIN)).into_iter()
+				.filter(|(key, _)| key & 1 == request.action as i64)
+				.map(|(key, count)| {
+					let int_value = |value: Option<&Value>| match value {
+						Some(Int(value)) => *value as u64,
+						_ => 0,
+					};
+					(key as u64 >> 1, TopCounter {
+						count: int_value(Some(count)),
+						sum: int_value(sums.get(&key).copied()),
+					})
+				})
+				.collect()
+		},
+	}
+}
+
+/// Entry of a profile list, ordered by the negated time so the newest tag comes first
+pub fn profile_entry(tag: &UserTagEvent) -> Value {
+	List(vec![Int(-tag.time), Value::Blob(tag.encode())])
+}
+
+/// Appends the entries of every bin to its profile list and trims the list to `max_tags`, all in
+/// the operations of a single atomic `operate`. The list is ordered newest first, so everything
+/// past `max_tags` is the oldest, whatever order concurrent writes arrive in.
+pub(crate) fn profile_append_operations<'a>(bins: &'a [(&'a str, Vec<Value>)], max_tags: usize) -> Vec<Operation<'a>> {
+	let policy = ListPolicy::new(ListOrderType::Ordered, ListWriteFlags::Default);
+	bins.iter()
+		.filter(|(_, entries)| !entries.is_empty())
+		.flat_map(|(bin, entries)| [
+			lists::append_items(&policy, bin, entries),
+			lists::remove_by_index_range(bin, max_tags as i64, ListReturnType::None),
+		])
+		.collect()
+}
+
+/// Values the aggregate operations of a single event refer to
+struct AggregateEventValues {
+	/// Shard of every cell and of the product
+	cell_shards: Vec<i64>,
+	product_shard: i64,
+	cells: Vec<Value>,
+	contexts: Vec<[CdtContext; 1]>,
+	price: Value,
+	negated_price: Value,
+	fingerprint: Value,
+	one: Value,
+	product: Value,
+}
+
+impl AggregateEventValues {
+	fn new(db: &AerospikeDB, tag: &AggregateTagEvent) -> Self {
+		let cells: Vec<i64> = CubeKey::combinations(tag)
+			.map(|cell| cell.encode())
+			.collect();
+		let product = AerospikeDB::product_key(tag.action, tag.product_id);
+		let cell_shards = cells.iter().map(|cell| db.tier_shard(*cell)).collect();
+		let cells: Vec<Value> = cells.into_iter().map(|cell| as_val!(cell)).collect();
+		let contexts = cells.iter()
+			.map(|cell| [ctx_map_key_create(cell.clone(), MapOrder::KeyOrdered)])
+			.collect();
+		Self {
+			cell_shards,
+			product_shard: db.tier_shard(product),
+			cells,
+			contexts,
+			price: as_val!(tag.price as i64),
+			negated_price: as_val!(-(tag.price as i64)),
+			fingerprint: as_val!(tag.cookie_fingerprint as i64),
+			one: as_val!(1),
+			product: as_val!(product),
 		}
-		unreachable!("Aerospike got a mindfuck and returned sth else than a list of return value of a get_by_value (expecting list of indexes)")
 	}
-	unreachable!("Aerospike got a mindfuck and returned sth else than a list of return values for a list of operations")
+}
+
+/// Reads an entry of a profile list, including blobs and json strings of the legacy lists
+pub fn decode_profile_entry(value: &Value) -> Option<UserTagEvent> {
+	match value {
+		List(entry) => entry.get(1).and_then(decode_profile_entry),
+		Value::Blob(bytes) => UserTagEvent::decode(bytes).ok(),
+		Value::String(str) => serde_json::from_str(str.as_str()).ok(),
+		_ => None,
+	}
 }
 
 impl AerospikeDB {
-	pub fn new() -> Self {
+	pub fn new(config: &AerospikeConfig, max_tags: usize) -> DbResult<Self> {
 		let client_policy = ClientPolicy::default();
-		let hosts = env::var("AEROSPIKE_HOSTS")
-			.unwrap_or(String::from("127.0.0.1:3000"));
-		Self {
-			client: Client::new(&client_policy, &hosts)
-				.expect("Failed to connect to cluster"),
+		let mut base_policy = ReadPolicy::default();
+		if let Some(timeout) = config.policy.timeout() {
+			base_policy.timeout = Some(timeout);
+		}
+		if let Some(max_retries) = config.policy.max_retries {
+			base_policy.max_retries = Some(max_retries);
+		}
+		if let Some(sleep_between_retries) = config.policy.sleep_between_retries() {
+			base_policy.sleep_between_retries = Some(sleep_between_retries);
+		}
+		Ok(Self {
+			client: Arc::new(Client::new(&client_policy, &config.hosts)?),
+			namespace: config.namespace.clone(),
+			sets: config.sets.clone(),
+			tier_shards: config.tier_shards as i64,
+			max_tags,
+			batch_policy: BatchPolicy {
+				base_policy: base_policy.clone(),
+				..BatchPolicy::default()
+			},
 			write_policy: WritePolicy {
-				base_policy: Default::default(),
+				base_policy,
 				record_exists_action: Default::default(),
 				generation_policy: Default::default(),
 				commit_level: Default::default(),
 				generation: 0,
-				expiration: Expiration::NamespaceDefault,
+				expiration: match config.policy.expiration_secs {
+					Some(seconds) => Expiration::Seconds(seconds),
+					None => Expiration::NamespaceDefault,
+				},
 				send_key: false,
 				respond_per_each_op: true,
-				durable_delete: false,
+				durable_delete: config.policy.durable_delete,
 				filter_expression: None,
 			},
 			insert_unique_list_policy: ListPolicy {
 				attributes: ListOrderType::Unordered,
 				flags: ListWriteFlags::AddUnique,
 			},
-			list_policy: ListPolicy::new(ListOrderType::Unordered, ListWriteFlags::Default),
+			map_policy: MapPolicy::new(MapOrder::KeyOrdered, MapWriteMode::Update),
+		})
+	}
+	
+	/// Counts the events of a single minute in the records of every tier, one operate per shard
+	/// record they touch
+	fn add_minute_events(&self, minute: i64, tags: &[&AggregateTagEvent]) -> DbResult<()> {
+		let values: Vec<AggregateEventValues> = tags.iter().map(|tag| AggregateEventValues::new(self, tag)).collect();
+		let mut records = 0;
+		for tier in AggregateTier::ALL {
+			let users_capacity = Self::users_capacity(tier) as i64;
+			let mut shards: Vec<Vec<Operation>> = (0..self.tier_shards).map(|_| vec![]).collect();
+			for values in &values {
+				let product = &mut shards[values.product_shard as usize];
+				product.push(maps::increment_value(&self.map_policy, Self::PRODUCT_SUM_BIN, &values.product, &values.price));
+				product.push(maps::increment_value(&self.map_policy, Self::PRODUCT_COUNT_BIN, &values.product, &values.one));
+				for ((cell, context), shard) in values.cells.iter().zip(values.contexts.iter()).zip(values.cell_shards.iter()) {
+					let operations = &mut shards[*shard as usize];
+					operations.push(maps::increment_value(&self.map_policy, Self::SUM_BIN, cell, &values.price));
+					operations.push(maps::increment_value(&self.map_policy, Self::COUNT_BIN, cell, &values.one));
+					// min, max and distinct users are key ordered maps trimmed right after the insert
+					operations.push(maps::put(&self.map_policy, Self::MIN_BIN, &values.price, &values.one).set_context(context));
+					operations.push(maps::remove_by_index_range_from(Self::MIN_BIN, 1, MapReturnType::None).set_context(context));
+					operations.push(maps::put(&self.map_policy, Self::MAX_BIN, &values.negated_price, &values.one).set_context(context));
+					operations.push(maps::remove_by_index_range_from(Self::MAX_BIN, 1, MapReturnType::None).set_context(context));
+				}
+				// distinct users are kept by the full cell only, the last of the combinations
+				let full = values.contexts.last().unwrap();
+				let operations = &mut shards[*values.cell_shards.last().unwrap() as usize];
+				operations.push(maps::put(&self.map_policy, Self::USERS_BIN, &values.fingerprint, &values.one).set_context(full));
+				operations.push(maps::remove_by_index_range_from(Self::USERS_BIN, users_capacity, MapReturnType::None).set_context(full));
+			}
+			
+			// a single operate per record is as atomic as it gets
+			for (shard, operations) in shards.iter().enumerate().filter(|(_, operations)| !operations.is_empty()) {
+				match self.operate(&self.tier_key(tier, tier.of_minute(minute), shard as i64), operations) {
+					Ok(_) => records += 1,
+					Err(err) if records == 0 => return Err(err),
+					Err(err) => return Err(DbError::Partial(format!("events of minute {} counted in {} records only, failed in the {:?} tier: {}", minute, records, tier, err))),
+				}
+			}
+		}
+		Ok(())
+	}
+	
+	/// Drops the oldest entries of the legacy bins past `max_tags`. Nothing appends to them
+	/// anymore and the generation check keeps concurrent reads from trimming them twice.
+	fn trim_legacy(&self, key: &Key, result: &Record) -> DbResult<()> {
+		let operations: Vec<Operation> = [Self::LEGACY_VIEW_BIN, Self::LEGACY_BUY_BIN].into_iter()
+			.filter_map(|bin| match result.bins.get(bin) {
+				// appended oldest first
+				Some(List(list)) if list.len() > self.max_tags => {
+					Some(lists::remove_by_index_range_count(bin, 0, (list.len() - self.max_tags) as i64, ListReturnType::None))
+				},
+				_ => None,
+			})
+			.collect();
+		if operations.is_empty() {
+			return Ok(());
 		}
+		let policy = WritePolicy {
+			generation_policy: GenerationPolicy::ExpectGenEqual,
+			generation: result.generation,
+			..self.write_policy.clone()
+		};
+		match self.client.operate(&policy, key, &operations) {
+			Ok(_) | Err(aerospike::Error(ErrorKind::ServerError(ResultCode::GenerationError), _)) => Ok(()),
+			Err(err) => Err(err.into()),
+		}
+	}
+	
+	/// Whether the client holds a connection to at least one cluster node
+	pub fn is_connected(&self) -> bool {
+		self.client.is_connected()
 	}
 }
 
 impl Database for AerospikeDB {
-	async fn add_user_event(&self, cookie: &Cookie, tag: UserTagEvent, action: UserAction) {
-		let key = as_key!(Self::NAMESPACE, Self::TAG_SET, &cookie.0);
-		let value = as_val!(serde_json::to_string(&tag).unwrap());
-		
-		let action = match action {
-			UserAction::VIEW => Self::VIEW_BIN,
-			UserAction::BUY => Self::BUY_BIN,
+	/// Appends and trims in a single atomic `operate`, see `profile_append_operations`
+	async fn add_user_event(&self, cookie: &Cookie, tag: UserTagEvent, action: UserAction) -> DbResult<()> {
+		self.add_user_events(cookie, vec![(tag, action)]).await
+	}
+	
+	/// All tags of the cookie go into one `operate`, appended and trimmed like in `add_user_event`
+	async fn add_user_events(&self, cookie: &Cookie, tags: Vec<(UserTagEvent, UserAction)>) -> DbResult<()> {
+		let key = self.tag_key(cookie);
+		let entries = |action: UserAction| -> Vec<Value> {
+			tags.iter()
+				.filter(|(_, tag_action)| *tag_action == action)
+				.map(|(tag, _)| profile_entry(tag))
+				.collect()
 		};
-		let add_operation = lists::append(&self.list_policy, &action, &value);
+		let bins = [(Self::VIEW_BIN, entries(UserAction::VIEW)), (Self::BUY_BIN, entries(UserAction::BUY))];
 		
-		let result = self.operate(&key, &vec![add_operation]);
-		if let Int(count) = result.bins.get(action).unwrap() {
-			if *count as usize > MAX_TAGS {
-				let remove_first_operation = lists::remove_by_index(&action, 0, ListReturnType::None);
-				self.operate(&key, &vec![remove_first_operation]);
-			}
+		let operations = profile_append_operations(&bins, self.max_tags);
+		if !operations.is_empty() {
+			self.operate(&key, &operations)?;
 		}
+		Ok(())
 	}
 	
-	async fn get_user_profile(&self, cookie: &Cookie) -> UserProfile {
-		let key = as_key!(Self::NAMESPACE, Self::TAG_SET, &cookie.0);
-		let get_view_operation = lists::get_by_index_range(&Self::VIEW_BIN, 0, ListReturnType::Values);
-		let get_buy_operation = lists::get_by_index_range(&Self::BUY_BIN, 0, ListReturnType::Values);
+	async fn get_user_profile(&self, cookie: &Cookie) -> DbResult<UserProfile> {
+		let key = self.tag_key(cookie);
+		let operations = [Self::VIEW_BIN, Self::BUY_BIN, Self::LEGACY_VIEW_BIN, Self::LEGACY_BUY_BIN]
+			.map(|bin| lists::get_by_index_range(bin, 0, ListReturnType::Values));
 		
-		let result = self.operate(&key, &vec![get_view_operation, get_buy_operation]);
+		let Some(result) = self.try_operate(&key, &operations)? else {
+			return Ok(UserProfile::default());
+		};
 		
-		let value_to_user_tag = |v| -> Option<UserTagEvent> {
-			if let Value::String(str) = v {
-				serde_json::from_str(str.as_str()).ok()
-			} else {
-				None
+		let decode = |bin: &str| -> Vec<UserTagEvent> {
+			match result.bins.get(bin) {
+				Some(List(list)) => list.iter().filter_map(decode_profile_entry).collect(),
+				_ => vec![],
 			}
 		};
-		let value_to_user_tag_list = |v: &Value| -> Option<Vec<UserTagEvent>> {
-			if let List(list) = v.clone() {
-				Some(list.into_iter()
-					.flat_map(value_to_user_tag)
-					.collect())
-			} else {
-				None
+		let events = |bin: &str, legacy_bin: &str| -> Vec<UserTagEvent> {
+			// stored newest first
+			let mut events: Vec<UserTagEvent> = decode(bin).into_iter().rev().collect();
+			let legacy = decode(legacy_bin);
+			if legacy.is_empty() {
+				return events;
 			}
+			// entries of the legacy bins are merged in until they age out
+			events.extend(legacy);
+			events.sort_by_key(|event| event.time);
+			events.split_off(events.len().saturating_sub(self.max_tags))
 		};
 		
-		let view_events = result.bins.get(Self::VIEW_BIN)
-			.and_then(value_to_user_tag_list)
-			.unwrap_or(vec![]);
-		let buy_events = result.bins.get(Self::BUY_BIN)
-			.and_then(value_to_user_tag_list)
-			.unwrap_or(vec![]);
+		let profile = UserProfile {
+			view_events: events(Self::VIEW_BIN, Self::LEGACY_VIEW_BIN),
+			buy_events: events(Self::BUY_BIN, Self::LEGACY_BUY_BIN),
+		};
+		self.trim_legacy(&key, &result)?;
+		Ok(profile)
+	}
+	
+	async fn add_aggregate_event(&self, timestamp: i64, tag: AggregateTagEvent) -> DbResult<()> {
+		self.add_minute_events(timestamp, &[&tag])
+	}
+	
+	/// Events of the same minute share their records, so they are counted together
+	async fn add_aggregate_events(&self, events: Vec<(i64, AggregateTagEvent)>) -> Vec<DbResult<()>> {
+		let mut minutes: HashMap<i64, Vec<usize>> = HashMap::new();
+		for (index, (timestamp, _)) in events.iter().enumerate() {
+			minutes.entry(*timestamp).or_default().push(index);
+		}
 		
-		UserProfile {
-			view_events,
-			buy_events,
+		let mut results = vec![Ok(()); events.len()];
+		for (minute, indexes) in minutes {
+			for chunk in indexes.chunks(Self::EVENTS_PER_OPERATE) {
+				let tags: Vec<&AggregateTagEvent> = chunk.iter().map(|index| &events[*index].1).collect();
+				if let Err(err) = self.add_minute_events(minute, &tags) {
+					for index in chunk {
+						results[*index] = Err(err.clone());
+					}
+				}
+			}
 		}
+		results
 	}
 	
-	async fn add_aggregate_event(&self, timestamp: i64, tag: AggregateTagEvent) {
-		let key = as_key!(Self::NAMESPACE, Self::MINUTE_SET, Self::EMPTY_KEY);
-		let map_key = as_val!(timestamp);
-		let value = as_val!(serde_json::to_string(&tag).unwrap());
+	/// Reads every stored bucket of the range in one batch and picks the requested cell. Only the
+	/// shard of the cell is read, unless the distinct users of the full cells are needed too.
+	async fn get_aggregate(&self, request: &GetAggregateRequest) -> DbResult<GetAggregateResponse> {
+		let cell = CubeKey::of_request(request);
+		let tier = request.granularity.tier();
+		let shards = match request.distinct_users {
+			true => 0..self.tier_shards,
+			false => self.tier_shard(cell.encode())..self.tier_shard(cell.encode()) + 1,
+		};
+		let keys = (request.time_range.start..request.time_range.end)
+			.flat_map(|bucket| request.tier_range(bucket))
+			.flat_map(|index| shards.clone().map(move |shard| (index, shard)))
+			.map(|(index, shard)| self.tier_key(tier, index, shard))
+			.collect();
+		let bins = match request.distinct_users {
+			true => &[Self::SUM_BIN, Self::COUNT_BIN, Self::MIN_BIN, Self::MAX_BIN, Self::USERS_BIN][..],
+			false => &[Self::SUM_BIN, Self::COUNT_BIN, Self::MIN_BIN, Self::MAX_BIN][..],
+		};
+		let records = self.batch_get(keys, bins).await?;
 		
-		let context = [ctx_map_key_create(map_key, MapOrder::KeyOrdered)];
-		let add_operation = lists::append(&self.list_policy, Self::TAG_BIN, &value);
-		let add_operation = add_operation.set_context(&context);
+		let mut records = records.into_iter();
+		let aggregates = (request.time_range.start..request.time_range.end)
+			.map(|bucket| {
+				let mut result = AggregateBucket::default();
+				for record in records.by_ref().take(request.tier_range(bucket).count() * shards.clone().count()).flatten() {
+					result.merge(&retrieve_cell_from_result(&record, &cell, Self::users_capacity(tier)));
+				}
+				result
+			})
+			.collect();
 		
-		self.operate(&key, &vec![add_operation]);
+		Ok(GetAggregateResponse { aggregates })
 	}
 	
-	async fn get_aggregate(&self, request: &GetAggregateRequest) -> GetAggregateResponse {
-		let key = as_key!(Self::NAMESPACE, Self::MINUTE_SET, Self::EMPTY_KEY);
-		let start_key_range = as_val!(request.time_range.start);
-		let end_key_range = as_val!(request.time_range.end);
+	/// Reads every shard of every stored bucket of the range in one batch
+	async fn get_grouped_aggregate(&self, request: &GetAggregateRequest) -> DbResult<GetGroupedAggregateResponse> {
+		let tier = request.granularity.tier();
+		let keys = (request.time_range.start..request.time_range.end)
+			.flat_map(|bucket| request.tier_range(bucket))
+			.flat_map(|index| self.tier_keys(tier, index))
+			.collect();
+		let bins = match request.distinct_users {
+			true => &[Self::SUM_BIN, Self::COUNT_BIN, Self::MIN_BIN, Self::MAX_BIN, Self::USERS_BIN][..],
+			false => &[Self::SUM_BIN, Self::COUNT_BIN, Self::MIN_BIN, Self::MAX_BIN][..],
+		};
+		let records = self.batch_get(keys, bins).await?;
 		
-		let get_tags = maps::get_by_key_range(
-			&Self::TAG_BIN,
-			&start_key_range,
-			&end_key_range,
-			MapReturnType::Value);
+		let mut records = records.into_iter();
+		let groups = (request.time_range.start..request.time_range.end)
+			.map(|bucket| {
+				let mut groups: HashMap<CubeKey, AggregateBucket> = HashMap::new();
+				for record in records.by_ref().take(request.tier_range(bucket).count() * self.tier_shards as usize).flatten() {
+					for (key, cell) in retrieve_cells_from_result(&record, Self::users_capacity(tier)) {
+						if key.is_group_of(request) {
+							groups.entry(key).or_default().merge(&cell);
+						}
+						if let Some(group) = key.group_of(request).filter(|_| request.distinct_users && key.is_full()) {
+							groups.entry(group).or_default().distinct_users.merge(&cell.distinct_users);
+						}
+					}
+				}
+				AggregateGroup::sorted(groups)
+			})
+			.collect();
 		
-		let result = self.operate(&key, &vec![get_tags]);
+		Ok(GetGroupedAggregateResponse { groups })
+	}
+	
+	/// Reads every shard of the tier buckets covering the range in one batch
+	async fn get_top(&self, request: &GetTopRequest) -> DbResult<GetTopResponse> {
+		let bins = match request.dimension.cube_dimension() {
+			Some(_) => [Self::SUM_BIN, Self::COUNT_BIN],
+			None => [Self::PRODUCT_SUM_BIN, Self::PRODUCT_COUNT_BIN],
+		};
+		let keys = AggregateTier::cover(request.time_range.start..request.time_range.end).into_iter()
+			.flat_map(|(tier, index)| self.tier_keys(tier, index))
+			.collect();
+		let records = self.batch_get(keys, &bins).await?;
 		
-		let tag_list = result.bins.get(Self::TAG_BIN);
+		let mut counters: HashMap<u64, TopCounter> = HashMap::new();
+		for record in records.into_iter().flatten() {
+			for (id, counter) in retrieve_top_from_result(request, &record) {
+				counters.entry(id).or_default().merge(&counter);
+			}
+		}
 		
-		println!("Tag list {:?}", tag_list);
+		Ok(GetTopResponse::ranked(counters, request))
+	}
+	
+	async fn get_dictionaries(&self) -> DbResult<Vec<DictionaryFill>> {
+		let key = self.mappings_key();
+		let operations: Vec<Operation> = Dictionary::ALL.iter()
+			.map(|dictionary| lists::size(Self::mapping_bin(*dictionary)))
+			.collect();
+		let result = self.try_operate(&key, &operations)?;
 		
-		todo!()
+		Ok(Dictionary::ALL.iter()
+			.map(|dictionary| {
+				let size = result.as_ref().map(|record| retrieve_int_from_result(Self::mapping_bin(*dictionary), record)).unwrap_or(0);
+				DictionaryFill::new(*dictionary, size as u64)
+			})
+			.collect())
 	}
 }
 
 impl Compressor<UserTagEvent> for AerospikeDB {
-	async fn compress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> UserTagEventCompressedData {
-		let key = as_key!(Self::NAMESPACE, Self::MAPPINGS_SET, Self::EMPTY_KEY);
-		
-		let mut operations_definitions = vec![];
-		self.add_or_get_mapping(&partial.product_id, Self::PRODUCT_ID_BIN, &mut operations_definitions);
-		self.add_or_get_mapping(&partial.brand_id, Self::BRAND_ID_BIN, &mut operations_definitions);
-		self.add_or_get_mapping(&partial.category_id, Self::CATEGORY_ID_BIN, &mut operations_definitions);
-		self.add_or_get_mapping(&partial.country_id, Self::COUNTRY_BIN, &mut operations_definitions);
-		self.add_or_get_mapping(&partial.origin_id, Self::ORIGIN_ID_BIN, &mut operations_definitions);
-		
-		let operations = self.prepare_operations(&mut operations_definitions);
-		
-		let result = self.operate(&key, &operations);
+	async fn compress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> DbResult<UserTagEventCompressedData> {
+		Compressor::<UserTagEvent>::compress_batch_with_partial(self, vec![partial]).await.pop().unwrap()
+	}
+	
+	async fn compress_batch_with_partial(&self, partials: Vec<PartialUserTagEventCompressedData>) -> Vec<DbResult<UserTagEventCompressedData>> {
+		let ids = match self.resolve_mappings(partials.iter()
+			.flat_map(|partial| [
+				unknown_value(Dictionary::ProductId, &partial.product_id),
+				unknown_value(Dictionary::BrandId, &partial.brand_id),
+				unknown_value(Dictionary::CategoryId, &partial.category_id),
+				unknown_value(Dictionary::Country, &partial.country_id),
+				unknown_value(Dictionary::OriginId, &partial.origin_id),
+			])
+			.flatten()) {
+			Ok(ids) => ids,
+			// the whole batch shares the failed round trip
+			Err(err) => return partials.iter().map(|_| Err(err.clone())).collect(),
+		};
 		
-		UserTagEventCompressedData {
-			product_id: retrieve_value_from_mapping_result(Self::PRODUCT_ID_BIN, &result) as u64,
-			brand_id: retrieve_value_from_mapping_result(Self::BRAND_ID_BIN, &result) as u16,
-			category_id: retrieve_value_from_mapping_result(Self::CATEGORY_ID_BIN, &result) as u16,
-			country_id: retrieve_value_from_mapping_result(Self::COUNTRY_BIN, &result) as u8,
-			origin_id: retrieve_value_from_mapping_result(Self::ORIGIN_ID_BIN, &result) as u16,
-		}
+		partials.iter()
+			.map(|partial| Ok(UserTagEventCompressedData {
+				product_id: resolve_id(&ids, Dictionary::ProductId, &partial.product_id)?,
+				brand_id: resolve_id(&ids, Dictionary::BrandId, &partial.brand_id)? as u16,
+				category_id: resolve_id(&ids, Dictionary::CategoryId, &partial.category_id)? as u16,
+				country_id: resolve_id(&ids, Dictionary::Country, &partial.country_id)? as u8,
+				origin_id: resolve_id(&ids, Dictionary::OriginId, &partial.origin_id)? as u16,
+			}))
+			.collect()
 	}
 }
 
 impl Decompressor<UserTagEvent> for AerospikeDB {
-	async fn decompress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> UserTagEventDecompressedData {
-		// let key = as_key!(Self::NAMESPACE, Self::MAPPINGS_SET, Self::EMPTY_KEY);
-		// let mut operations_definitions = vec![];
-		//
-		// // self.add_or_get_mapping(&value.origin, Self::ORIGIN_ID_BIN, &mut operations_definitions);
-		//
-		// let mut operations = vec![];
-		// for (bin, value) in operations_definitions.iter() {
-		// 	operations.push(lists::append(&self.insert_unique_list_policy, bin, value));
-		// }
-		//
-		// UserTagEventDecompressedData {
-		// 	product_id: "".to_string(),
-		// 	brand_id: "".to_string(),
-		// 	category_id: "".to_string(),
-		// 	country: "".to_string(),
-		// 	origin: "".to_string(),
-		// }
-		todo!()
+	async fn decompress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> DbResult<UserTagEventDecompressedData> {
+		let key = self.mappings_key();
+		
+		let mut operations = vec![];
+		self.get_reverse_mapping(&partial.product_id, Self::PRODUCT_ID_BIN, &mut operations);
+		self.get_reverse_mapping(&partial.brand_id, Self::BRAND_ID_BIN, &mut operations);
+		self.get_reverse_mapping(&partial.category_id, Self::CATEGORY_ID_BIN, &mut operations);
+		self.get_reverse_mapping(&partial.country_id, Self::COUNTRY_BIN, &mut operations);
+		self.get_reverse_mapping(&partial.origin_id, Self::ORIGIN_ID_BIN, &mut operations);
+		
+		let result = match operations.is_empty() {
+			true => None,
+			false => Some(self.operate(&key, &operations)?),
+		};
+		
+		Ok(UserTagEventDecompressedData {
+			product_id: retrieve_string_from_mapping_result(Self::PRODUCT_ID_BIN, partial.product_id, result.as_ref())?,
+			brand_id: retrieve_string_from_mapping_result(Self::BRAND_ID_BIN, partial.brand_id, result.as_ref())?,
+			category_id: retrieve_string_from_mapping_result(Self::CATEGORY_ID_BIN, partial.category_id, result.as_ref())?,
+			country_id: retrieve_string_from_mapping_result(Self::COUNTRY_BIN, partial.country_id, result.as_ref())?,
+			origin_id: retrieve_string_from_mapping_result(Self::ORIGIN_ID_BIN, partial.origin_id, result.as_ref())?,
+		})
 	}
 }
 
 
 impl Compressor<AggregateTagEvent> for AerospikeDB {
-	async fn compress_with_partial(&self, partial: PartialAggregateTagEventCompressedData) -> AggregateTagEventCompressedData {
-		let key = as_key!(Self::NAMESPACE, Self::MAPPINGS_SET, Self::EMPTY_KEY);
+	async fn compress_with_partial(&self, partial: PartialAggregateTagEventCompressedData) -> DbResult<AggregateTagEventCompressedData> {
+		let ids = self.resolve_mappings([
+			unknown_value(Dictionary::ProductId, &partial.product_id),
+			unknown_value(Dictionary::BrandId, &partial.brand_id),
+			unknown_value(Dictionary::CategoryId, &partial.category_id),
+			unknown_value(Dictionary::OriginId, &partial.origin_id),
+		].into_iter().flatten())?;
+		
+		Ok(AggregateTagEventCompressedData {
+			product_id: resolve_id(&ids, Dictionary::ProductId, &partial.product_id)?,
+			brand_id: resolve_id(&ids, Dictionary::BrandId, &partial.brand_id)? as u16,
+			category_id: resolve_id(&ids, Dictionary::CategoryId, &partial.category_id)? as u16,
+			origin_id: resolve_id(&ids, Dictionary::OriginId, &partial.origin_id)? as u16,
+		})
+	}
+}
+
+impl Compressor<GetAggregateRequest> for AerospikeDB {
+	/// Read-only: unknown filter values are reported through `has_unknown_value` instead of
+	/// being inserted into the mappings
+	async fn compress_with_partial(&self, partial: PartialGetAggregateRequestCompressedData) -> DbResult<GetAggregateRequestCompressedData> {
+		let key = self.mappings_key();
 		
 		let mut operations_definitions = vec![];
-		self.add_or_get_mapping(&partial.brand_id, Self::BRAND_ID_BIN, &mut operations_definitions);
-		self.add_or_get_mapping(&partial.category_id, Self::CATEGORY_ID_BIN, &mut operations_definitions);
-		self.add_or_get_mapping(&partial.origin_id, Self::ORIGIN_ID_BIN, &mut operations_definitions);
+		self.get_mapping_if_present(&partial.origin_id, Self::ORIGIN_ID_BIN, &mut operations_definitions);
+		self.get_mapping_if_present(&partial.brand_id, Self::BRAND_ID_BIN, &mut operations_definitions);
+		self.get_mapping_if_present(&partial.category_id, Self::CATEGORY_ID_BIN, &mut operations_definitions);
 		
-		let operations = self.prepare_operations(&mut operations_definitions);
+		let operations: Vec<Operation> = operations_definitions.iter()
+			.map(|(bin, value)| lists::get_by_value(bin, value, ListReturnType::Index))
+			.collect();
 		
-		let result = self.operate(&key, &operations);
+		let result = match operations.is_empty() {
+			true => None,
+			false => self.try_operate(&key, &operations)?,
+		};
 		
-		AggregateTagEventCompressedData {
-			brand_id: retrieve_value_from_mapping_result(Self::BRAND_ID_BIN, &result) as u16,
-			category_id: retrieve_value_from_mapping_result(Self::CATEGORY_ID_BIN, &result) as u16,
-			origin_id: retrieve_value_from_mapping_result(Self::ORIGIN_ID_BIN, &result) as u16,
-		}
+		let mut has_unknown_value = false;
+		let mut resolve = |value: Partial<Option<String>, Option<u16>>, bin: &str| match value {
+			Partial::Same(Some(_)) => {
+				let id = retrieve_optional_value_from_mapping_result(bin, result.as_ref());
+				has_unknown_value |= id.is_none();
+				id.map(|x| x as u16)
+			},
+			Partial::Same(None) => None,
+			Partial::Changed(x) => x,
+		};
+		
+		Ok(GetAggregateRequestCompressedData {
+			origin_id: resolve(partial.origin_id, Self::ORIGIN_ID_BIN),
+			brand_id: resolve(partial.brand_id, Self::BRAND_ID_BIN),
+			category_id: resolve(partial.category_id, Self::CATEGORY_ID_BIN),
+			has_unknown_value,
+		})
 	}
 }
 
-impl Compressor<GetAggregateRequest> for AerospikeDB {
-	async fn compress_with_partial(&self, partial: PartialGetAggregateRequestCompressedData) -> GetAggregateRequestCompressedData {
-		todo!()
+impl Decompressor<CubeKey> for AerospikeDB {
+	async fn decompress_with_partial(&self, partial: PartialCubeKeyDecompressedData) -> DbResult<CubeKeyDecompressedData> {
+		let key = self.mappings_key();
+		
+		let mut operations = vec![];
+		self.get_optional_reverse_mapping(&partial.origin_id, Self::ORIGIN_ID_BIN, &mut operations);
+		self.get_optional_reverse_mapping(&partial.brand_id, Self::BRAND_ID_BIN, &mut operations);
+		self.get_optional_reverse_mapping(&partial.category_id, Self::CATEGORY_ID_BIN, &mut operations);
+		
+		let result = match operations.is_empty() {
+			true => None,
+			false => Some(self.operate(&key, &operations)?),
+		};
+		
+		let resolve = |value: Partial<Option<String>, Option<u16>>, bin: &str| -> DbResult<Option<String>> {
+			match value {
+				Partial::Same(x) => Ok(x),
+				Partial::Changed(None) => Ok(None),
+				Partial::Changed(Some(id)) => Ok(Some(retrieve_string_from_mapping_result(bin, Partial::Changed(id), result.as_ref())?)),
+			}
+		};
+		
+		Ok(CubeKeyDecompressedData {
+			origin_id: resolve(partial.origin_id, Self::ORIGIN_ID_BIN)?,
+			brand_id: resolve(partial.brand_id, Self::BRAND_ID_BIN)?,
+			category_id: resolve(partial.category_id, Self::CATEGORY_ID_BIN)?,
+		})
+	}
+}
+
+impl Decompressor<TopKey> for AerospikeDB {
+	async fn decompress_with_partial(&self, partial: PartialTopKeyDecompressedData) -> DbResult<Option<String>> {
+		match partial.value {
+			Partial::Same(x) => Ok(Some(x)),
+			Partial::Changed(id) => {
+				let key = self.mappings_key();
+				let bin = Self::mapping_bin(partial.dimension.dictionary());
+				let result = self.try_operate(&key, &[lists::get_by_index(bin, id as i64, ListReturnType::Values)])?;
+				match result.as_ref().and_then(|record| record.bins.get(bin)) {
+					Some(Value::String(out)) => Ok(Some(out.clone())),
+					_ => Ok(None),
+				}
+			},
+		}
 	}
 }
 
@@ -0,0 +1,179 @@
+use crate::api::*;
+use crate::config::Backend;
+use crate::data::*;
+use crate::metrics::{METRICS, observe_db_call};
+use crate::database::{AerospikeDB, CachedDB, Compressor, Database, DbResult, Decompressor, LocalDB};
+
+/// Database picked at startup. The traits are not object safe, so every call is forwarded
+/// to the configured backend by a `match` instead.
+pub enum BackendDB {
+	Local(LocalDB),
+	/// Boxed, the write queue and the hot profiles make it much larger than the others
+	CachedAerospike(Box<CachedDB<LocalDB, AerospikeDB>>),
+	Aerospike(AerospikeDB),
+}
+
+macro_rules! dispatch {
+	($self:ident, $db:ident => $call:expr) => {
+		match $self {
+			BackendDB::Local($db) => $call,
+			BackendDB::CachedAerospike($db) => {
+				let $db = &**$db;
+				$call
+			},
+			BackendDB::Aerospike($db) => $call,
+		}
+	};
+}
+
+impl BackendDB {
+	pub fn backend(&self) -> Backend {
+		match self {
+			BackendDB::Local(_) => Backend::Local,
+			BackendDB::CachedAerospike(_) => Backend::CachedAerospike,
+			BackendDB::Aerospike(_) => Backend::Aerospike,
+		}
+	}
+	
+	/// In-memory database of the backend, if it keeps one
+	pub fn local_db(&self) -> Option<&LocalDB> {
+		match self {
+			BackendDB::Local(db) => Some(db),
+			BackendDB::CachedAerospike(db) => Some(db.local_db()),
+			BackendDB::Aerospike(_) => None,
+		}
+	}
+	
+	/// Aerospike behind the backend, if it uses one
+	pub fn remote_db(&self) -> Option<&AerospikeDB> {
+		match self {
+			BackendDB::Local(_) => None,
+			BackendDB::CachedAerospike(db) => Some(db.remote_db()),
+			BackendDB::Aerospike(db) => Some(db),
+		}
+	}
+	
+	/// Events queued for a remote write and not written yet
+	pub fn pending_writes(&self) -> usize {
+		match self {
+			BackendDB::CachedAerospike(db) => db.pending_writes(),
+			_ => 0,
+		}
+	}
+	
+	/// Events the backend gave up writing
+	pub fn lost_writes(&self) -> u64 {
+		match self {
+			BackendDB::CachedAerospike(db) => db.lost_writes(),
+			_ => 0,
+		}
+	}
+	
+	/// Writes everything the backend still holds back
+	pub async fn flush(&self) {
+		if let BackendDB::CachedAerospike(db) = self {
+			db.flush().await;
+		}
+	}
+}
+
+impl Database for BackendDB {
+	async fn add_user_event(&self, cookie: &Cookie, tag: UserTagEvent, action: UserAction) -> DbResult<()> {
+		observe_db_call("add_user_event", async { dispatch!(self, db => db.add_user_event(cookie, tag, action).await) }).await
+	}
+	
+	async fn add_user_events(&self, cookie: &Cookie, tags: Vec<(UserTagEvent, UserAction)>) -> DbResult<()> {
+		observe_db_call("add_user_events", async { dispatch!(self, db => db.add_user_events(cookie, tags).await) }).await
+	}
+	
+	async fn get_user_profile(&self, cookie: &Cookie) -> DbResult<UserProfile> {
+		observe_db_call("get_user_profile", async { dispatch!(self, db => db.get_user_profile(cookie).await) }).await
+	}
+	
+	async fn add_aggregate_event(&self, timestamp: i64, tag: AggregateTagEvent) -> DbResult<()> {
+		observe_db_call("add_aggregate_event", async { dispatch!(self, db => db.add_aggregate_event(timestamp, tag).await) }).await
+	}
+	
+	/// The call as a whole never fails, the errors of its events are recorded one by one
+	async fn add_aggregate_events(&self, events: Vec<(i64, AggregateTagEvent)>) -> Vec<DbResult<()>> {
+		let results = observe_db_call("add_aggregate_events", async { Ok(dispatch!(self, db => db.add_aggregate_events(events).await)) })
+			.await
+			.unwrap_or_default();
+		for err in results.iter().filter_map(|result| result.as_ref().err()) {
+			METRICS.record_db_error("add_aggregate_events", err);
+		}
+		results
+	}
+	
+	async fn get_aggregate(&self, request: &GetAggregateRequest) -> DbResult<GetAggregateResponse> {
+		observe_db_call("get_aggregate", async { dispatch!(self, db => db.get_aggregate(request).await) }).await
+	}
+	
+	async fn get_grouped_aggregate(&self, request: &GetAggregateRequest) -> DbResult<GetGroupedAggregateResponse> {
+		observe_db_call("get_grouped_aggregate", async { dispatch!(self, db => db.get_grouped_aggregate(request).await) }).await
+	}
+	
+	async fn get_top(&self, request: &GetTopRequest) -> DbResult<GetTopResponse> {
+		observe_db_call("get_top", async { dispatch!(self, db => db.get_top(request).await) }).await
+	}
+	
+	async fn get_dictionaries(&self) -> DbResult<Vec<DictionaryFill>> {
+		observe_db_call("get_dictionaries", async { dispatch!(self, db => db.get_dictionaries().await) }).await
+	}
+}
+
+impl Compressor<UserTagEvent> for BackendDB {
+	async fn compress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> DbResult<UserTagEventCompressedData> {
+		dispatch!(self, db => Compressor::<UserTagEvent>::compress_with_partial(db, partial).await)
+	}
+	
+	async fn compress_batch_with_partial(&self, partials: Vec<PartialUserTagEventCompressedData>) -> Vec<DbResult<UserTagEventCompressedData>> {
+		dispatch!(self, db => Compressor::<UserTagEvent>::compress_batch_with_partial(db, partials).await)
+	}
+}
+
+impl Compressor<AggregateTagEvent> for BackendDB {
+	async fn compress_with_partial(&self, partial: PartialAggregateTagEventCompressedData) -> DbResult<AggregateTagEventCompressedData> {
+		dispatch!(self, db => Compressor::<AggregateTagEvent>::compress_with_partial(db, partial).await)
+	}
+	
+	async fn compress_batch_with_partial(&self, partials: Vec<PartialAggregateTagEventCompressedData>) -> Vec<DbResult<AggregateTagEventCompressedData>> {
+		dispatch!(self, db => Compressor::<AggregateTagEvent>::compress_batch_with_partial(db, partials).await)
+	}
+}
+
+impl Compressor<GetAggregateRequest> for BackendDB {
+	async fn compress_with_partial(&self, partial: PartialGetAggregateRequestCompressedData) -> DbResult<GetAggregateRequestCompressedData> {
+		dispatch!(self, db => Compressor::<GetAggregateRequest>::compress_with_partial(db, partial).await)
+	}
+}
+
+impl Decompressor<UserTagEvent> for BackendDB {
+	async fn decompress(&self, value: &UserTagEvent) -> DbResult<UserTagEventDecompressedData> {
+		dispatch!(self, db => Decompressor::<UserTagEvent>::decompress(db, value).await)
+	}
+	
+	async fn decompress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> DbResult<UserTagEventDecompressedData> {
+		dispatch!(self, db => Decompressor::<UserTagEvent>::decompress_with_partial(db, partial).await)
+	}
+}
+
+impl Decompressor<CubeKey> for BackendDB {
+	async fn decompress(&self, value: &CubeKey) -> DbResult<CubeKeyDecompressedData> {
+		dispatch!(self, db => Decompressor::<CubeKey>::decompress(db, value).await)
+	}
+	
+	async fn decompress_with_partial(&self, partial: PartialCubeKeyDecompressedData) -> DbResult<CubeKeyDecompressedData> {
+		dispatch!(self, db => Decompressor::<CubeKey>::decompress_with_partial(db, partial).await)
+	}
+}
+
+impl Decompressor<TopKey> for BackendDB {
+	async fn decompress(&self, value: &TopKey) -> DbResult<Option<String>> {
+		dispatch!(self, db => Decompressor::<TopKey>::decompress(db, value).await)
+	}
+	
+	async fn decompress_with_partial(&self, partial: PartialTopKeyDecompressedData) -> DbResult<Option<String>> {
+		dispatch!(self, db => Decompressor::<TopKey>::decompress_with_partial(db, partial).await)
+	}
+}
@@ -1,94 +1,283 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::api::*;
 use crate::data::*;
-use crate::database::{Compressor, Database, Decompressor, PartialCompressor, PartialDecompressor};
+use crate::metrics::{Lookup, METRICS};
+use crate::config::{CacheConfig, WriteQueueConfig};
+use crate::database::{Compressor, Database, DbResult, Decompressor, HotProfiles, HotRead, PartialCompressor, PartialDecompressor, UnflushedSnapshot, UnflushedWatch, Write, WriteQueue};
 
 pub trait Synced: Send + Sync + 'static {}
 pub trait CompressingDB: Synced {}
 pub trait SyncedDB: Synced + Database {}
 
+/// Remote profile reads a write of the cookie may race before its unflushed events are merged
+/// by value, see `CachedDB::read_profile`
+const PROFILE_READ_ATTEMPTS: usize = 3;
+const PROFILE_READ_BACKOFF: Duration = Duration::from_millis(2);
+
 pub struct CachedDB<L: CompressingDB, T: SyncedDB> {
 	local_db: L,
 	remote_db: Arc<T>,
+	write_queue: WriteQueue,
+	max_tags: usize,
+	hot_profiles: Option<HotProfiles>,
 }
 
 impl<L: CompressingDB, T: SyncedDB> CachedDB<L, T> {
-	pub fn new(local_db: L, remote_db: T) -> Self {
-		Self {
+	/// Fails when the writers of the write-behind queue cannot be started
+	pub fn new(local_db: L, remote_db: T, write_queue: &WriteQueueConfig) -> std::io::Result<Self> {
+		let remote_db = Arc::new(remote_db);
+		Ok(Self {
 			local_db,
-			remote_db: Arc::new(remote_db),
+			write_queue: WriteQueue::new(write_queue, remote_db.clone())?,
+			remote_db,
+			max_tags: MAX_TAGS,
+			hot_profiles: None,
+		})
+	}
+	
+	/// Number of tags of every action kept in a profile merged with the unflushed events
+	pub fn with_max_tags(mut self, max_tags: usize) -> Self {
+		self.max_tags = max_tags;
+		self
+	}
+	
+	/// Keeps the profiles of the cookies read often in memory and serves them from there, see
+	/// `HotProfiles`. Only correct while every tag of those cookies goes through this instance.
+	pub fn with_local_profiles(mut self, config: &CacheConfig) -> Self {
+		self.hot_profiles = config.local_profiles.then(|| HotProfiles::new(config));
+		self
+	}
+	
+	pub fn local_db(&self) -> &L {
+		&self.local_db
+	}
+	
+	pub fn remote_db(&self) -> &T {
+		&self.remote_db
+	}
+	
+	/// Events queued for the remote database and not written yet
+	pub fn pending_writes(&self) -> usize {
+		self.write_queue.pending()
+	}
+	
+	/// Events given up on, dropped on a full queue or failed after every retry
+	pub fn lost_writes(&self) -> u64 {
+		self.write_queue.lost()
+	}
+	
+	/// Profile of the cookie served from memory, if any
+	pub fn cached_profile(&self, cookie: &Cookie) -> Option<UserProfile> {
+		self.hot_profiles.as_ref()?.cached(cookie)
+	}
+	
+	/// Writes everything still queued, new writes are rejected from now on
+	pub async fn flush(&self) {
+		self.write_queue.flush().await
+	}
+	
+	/// The remote profile with the unflushed events merged in. The remote read holds exactly the
+	/// events flushed before it only if no write of the cookie ran meanwhile, so it is repeated
+	/// a few times before the unflushed events equal to ones in the profile are skipped instead.
+	/// The unflushed events merged are returned with a profile read that way.
+	async fn read_profile(&self, cookie: &Cookie, watch: &UnflushedWatch<'_>) -> DbResult<(UserProfile, Option<UnflushedSnapshot>)> {
+		let mut attempts = 1;
+		loop {
+			let unflushed = watch.snapshot();
+			let mut profile = self.remote_db.get_user_profile(cookie).await?;
+			let later = watch.snapshot();
+			if unflushed.settled_until(&later) {
+				// events queued during the read are not written yet either
+				for (_, tag, action) in &later.events {
+					profile.add(*tag, *action, self.max_tags);
+				}
+				return Ok((profile, Some(later)));
+			}
+			if attempts == PROFILE_READ_ATTEMPTS {
+				profile.add_missing(later.events.into_iter().map(|(_, tag, action)| (tag, action)), self.max_tags);
+				return Ok((profile, None));
+			}
+			attempts += 1;
+			tokio::time::sleep(PROFILE_READ_BACKOFF).await;
 		}
 	}
 }
 
 impl<L: CompressingDB, T: SyncedDB> Database for CachedDB<L, T> {
-	async fn add_user_event(&self, cookie: &Cookie, tag: UserTagEvent, action: UserAction) {
-		let remote = self.remote_db.clone();
-		let cookie = cookie.clone();
-		tokio::spawn(async move {
-			remote.add_user_event(&cookie.clone(), tag, action).await;
-		});
+	/// Remote writes go through the write-behind queue, failing only when it rejects them
+	async fn add_user_event(&self, cookie: &Cookie, tag: UserTagEvent, action: UserAction) -> DbResult<()> {
+		self.add_user_events(cookie, vec![(tag, action)]).await
 	}
 	
-	async fn get_user_profile(&self, cookie: &Cookie) -> UserProfile {
-		self.remote_db.get_user_profile(cookie).await
+	async fn add_user_events(&self, cookie: &Cookie, tags: Vec<(UserTagEvent, UserAction)>) -> DbResult<()> {
+		let Some(hot_profiles) = &self.hot_profiles else {
+			return self.write_queue.push(Write::UserEvents { cookie: cookie.clone(), tags, seqs: vec![] }).await.map(|_| ());
+		};
+		let seqs = self.write_queue.push(Write::UserEvents { cookie: cookie.clone(), tags: tags.clone(), seqs: vec![] }).await?;
+		hot_profiles.add(cookie, &seqs, &tags, self.max_tags);
+		Ok(())
 	}
 	
-	async fn add_aggregate_event(&self, timestamp: i64, tag: AggregateTagEvent) {
-		let remote = self.remote_db.clone();
-		tokio::spawn(async move {
-			remote.add_aggregate_event(timestamp, tag).await;
-		});
+	/// The remote profile with the events still waiting in the write queue merged in, or the
+	/// cached one of a hot cookie
+	async fn get_user_profile(&self, cookie: &Cookie) -> DbResult<UserProfile> {
+		let read = match &self.hot_profiles {
+			Some(hot_profiles) => hot_profiles.read(cookie),
+			None => HotRead::Cold,
+		};
+		if let HotRead::Cached(profile) = read {
+			return Ok(profile);
+		}
+		let watch = self.write_queue.watch(cookie);
+		let (profile, unflushed) = self.read_profile(cookie, &watch).await?;
+		if let (Some(hot_profiles), HotRead::Hot, Some(unflushed)) = (&self.hot_profiles, read, unflushed) {
+			hot_profiles.fill(cookie, profile.clone(), &unflushed, || watch.snapshot(), self.max_tags);
+		}
+		Ok(profile)
+	}
+	
+	async fn add_aggregate_event(&self, timestamp: i64, tag: AggregateTagEvent) -> DbResult<()> {
+		self.write_queue.push(Write::AggregateEvents { timestamp, tags: vec![tag] }).await.map(|_| ())
+	}
+	
+	/// Queues a single write for the events of every minute
+	async fn add_aggregate_events(&self, events: Vec<(i64, AggregateTagEvent)>) -> Vec<DbResult<()>> {
+		let mut results = vec![Ok(()); events.len()];
+		let mut minutes: HashMap<i64, (Vec<usize>, Vec<AggregateTagEvent>)> = HashMap::new();
+		for (index, (timestamp, tag)) in events.into_iter().enumerate() {
+			let (indexes, tags) = minutes.entry(timestamp).or_default();
+			indexes.push(index);
+			tags.push(tag);
+		}
+		
+		for (timestamp, (indexes, tags)) in minutes {
+			if let Err(err) = self.write_queue.push(Write::AggregateEvents { timestamp, tags }).await {
+				for index in indexes {
+					results[index] = Err(err.clone());
+				}
+			}
+		}
+		results
 	}
 	
-	async fn get_aggregate(&self, request: &GetAggregateRequest) -> GetAggregateResponse {
+	async fn get_aggregate(&self, request: &GetAggregateRequest) -> DbResult<GetAggregateResponse> {
 		self.remote_db.get_aggregate(request).await
 	}
+	
+	async fn get_grouped_aggregate(&self, request: &GetAggregateRequest) -> DbResult<GetGroupedAggregateResponse> {
+		self.remote_db.get_grouped_aggregate(request).await
+	}
+	
+	async fn get_top(&self, request: &GetTopRequest) -> DbResult<GetTopResponse> {
+		self.remote_db.get_top(request).await
+	}
+	
+	async fn get_dictionaries(&self) -> DbResult<Vec<DictionaryFill>> {
+		self.remote_db.get_dictionaries().await
+	}
 }
 
 impl<L: CompressingDB + PartialCompressor<UserTagEvent>, T: SyncedDB + Compressor<UserTagEvent>> Compressor<UserTagEvent> for CachedDB<L, T> {
-	async fn compress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> UserTagEventCompressedData {
+	async fn compress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> DbResult<UserTagEventCompressedData> {
 		let compressed_locally = self.local_db.partial_compress_with_partial(partial).await;
-		let compressed = self.remote_db.compress_with_partial(compressed_locally.clone()).await;
+		METRICS.record_lookups(Lookup::Compress, &compressed_locally);
+		let compressed = self.remote_db.compress_with_partial(compressed_locally.clone()).await?;
 		self.local_db.update_compression(&compressed_locally, &compressed).await;
+		Ok(compressed)
+	}
+	
+	async fn compress_batch_with_partial(&self, partials: Vec<PartialUserTagEventCompressedData>) -> Vec<DbResult<UserTagEventCompressedData>> {
+		let mut compressed_locally = vec![];
+		for partial in partials {
+			let partial = self.local_db.partial_compress_with_partial(partial).await;
+			METRICS.record_lookups(Lookup::Compress, &partial);
+			compressed_locally.push(partial);
+		}
+		let compressed = self.remote_db.compress_batch_with_partial(compressed_locally.clone()).await;
+		for (partial, compressed) in compressed_locally.iter().zip(compressed.iter()) {
+			if let Ok(compressed) = compressed {
+				self.local_db.update_compression(partial, compressed).await;
+			}
+		}
 		compressed
 	}
 }
 
 impl<L: CompressingDB + PartialDecompressor<UserTagEvent>, T: SyncedDB + Decompressor<UserTagEvent>> Decompressor<UserTagEvent> for CachedDB<L, T> {
-	async fn decompress(&self, value: &UserTagEvent) -> UserTagEventDecompressedData {
-		let partial = PartialUserTagEventCompressedData::from(value.clone());
+	async fn decompress(&self, value: &UserTagEvent) -> DbResult<UserTagEventDecompressedData> {
+		let partial = PartialUserTagEventCompressedData::from(*value);
 		let decompressed_locally = self.local_db.partial_decompress_with_partial(partial).await;
-		let decompressed = self.remote_db.decompress_with_partial(decompressed_locally.clone()).await;
-		self.local_db.update_compression(&decompressed_locally, &value).await;
-		decompressed
+		METRICS.record_lookups(Lookup::Decompress, &decompressed_locally);
+		let decompressed = self.remote_db.decompress_with_partial(decompressed_locally.clone()).await?;
+		self.local_db.update_compression(&decompressed_locally, value).await;
+		Ok(decompressed)
 	}
 	
 	// Does not update local compression database
-	async fn decompress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> UserTagEventDecompressedData {
+	async fn decompress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> DbResult<UserTagEventDecompressedData> {
 		// let partial = PartialUserTagEventCompressedData::from(value.clone());
 		let decompressed_locally = self.local_db.partial_decompress_with_partial(partial).await;
-		let decompressed = self.remote_db.decompress_with_partial(decompressed_locally.clone()).await;
+		METRICS.record_lookups(Lookup::Decompress, &decompressed_locally);
 		// self.local_db.update_compression(&decompressed_locally, &value).await;
-		decompressed
+		self.remote_db.decompress_with_partial(decompressed_locally).await
 	}
 }
 
 impl<L: CompressingDB + PartialCompressor<AggregateTagEvent>, T: SyncedDB + Compressor<AggregateTagEvent>> Compressor<AggregateTagEvent> for CachedDB<L, T> {
-	async fn compress_with_partial(&self, partial: PartialAggregateTagEventCompressedData) -> AggregateTagEventCompressedData {
+	async fn compress_with_partial(&self, partial: PartialAggregateTagEventCompressedData) -> DbResult<AggregateTagEventCompressedData> {
 		let compressed_locally = self.local_db.partial_compress_with_partial(partial).await;
-		let compressed = self.remote_db.compress_with_partial(compressed_locally.clone()).await;
+		METRICS.record_lookups(Lookup::Compress, &compressed_locally);
+		let compressed = self.remote_db.compress_with_partial(compressed_locally.clone()).await?;
 		self.local_db.update_compression(&compressed_locally, &compressed).await;
-		compressed
+		Ok(compressed)
 	}
 }
 
 impl<L: CompressingDB + PartialCompressor<GetAggregateRequest>, T: SyncedDB + Compressor<GetAggregateRequest>> Compressor<GetAggregateRequest> for CachedDB<L, T> {
-	async fn compress_with_partial(&self, partial: PartialGetAggregateRequestCompressedData) -> GetAggregateRequestCompressedData {
+	async fn compress_with_partial(&self, partial: PartialGetAggregateRequestCompressedData) -> DbResult<GetAggregateRequestCompressedData> {
 		let compressed_locally = self.local_db.partial_compress_with_partial(partial).await;
-		let compressed = self.remote_db.compress_with_partial(compressed_locally.clone()).await;
+		METRICS.record_lookups(Lookup::Compress, &compressed_locally);
+		let compressed = self.remote_db.compress_with_partial(compressed_locally.clone()).await?;
 		self.local_db.update_compression(&compressed_locally, &compressed).await;
-		compressed
+		Ok(compressed)
 	}
-}
\ No newline at end of file
+}
+
+impl<L: CompressingDB + PartialDecompressor<CubeKey>, T: SyncedDB + Decompressor<CubeKey>> Decompressor<CubeKey> for CachedDB<L, T> {
+	async fn decompress(&self, value: &CubeKey) -> DbResult<CubeKeyDecompressedData> {
+		let partial = PartialCubeKeyDecompressedData::from(*value);
+		let decompressed_locally = self.local_db.partial_decompress_with_partial(partial).await;
+		METRICS.record_lookups(Lookup::Decompress, &decompressed_locally);
+		let decompressed = self.remote_db.decompress_with_partial(decompressed_locally).await?;
+		self.local_db.update_compression(&decompressed.clone().into(), value).await;
+		Ok(decompressed)
+	}
+	
+	async fn decompress_with_partial(&self, partial: PartialCubeKeyDecompressedData) -> DbResult<CubeKeyDecompressedData> {
+		let decompressed_locally = self.local_db.partial_decompress_with_partial(partial).await;
+		METRICS.record_lookups(Lookup::Decompress, &decompressed_locally);
+		self.remote_db.decompress_with_partial(decompressed_locally).await
+	}
+}
+
+impl<L: CompressingDB + PartialDecompressor<TopKey>, T: SyncedDB + Decompressor<TopKey>> Decompressor<TopKey> for CachedDB<L, T> {
+	async fn decompress(&self, value: &TopKey) -> DbResult<Option<String>> {
+		let decompressed_locally = self.local_db.partial_decompress_with_partial(PartialTopKeyDecompressedData::from(*value)).await;
+		METRICS.record_lookups(Lookup::Decompress, &decompressed_locally);
+		let decompressed = self.remote_db.decompress_with_partial(decompressed_locally.clone()).await?;
+		if let (Partial::Changed(_), Some(x)) = (&decompressed_locally.value, &decompressed) {
+			let partial = PartialTopKeyDecompressedData { dimension: value.dimension, value: Partial::Same(x.clone()) };
+			self.local_db.update_compression(&partial, value).await;
+		}
+		Ok(decompressed)
+	}
+	
+	async fn decompress_with_partial(&self, partial: PartialTopKeyDecompressedData) -> DbResult<Option<String>> {
+		let decompressed_locally = self.local_db.partial_decompress_with_partial(partial).await;
+		METRICS.record_lookups(Lookup::Decompress, &decompressed_locally);
+		self.remote_db.decompress_with_partial(decompressed_locally).await
+	}
+}
@@ -0,0 +1,99 @@
+use std::fmt::{Display, Formatter};
+
+use aerospike::{ErrorKind, ResultCode};
+
+use crate::data::IdSpaceOverflow;
+
+/// Failure of a database call, detailed enough for the endpoints to pick a status code
+#[derive(Clone, Debug)]
+pub enum DbError {
+	/// The backend did not answer in time, the call may have been applied anyway
+	Timeout(String),
+	/// The call was not sent or the backend turned it down before applying it, because it could
+	/// not be reached or the record or its device was too busy
+	Unavailable(String),
+	/// A record or a dictionary entry that has to exist is missing
+	NotFound(String),
+	/// The backend failed or answered with something that could not be interpreted
+	Protocol(String),
+	/// A new value does not fit into its dictionary
+	Overflow(IdSpaceOverflow),
+	/// The write-behind queue is full or closed
+	Overloaded(String),
+	/// The local write-ahead log could not be written
+	Storage(String),
+	/// A timestamp lies too far from the data held
+	OutOfRange(String),
+	/// A write spanning several records was applied to some of them only, so repeating it
+	/// would count it twice
+	Partial(String),
+}
+
+pub type DbResult<T> = Result<T, DbError>;
+
+impl DbError {
+	/// Name of the variant, used as a metric label
+	pub fn kind(&self) -> &'static str {
+		match self {
+			DbError::Timeout(_) => "timeout",
+			DbError::Unavailable(_) => "unavailable",
+			DbError::NotFound(_) => "not_found",
+			DbError::Protocol(_) => "protocol",
+			DbError::Overflow(_) => "overflow",
+			DbError::Overloaded(_) => "overloaded",
+			DbError::Storage(_) => "storage",
+			DbError::OutOfRange(_) => "out_of_range",
+			DbError::Partial(_) => "partial",
+		}
+	}
+	
+	/// Whether repeating the call may succeed, only when the backend timed out or was unavailable.
+	/// A protocol error is answered the same way again.
+	pub fn is_transient(&self) -> bool {
+		matches!(self, DbError::Timeout(_) | DbError::Unavailable(_))
+	}
+	
+	/// Whether the call surely changed nothing, so repeating it cannot apply it twice
+	pub fn is_unapplied(&self) -> bool {
+		matches!(self, DbError::Unavailable(_))
+	}
+}
+
+impl Display for DbError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			DbError::Timeout(details) => write!(f, "database timed out: {}", details),
+			DbError::Unavailable(details) => write!(f, "database unavailable: {}", details),
+			DbError::NotFound(details) => write!(f, "not found: {}", details),
+			DbError::Protocol(details) => write!(f, "database error: {}", details),
+			DbError::Overflow(overflow) => overflow.fmt(f),
+			DbError::Overloaded(details) => write!(f, "overloaded: {}", details),
+			DbError::Storage(details) => write!(f, "storage error: {}", details),
+			DbError::OutOfRange(details) => write!(f, "out of range: {}", details),
+			DbError::Partial(details) => write!(f, "partially written: {}", details),
+		}
+	}
+}
+
+impl std::error::Error for DbError {}
+
+impl From<IdSpaceOverflow> for DbError {
+	fn from(value: IdSpaceOverflow) -> Self {
+		DbError::Overflow(value)
+	}
+}
+
+impl From<aerospike::Error> for DbError {
+	fn from(value: aerospike::Error) -> Self {
+		match value.kind() {
+			ErrorKind::Timeout(_) | ErrorKind::ServerError(ResultCode::Timeout) => DbError::Timeout(value.to_string()),
+			ErrorKind::Connection(_)
+			| ErrorKind::NoMoreConnections
+			| ErrorKind::InvalidNode(_)
+			| ErrorKind::ServerError(ResultCode::KeyBusy)
+			| ErrorKind::ServerError(ResultCode::DeviceOverload) => DbError::Unavailable(value.to_string()),
+			ErrorKind::ServerError(ResultCode::KeyNotFoundError) => DbError::NotFound(value.to_string()),
+			_ => DbError::Protocol(value.to_string()),
+		}
+	}
+}
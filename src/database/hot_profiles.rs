@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::CacheConfig;
+use crate::data::{Cookie, UserAction, UserProfile, UserTagEvent};
+use crate::database::UnflushedSnapshot;
+
+/// Outcome of a profile read as `HotProfiles` sees it
+pub enum HotRead {
+	Cached(UserProfile),
+	/// Read often enough, the profile read from the remote database is to be cached
+	Hot,
+	Cold,
+}
+
+struct HotEntry {
+	reads: u32,
+	last_read: Instant,
+	/// Position in the recency order
+	tick: u64,
+	profile: Option<UserProfile>,
+	/// When the profile was read from the remote database
+	filled: Instant,
+	/// Sequence numbers of the unflushed events the profile was filled with, each one is taken
+	/// out once the write that queued it adds it again
+	applied: HashSet<u64>,
+}
+
+#[derive(Default)]
+struct HotState {
+	entries: HashMap<Cookie, HotEntry>,
+	/// Cookies by their last read, the least recent first
+	recency: BTreeMap<u64, Cookie>,
+	tick: u64,
+}
+
+impl HotState {
+	fn drop_least_recent(&mut self) {
+		if let Some((_, cookie)) = self.recency.pop_first() {
+			self.entries.remove(&cookie);
+		}
+	}
+}
+
+/// Profiles of the cookies read at least `min_reads` times, served from memory by `CachedDB`.
+/// Tracks at most `max_profiles` cookies, dropping the least recently read one first, and
+/// forgets a cookie not read for the TTL. A profile cached for `max_age` is read again, so one
+/// changed behind this instance's back is not served forever.
+///
+/// Kept apart from the profiles of the local database: `CachedDB` is generic over it, and
+/// every entry needs its reads and the unflushed events it holds next to the profile.
+pub struct HotProfiles {
+	state: Mutex<HotState>,
+	min_reads: u32,
+	capacity: usize,
+	ttl: Duration,
+	max_age: Duration,
+}
+
+impl HotProfiles {
+	pub fn new(config: &CacheConfig) -> Self {
+		Self {
+			state: Default::default(),
+			min_reads: config.min_reads,
+			capacity: config.max_profiles,
+			ttl: config.profile_ttl(),
+			max_age: config.profile_max_age(),
+		}
+	}
+	
+	/// Counts a read of the cookie and returns its profile if cached
+	pub fn read(&self, cookie: &Cookie) -> HotRead {
+		let now = Instant::now();
+		let mut state = self.state.lock().unwrap();
+		while let Some((_, oldest)) = state.recency.first_key_value() {
+			if now.duration_since(state.entries[oldest].last_read) < self.ttl {
+				break;
+			}
+			state.drop_least_recent();
+		}
+		
+		state.tick += 1;
+		let tick = state.tick;
+		let HotState { entries, recency, .. } = &mut *state;
+		let entry = entries.entry(cookie.clone()).or_insert_with(|| HotEntry {
+			reads: 0,
+			last_read: now,
+			tick,
+			profile: None,
+			filled: now,
+			applied: Default::default(),
+		});
+		if entry.profile.is_some() && now.duration_since(entry.filled) >= self.max_age {
+			entry.profile = None;
+			entry.applied.clear();
+		}
+		recency.remove(&entry.tick);
+		recency.insert(tick, cookie.clone());
+		entry.reads = entry.reads.saturating_add(1);
+		entry.last_read = now;
+		entry.tick = tick;
+		let read = match &entry.profile {
+			Some(profile) => HotRead::Cached(profile.clone()),
+			None if entry.reads >= self.min_reads => HotRead::Hot,
+			None => HotRead::Cold,
+		};
+		
+		while state.entries.len() > self.capacity {
+			state.drop_least_recent();
+		}
+		read
+	}
+	
+	/// Caches the profile read while `unflushed` was current and adds the events queued since.
+	/// Skipped when a write of the cookie started since or the cookie was dropped meanwhile.
+	pub fn fill(&self, cookie: &Cookie, mut profile: UserProfile, unflushed: &UnflushedSnapshot, current: impl FnOnce() -> UnflushedSnapshot, max_tags: usize) {
+		let mut state = self.state.lock().unwrap();
+		let Some(entry) = state.entries.get_mut(cookie) else {
+			return;
+		};
+		if entry.profile.is_some() {
+			return;
+		}
+		// taken under the lock, so every write queued later adds its events to the cached profile
+		let later = current();
+		if !unflushed.settled_until(&later) {
+			return;
+		}
+		let merged: HashSet<u64> = unflushed.events.iter().map(|(seq, _, _)| *seq).collect();
+		for (seq, tag, action) in &later.events {
+			if !merged.contains(seq) {
+				profile.add(*tag, *action, max_tags);
+			}
+		}
+		entry.applied = later.events.iter().map(|(seq, _, _)| *seq).collect();
+		entry.profile = Some(profile);
+		entry.filled = Instant::now();
+	}
+	
+	/// Adds the tags just queued under the sequence numbers to the profile if it is cached
+	pub fn add(&self, cookie: &Cookie, seqs: &[u64], tags: &[(UserTagEvent, UserAction)], max_tags: usize) {
+		let mut state = self.state.lock().unwrap();
+		let Some(HotEntry { profile: Some(profile), applied, .. }) = state.entries.get_mut(cookie) else {
+			return;
+		};
+		for (seq, (tag, action)) in seqs.iter().zip(tags) {
+			if !applied.remove(seq) {
+				profile.add(*tag, *action, max_tags);
+			}
+		}
+	}
+	
+	/// The cached profile, without counting a read
+	pub fn cached(&self, cookie: &Cookie) -> Option<UserProfile> {
+		self.state.lock().unwrap()
+			.entries.get(cookie)
+			.and_then(|entry| entry.profile.clone())
+	}
+}
@@ -1,64 +1,399 @@
 use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use anyhow::bail;
 use dashmap::DashMap;
-use rayon::prelude::*;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use crate::api::*;
 use crate::data::*;
-use crate::database::{CompressingDB, Compressor, Database, Decompressor, PartialCompressor, PartialDecompressor, Synced};
+use crate::data::time::TimeRange;
+use crate::config::WalConfig;
+use crate::database::{BinarySnapshot, CompressingDB, Compressor, Database, DbError, DbResult, Decompressor, MapperContent, MappingsSnapshot, PartialCompressor, PartialDecompressor, Snapshot, SnapshotReader, SnapshotWriter, Synced, TimeRing, Wal, WalRecord};
+
+/// Ids of the values and values by id. A `CachedDB` learns ids of the remote dictionary out of
+/// order, so some ids below the highest known one may have no value yet.
+type Mapping = (HashMap<String, usize>, Vec<Option<String>>);
 
 #[derive(Default)]
 pub struct Mapper {
-	mapper: RwLock<(HashMap<String, usize>, Vec<String>)>,
+	mapper: RwLock<Mapping>,
+}
+
+fn insert_mapping(mapper: &mut Mapping, key: &str, id: usize) {
+	mapper.0.insert(key.to_owned(), id);
+	if mapper.1.len() <= id {
+		mapper.1.resize(id + 1, None);
+	}
+	mapper.1[id] = Some(key.to_owned());
 }
 
 impl Mapper {
-	pub async fn get_or_insert_id(&self, key: &String) -> usize {
+	/// Id of the key, a new one is assigned only while it still fits into the dictionary.
+	/// `log` gets a new id before anyone else can use it and can still refuse it.
+	pub async fn get_or_insert_id<F: Future<Output = DbResult<()>>>(&self, key: &str, dictionary: Dictionary, log: impl FnOnce(usize) -> F) -> DbResult<usize> {
 		let mut write_lock = self.mapper.write().await;
-		let length = write_lock.1.len();
-		let id = *write_lock.0.entry(key.clone()).or_insert(length);
-		if id == length {
-			write_lock.1.push(key.clone());
+		if let Some(id) = write_lock.0.get(key) {
+			return Ok(*id);
 		}
-		id
+		let id = dictionary.check(write_lock.1.len() as u64)? as usize;
+		log(id).await?;
+		write_lock.0.insert(key.to_owned(), id);
+		write_lock.1.push(Some(key.to_owned()));
+		Ok(id)
 	}
 	
-	pub async fn force_insert_mapping(&self, key: &String, id: usize) {
-		let mut write_lock = self.mapper.write().await;
-		write_lock.0.insert(key.clone(), id);
-		if write_lock.1.len() < id {
-			write_lock.1.resize(id, String::from(""));
-		}
-		*write_lock.1.get_mut(id).unwrap() = key.clone();
+	pub async fn force_insert_mapping(&self, key: &str, id: usize) {
+		insert_mapping(&mut *self.mapper.write().await, key, id);
+	}
+	
+	/// `force_insert_mapping` of a mapper not shared yet
+	fn restore_mapping(&mut self, key: &str, id: usize) {
+		insert_mapping(self.mapper.get_mut(), key, id);
 	}
 	
 	pub async fn try_get_id(&self, key: &String) -> Partial<String, usize> {
-		match self.mapper.read().await.0.get(key).map(Clone::clone) {
+		match self.mapper.read().await.0.get(key).cloned() {
 			Some(v) => Partial::Changed(v),
 			None => Partial::Same(key.clone()),
 		}
 	}
 	
 	pub async fn get_string(&self, id: usize) -> Option<String> {
-		self.mapper.read().await.1.get(id).map(Clone::clone)
+		self.mapper.read().await.1.get(id).cloned().flatten()
 	}
 	
 	pub async fn try_get_string(&self, id: usize) -> Partial<String, usize> {
-		match self.mapper.read().await.1.get(id).map(Clone::clone) {
+		match self.get_string(id).await {
 			Some(x) => Partial::Same(x),
 			None => Partial::Changed(id),
 		}
 	}
+	
+	/// Number of values known, the gaps left by ids learnt out of order are not counted
+	pub async fn len(&self) -> usize {
+		self.mapper.read().await.0.len()
+	}
+	
+	pub async fn content(&self) -> MapperContent {
+		let read_lock = self.mapper.read().await;
+		MapperContent {
+			ids: read_lock.0.clone(),
+			values: read_lock.1.clone(),
+		}
+	}
 }
 
-#[derive(Default)]
-struct AggregateHolder {
-	start_timestamp: Option<i64>,
-	events: Vec<Vec<AggregateTagEvent>>,
+impl From<MapperContent> for Mapper {
+	fn from(value: MapperContent) -> Self {
+		Self {
+			mapper: RwLock::new((value.ids, value.values)),
+		}
+	}
+}
+
+/// Pre-aggregated counters of a single minute, one bucket per `CubeKey` seen in that minute
+#[derive(Default, Clone)]
+struct AggregateCube {
+	cells: HashMap<CubeKey, AggregateBucket>,
+	products: HashMap<(UserAction, u64), TopCounter>,
+}
+
+impl AggregateCube {
+	fn add(&mut self, tag: &AggregateTagEvent) {
+		for key in CubeKey::combinations(tag) {
+			let cell = self.cells.entry(key).or_default();
+			cell.add(tag);
+			if key.is_full() {
+				cell.distinct_users.insert(tag.cookie_fingerprint);
+			}
+		}
+		self.products.entry((tag.action, tag.product_id)).or_default().add(tag);
+	}
+	
+	/// Counters of every value of the requested dimension seen in this bucket
+	fn top(&self, request: &GetTopRequest) -> Vec<(u64, TopCounter)> {
+		match request.dimension.cube_dimension() {
+			Some(dimension) => self.cells.iter()
+				.filter(|(key, _)| key.action == request.action && key.has_only(dimension))
+				.filter_map(|(key, cell)| key.get(dimension).map(|id| (id as u64, TopCounter::from(cell))))
+				.collect(),
+			None => self.products.iter()
+				.filter(|((action, _), _)| *action == request.action)
+				.map(|((_, id), counter)| (*id, *counter))
+				.collect(),
+		}
+	}
+	
+	fn get(&self, key: &CubeKey) -> Option<&AggregateBucket> {
+		self.cells.get(key)
+	}
+	
+	fn groups<'a>(&'a self, request: &'a GetAggregateRequest) -> impl Iterator<Item = (&'a CubeKey, &'a AggregateBucket)> + 'a {
+		self.cells.iter().filter(|(key, _)| key.is_group_of(request))
+	}
+	
+	/// Distinct users of the full cells, the only ones that keep them
+	fn sketches(&self) -> impl Iterator<Item = (&CubeKey, &DistinctSketch)> {
+		self.cells.iter()
+			.filter(|(key, _)| key.is_full())
+			.map(|(key, cell)| (key, &cell.distinct_users))
+	}
+	
+	fn encode(&self, writer: &mut SnapshotWriter) {
+		writer.count(self.cells.len());
+		for (key, cell) in &self.cells {
+			writer.i64(key.encode());
+			writer.u64(cell.sum);
+			writer.u64(cell.count);
+			encode_price(writer, cell.min);
+			encode_price(writer, cell.max);
+			let fingerprints = cell.distinct_users.fingerprints();
+			writer.count(fingerprints.len());
+			for fingerprint in fingerprints {
+				writer.u64(*fingerprint);
+			}
+		}
+		writer.count(self.products.len());
+		for ((action, product_id), counter) in &self.products {
+			writer.u8(*action as u8);
+			writer.u64(*product_id);
+			writer.u64(counter.count);
+			writer.u64(counter.sum);
+		}
+	}
+	
+	fn decode(reader: &mut SnapshotReader) -> anyhow::Result<Self> {
+		let mut cube = Self::default();
+		for _ in 0..reader.count()? {
+			let key = CubeKey::decode(reader.i64()?);
+			let mut cell = AggregateBucket {
+				sum: reader.u64()?,
+				count: reader.u64()?,
+				min: decode_price(reader)?,
+				max: decode_price(reader)?,
+				distinct_users: DistinctSketch::default(),
+			};
+			let mut fingerprints = vec![];
+			for _ in 0..reader.count()? {
+				fingerprints.push(reader.u64()?);
+			}
+			cell.distinct_users = DistinctSketch::from_fingerprints(fingerprints);
+			cube.cells.insert(key, cell);
+		}
+		for _ in 0..reader.count()? {
+			let key = (decode_action(reader.u8()?)?, reader.u64()?);
+			let counter = TopCounter {
+				count: reader.u64()?,
+				sum: reader.u64()?,
+			};
+			cube.products.insert(key, counter);
+		}
+		Ok(cube)
+	}
+}
+
+fn encode_price(writer: &mut SnapshotWriter, price: Option<i32>) {
+	writer.u8(price.is_some() as u8);
+	writer.i32(price.unwrap_or_default());
+}
+
+fn decode_price(reader: &mut SnapshotReader) -> anyhow::Result<Option<i32>> {
+	let present = reader.u8()?;
+	let price = reader.i32()?;
+	Ok((present != 0).then_some(price))
+}
+
+/// Number of buckets kept in every aggregate tier, older ones are evicted
+pub struct AggregateRetention {
+	pub minutes: usize,
+	pub hours: usize,
+	pub days: usize,
+	/// Minutes an event may lie past both the newest minute held and the clock
+	pub max_skew_minutes: usize,
+}
+
+impl Default for AggregateRetention {
+	fn default() -> Self {
+		Self {
+			minutes: 24 * 60,
+			hours: 30 * 24,
+			days: 365,
+			max_skew_minutes: 60,
+		}
+	}
+}
+
+/// Minute of the clock, in the buckets aggregate events are stored by
+fn current_minute() -> i64 {
+	chrono::Utc::now().timestamp_millis() / AGGREGATE_BUCKET
+}
+
+/// The cubes are shared with the snapshots taken, a write clones a cube only while a snapshot
+/// still holds it
+struct AggregateTiers {
+	minutes: TimeRing<Arc<AggregateCube>>,
+	hours: TimeRing<Arc<AggregateCube>>,
+	days: TimeRing<Arc<AggregateCube>>,
+}
+
+impl AggregateTiers {
+	fn new(retention: AggregateRetention) -> Self {
+		Self {
+			minutes: TimeRing::new(retention.minutes).with_max_skew(retention.max_skew_minutes),
+			hours: TimeRing::new(retention.hours),
+			days: TimeRing::new(retention.days),
+		}
+	}
+	
+	fn tier(&self, tier: AggregateTier) -> &TimeRing<Arc<AggregateCube>> {
+		match tier {
+			AggregateTier::Minute => &self.minutes,
+			AggregateTier::Hour => &self.hours,
+			AggregateTier::Day => &self.days,
+		}
+	}
+	
+	fn tier_mut(&mut self, tier: AggregateTier) -> &mut TimeRing<Arc<AggregateCube>> {
+		match tier {
+			AggregateTier::Minute => &mut self.minutes,
+			AggregateTier::Hour => &mut self.hours,
+			AggregateTier::Day => &mut self.days,
+		}
+	}
+	
+	/// Whether the minute lies too far past both the newest minute held and `now`
+	fn is_ahead(&self, timestamp: i64, now: i64) -> bool {
+		self.minutes.is_ahead(timestamp, now)
+	}
+	
+	fn add(&mut self, timestamp: i64, tag: &AggregateTagEvent, now: i64) {
+		for tier in AggregateTier::ALL {
+			if let Some(cube) = self.tier_mut(tier).get_or_insert(tier.of_minute(timestamp), tier.of_minute(now)) {
+				Arc::make_mut(cube).add(tag);
+			}
+		}
+	}
+}
+
+/// Point-in-time copy of a whole `LocalDB`
+struct LocalSnapshot {
+	/// First write-ahead log segment the snapshot does not hold
+	wal_segment: u64,
+	profiles: Vec<(Cookie, Arc<UserProfile>)>,
+	/// Cubes of every tier in `AggregateTier::ALL` order, with their bucket
+	tiers: Vec<Vec<(i64, Arc<AggregateCube>)>>,
+	/// Dictionaries in `Dictionary::ALL` order
+	dictionaries: Vec<MapperContent>,
+}
+
+/// magic: "RTBL", version: u8, then
+/// wal_segment: u64,
+/// profiles: count, (cookie: string, views: count, (UserTagEvent)*, buys: count, (UserTagEvent)*)*,
+/// tiers: ((bucket: i64, cube)* prefixed by a count) for minutes, hours and days,
+/// dictionaries: (size: count, entries: count, (value: string, id: u64)*) for every dictionary,
+/// crc: u32
+impl BinarySnapshot for LocalSnapshot {
+	const MAGIC: [u8; 4] = *b"RTBL";
+	const VERSION: u8 = 3;
+	const CONTENT: &'static str = "local database";
+	
+	fn encode(&self, writer: &mut SnapshotWriter) {
+		writer.u64(self.wal_segment);
+		writer.count(self.profiles.len());
+		for (cookie, profile) in &self.profiles {
+			writer.string(&cookie.0);
+			for events in [&profile.view_events, &profile.buy_events] {
+				writer.count(events.len());
+				for event in events {
+					writer.bytes(&event.encode());
+				}
+			}
+		}
+		for tier in &self.tiers {
+			writer.count(tier.len());
+			for (bucket, cube) in tier {
+				writer.i64(*bucket);
+				cube.encode(writer);
+			}
+		}
+		for dictionary in &self.dictionaries {
+			writer.count(dictionary.values.len());
+			writer.count(dictionary.ids.len());
+			for (value, id) in &dictionary.ids {
+				writer.string(value);
+				writer.u64(*id as u64);
+			}
+		}
+	}
+	
+	fn decode(reader: &mut SnapshotReader) -> anyhow::Result<Self> {
+		let wal_segment = reader.u64()?;
+		let mut profiles = vec![];
+		for _ in 0..reader.count()? {
+			let cookie = Cookie(reader.string()?);
+			let mut profile = UserProfile::default();
+			for events in [&mut profile.view_events, &mut profile.buy_events] {
+				for _ in 0..reader.count()? {
+					events.push(UserTagEvent::decode(reader.bytes(UserTagEvent::SIZE)?)?);
+				}
+			}
+			profiles.push((cookie, Arc::new(profile)));
+		}
+		let mut tiers = vec![];
+		for _ in AggregateTier::ALL {
+			let mut tier = vec![];
+			for _ in 0..reader.count()? {
+				tier.push((reader.i64()?, Arc::new(AggregateCube::decode(reader)?)));
+			}
+			tiers.push(tier);
+		}
+		let mut dictionaries = vec![];
+		for dictionary in Dictionary::ALL {
+			let size = reader.count()?;
+			if size as u64 > dictionary.capacity() {
+				bail!("dictionary {} has {} values, at most {} fit", Into::<&'static str>::into(dictionary), size, dictionary.capacity());
+			}
+			let mut content = MapperContent {
+				ids: HashMap::new(),
+				values: vec![None; size],
+			};
+			for _ in 0..reader.count()? {
+				let value = reader.string()?;
+				let id = reader.u64()? as usize;
+				if id >= size {
+					bail!("id {} is out of dictionary {} of {} values", id, Into::<&'static str>::into(dictionary), size);
+				}
+				content.values[id] = Some(value.clone());
+				content.ids.insert(value, id);
+			}
+			dictionaries.push(content);
+		}
+		Ok(Self {
+			wal_segment,
+			profiles,
+			tiers,
+			dictionaries,
+		})
+	}
 }
 
 pub struct LocalDB {
-	user_profiles: DashMap<Cookie, UserProfile>,
-	aggregates: RwLock<AggregateHolder>,
+	/// Shared with the blocking thread copying them for a snapshot. Copied on write like the
+	/// aggregate cubes, so a snapshot takes the pointers only.
+	user_profiles: Arc<DashMap<Cookie, Arc<UserProfile>>>,
+	aggregates: RwLock<AggregateTiers>,
+	mappings_snapshot: Option<PathBuf>,
+	snapshot: Option<PathBuf>,
+	/// Held until a snapshot is written, also when the task saving it is aborted
+	snapshot_lock: Arc<Mutex<()>>,
+	wal: Option<Wal>,
+	/// First write-ahead log segment the restored snapshot does not hold
+	wal_segment: u64,
+	/// Held by every write from logging an event to applying it, and exclusively by a snapshot
+	/// while it rotates the log
+	ingest: RwLock<()>,
+	max_tags: usize,
 	
 	product_id_map: Mapper,
 	origin_id_map: Mapper,
@@ -68,10 +403,61 @@ pub struct LocalDB {
 }
 
 impl LocalDB {
+	fn mapper(&self, dictionary: Dictionary) -> &Mapper {
+		match dictionary {
+			Dictionary::ProductId => &self.product_id_map,
+			Dictionary::OriginId => &self.origin_id_map,
+			Dictionary::BrandId => &self.brand_id_map,
+			Dictionary::Country => &self.country_id_map,
+			Dictionary::CategoryId => &self.category_id_map,
+		}
+	}
+	
+	fn mapper_mut(&mut self, dictionary: Dictionary) -> &mut Mapper {
+		match dictionary {
+			Dictionary::ProductId => &mut self.product_id_map,
+			Dictionary::OriginId => &mut self.origin_id_map,
+			Dictionary::BrandId => &mut self.brand_id_map,
+			Dictionary::Country => &mut self.country_id_map,
+			Dictionary::CategoryId => &mut self.category_id_map,
+		}
+	}
+	
+	/// Id of the value, a new one is logged first
+	async fn get_or_insert_id(&self, dictionary: Dictionary, value: &str) -> DbResult<usize> {
+		self.mapper(dictionary).get_or_insert_id(value, dictionary, |id| async move {
+			match &self.wal {
+				Some(wal) => wal.append(&WalRecord::Mapping {
+					dictionary,
+					value: value.to_owned(),
+					id: id as u64,
+				}).await,
+				None => Ok(()),
+			}
+		}).await
+	}
+	
+	/// Value of an id the dictionary has to hold already
+	async fn find_string(&self, dictionary: Dictionary, id: u64) -> DbResult<String> {
+		self.mapper(dictionary).get_string(id as usize).await
+			.ok_or_else(|| DbError::NotFound(format!("id {} in dictionary {}", id, Into::<&'static str>::into(dictionary))))
+	}
+	
 	pub fn new() -> Self {
+		Self::with_aggregate_retention(AggregateRetention::default())
+	}
+	
+	pub fn with_aggregate_retention(aggregate_retention: AggregateRetention) -> Self {
 		Self {
 			user_profiles: Default::default(),
-			aggregates: Default::default(),
+			aggregates: RwLock::new(AggregateTiers::new(aggregate_retention)),
+			mappings_snapshot: None,
+			snapshot: None,
+			snapshot_lock: Default::default(),
+			wal: None,
+			wal_segment: 0,
+			ingest: Default::default(),
+			max_tags: MAX_TAGS,
 			product_id_map: Default::default(),
 			origin_id_map: Default::default(),
 			brand_id_map: Default::default(),
@@ -79,95 +465,335 @@ impl LocalDB {
 			category_id_map: Default::default(),
 		}
 	}
+	
+	/// Number of tags of every action kept in a user profile
+	pub fn with_max_tags(mut self, max_tags: usize) -> Self {
+		self.max_tags = max_tags;
+		self
+	}
+	
+	/// Warm-loads the dictionaries from the snapshot file and keeps saving them there.
+	/// A missing or unreadable snapshot leaves the dictionaries empty, they are then filled
+	/// again from the remote database. Not combined with `with_snapshot`, which holds them too.
+	pub fn with_mappings_snapshot(mut self, path: impl Into<PathBuf>) -> Self {
+		let path = path.into();
+		match MappingsSnapshot::load(&path) {
+			Ok(Some(snapshot)) => {
+				self.product_id_map = snapshot.product_id.into();
+				self.origin_id_map = snapshot.origin_id.into();
+				self.brand_id_map = snapshot.brand_id.into();
+				self.country_id_map = snapshot.country_id.into();
+				self.category_id_map = snapshot.category_id.into();
+			},
+			Ok(None) => {},
+			Err(err) => eprintln!("Ignoring mappings snapshot: {}", err),
+		}
+		self.mappings_snapshot = Some(path);
+		self
+	}
+	
+	/// Saves the dictionaries to the snapshot file, if one is configured
+	pub async fn save_mappings(&self) -> anyhow::Result<()> {
+		let Some(path) = &self.mappings_snapshot else {
+			return Ok(());
+		};
+		let snapshot = MappingsSnapshot {
+			version: MappingsSnapshot::VERSION,
+			product_id: self.product_id_map.content().await,
+			origin_id: self.origin_id_map.content().await,
+			brand_id: self.brand_id_map.content().await,
+			country_id: self.country_id_map.content().await,
+			category_id: self.category_id_map.content().await,
+		};
+		snapshot.save(path).await
+	}
+	
+	/// Restores the profiles, aggregates and dictionaries from the snapshot file and keeps saving
+	/// them there. A missing or unreadable snapshot leaves the database empty. Only for a
+	/// `LocalDB` that is the whole database, not the dictionaries of a `CachedDB`.
+	pub fn with_snapshot(mut self, path: impl Into<PathBuf>) -> Self {
+		let path = path.into();
+		match LocalSnapshot::load(&path) {
+			Ok(Some(snapshot)) => self.restore(snapshot),
+			Ok(None) => {},
+			Err(err) => eprintln!("Ignoring local database snapshot: {:#}", err),
+		}
+		self.snapshot = Some(path);
+		self
+	}
+	
+	fn restore(&mut self, snapshot: LocalSnapshot) {
+		self.wal_segment = snapshot.wal_segment;
+		self.user_profiles = Arc::new(snapshot.profiles.into_iter().collect());
+		let aggregates = self.aggregates.get_mut();
+		for (tier, cubes) in AggregateTier::ALL.into_iter().zip(snapshot.tiers) {
+			// from the oldest, so a shorter retention keeps the latest buckets
+			for (bucket, cube) in cubes {
+				// checked when they were written
+				if let Some(slot) = aggregates.tier_mut(tier).get_or_insert(bucket, bucket) {
+					*slot = cube;
+				}
+			}
+		}
+		for (dictionary, content) in Dictionary::ALL.into_iter().zip(snapshot.dictionaries) {
+			*self.mapper_mut(dictionary) = content.into();
+		}
+	}
+	
+	/// Replays the write-ahead log written since the restored snapshot and keeps logging every
+	/// change there. Fails when the log cannot be read or written.
+	pub fn with_wal(mut self, config: &WalConfig) -> anyhow::Result<Self> {
+		Wal::replay(&config.dir, self.wal_segment, |record| self.replay(record))?;
+		self.wal = Some(Wal::open(config, self.wal_segment)?);
+		Ok(self)
+	}
+	
+	/// The snapshot holds exactly the segments before its first one, so every record is new
+	fn replay(&mut self, record: WalRecord) {
+		match record {
+			WalRecord::UserEvents { cookie, tags } => {
+				let mut user_profile = self.user_profiles.entry(cookie).or_default();
+				let user_profile = Arc::make_mut(&mut user_profile);
+				for (tag, action) in tags {
+					user_profile.add(tag, action, self.max_tags);
+				}
+			},
+			// accepted when it was logged, however far ahead of the rest it lies
+			WalRecord::AggregateEvent { timestamp, tag } => self.aggregates.get_mut().add(timestamp, &tag, timestamp),
+			WalRecord::Mapping { dictionary, value, id } => self.mapper_mut(dictionary).restore_mapping(&value, id as usize),
+		}
+	}
+	
+	/// Syncs the write-ahead log, for its periodic fsync policy
+	pub async fn sync_wal(&self) -> DbResult<()> {
+		match &self.wal {
+			Some(wal) => wal.sync().await,
+			None => Ok(()),
+		}
+	}
+	
+	/// Saves the whole database to the snapshot file, if one is configured, and drops the
+	/// write-ahead log segments it holds. Writers are blocked only while the log is rotated and
+	/// the pointers to the profiles and aggregate cubes are taken, so the snapshot holds exactly
+	/// the segments before the new one. The snapshot is encoded on a blocking thread.
+	pub async fn save_snapshot(&self) -> anyhow::Result<()> {
+		let Some(path) = self.snapshot.clone() else {
+			return Ok(());
+		};
+		let lock = self.snapshot_lock.clone().lock_owned().await;
+		let (wal_segment, profiles, tiers) = {
+			// the profiles and aggregates hold exactly the events logged before the new segment
+			let _ingest = self.ingest.write().await;
+			let wal_segment = match &self.wal {
+				Some(wal) => wal.rotate().await?,
+				None => self.wal_segment,
+			};
+			let user_profiles = self.user_profiles.clone();
+			let profiles = tokio::task::spawn_blocking(move || {
+				user_profiles.iter()
+					.map(|entry| (entry.key().clone(), entry.value().clone()))
+					.collect()
+			}).await?;
+			let reader = self.aggregates.read().await;
+			let tiers = AggregateTier::ALL.into_iter()
+				.map(|tier| reader.tier(tier).iter().map(|(bucket, cube)| (bucket, cube.clone())).collect())
+				.collect();
+			(wal_segment, profiles, tiers)
+		};
+		// copied last, so they hold every id the profiles and aggregates refer to
+		let mut dictionaries = vec![];
+		for dictionary in Dictionary::ALL {
+			dictionaries.push(self.mapper(dictionary).content().await);
+		}
+		let snapshot = LocalSnapshot {
+			wal_segment,
+			profiles,
+			tiers,
+			dictionaries,
+		};
+		tokio::task::spawn_blocking(move || {
+			let _lock = lock;
+			snapshot.write(&path)
+		}).await??;
+		if let Some(wal) = &self.wal {
+			wal.truncate(wal_segment)?;
+		}
+		Ok(())
+	}
+	
+	/// Number of cookies with a profile
+	pub fn cookie_count(&self) -> usize {
+		self.user_profiles.len()
+	}
+	
+	/// Minute buckets of the aggregates held, `None` before the first aggregate event
+	pub async fn aggregate_minutes(&self) -> Option<TimeRange> {
+		self.aggregates.read().await.minutes.range()
+	}
 }
 
 impl Database for LocalDB {
-	async fn add_user_event(&self, cookie: &Cookie, tag: UserTagEvent, action: UserAction) {
-		let mut user_profile = self.user_profiles.entry(cookie.clone()).or_insert(UserProfile::default());
-		let tags = match action {
-			UserAction::VIEW => &mut user_profile.view_events,
-			UserAction::BUY => &mut user_profile.buy_events,
-		};
-		tags.push(tag);
-		if tags.len() > MAX_TAGS {
-			tags.remove(0);
+	async fn add_user_event(&self, cookie: &Cookie, tag: UserTagEvent, action: UserAction) -> DbResult<()> {
+		let _ingest = self.ingest.read().await;
+		if let Some(wal) = &self.wal {
+			wal.append(&WalRecord::UserEvents { cookie: cookie.clone(), tags: vec![(tag, action)] }).await?;
 		}
+		Arc::make_mut(&mut self.user_profiles.entry(cookie.clone()).or_default()).add(tag, action, self.max_tags);
+		Ok(())
 	}
 	
-	async fn get_user_profile(&self, cookie: &Cookie) -> UserProfile {
-		self.user_profiles.get(cookie)
+	async fn add_user_events(&self, cookie: &Cookie, tags: Vec<(UserTagEvent, UserAction)>) -> DbResult<()> {
+		let _ingest = self.ingest.read().await;
+		if let Some(wal) = &self.wal {
+			wal.append(&WalRecord::UserEvents { cookie: cookie.clone(), tags: tags.clone() }).await?;
+		}
+		let mut user_profile = self.user_profiles.entry(cookie.clone()).or_default();
+		let user_profile = Arc::make_mut(&mut user_profile);
+		for (tag, action) in tags {
+			user_profile.add(tag, action, self.max_tags);
+		}
+		Ok(())
+	}
+	
+	async fn get_user_profile(&self, cookie: &Cookie) -> DbResult<UserProfile> {
+		Ok(self.user_profiles.get(cookie)
 			.map(|x| {
 				let user_profile_ref = x.value();
-				user_profile_ref.clone()
+				user_profile_ref.as_ref().clone()
 			})
-			.unwrap_or(UserProfile::default())
+			.unwrap_or_default())
+	}
+	
+	async fn add_aggregate_event(&self, timestamp: i64, tag: AggregateTagEvent) -> DbResult<()> {
+		self.add_aggregate_events(vec![(timestamp, tag)]).await.pop().unwrap()
 	}
 	
-	async fn add_aggregate_event(&self, timestamp: i64, tag: AggregateTagEvent) {
-		let mut writer = self.aggregates.write().await;
-		if let None = writer.start_timestamp {
-			writer.start_timestamp = Some(timestamp);
+	/// All events are added under a single lock of the aggregates, the accepted ones logged together
+	async fn add_aggregate_events(&self, events: Vec<(i64, AggregateTagEvent)>) -> Vec<DbResult<()>> {
+		let now = current_minute();
+		let _ingest = self.ingest.read().await;
+		let mut aggregates = self.aggregates.write().await;
+		let mut results: Vec<DbResult<()>> = events.iter()
+			.map(|(timestamp, _)| match aggregates.is_ahead(*timestamp, now) {
+				true => Err(DbError::OutOfRange(format!("minute {} is too far ahead of minute {}", timestamp, now))),
+				false => Ok(()),
+			})
+			.collect();
+		let accepted = || events.iter().zip(&results).filter(|(_, result)| result.is_ok()).map(|(event, _)| event);
+		if let Some(wal) = &self.wal {
+			let records: Vec<WalRecord> = accepted()
+				.map(|(timestamp, tag)| WalRecord::AggregateEvent { timestamp: *timestamp, tag: tag.clone() })
+				.collect();
+			if let Err(err) = wal.append_all(&records).await {
+				for result in results.iter_mut().filter(|result| result.is_ok()) {
+					*result = Err(err.clone());
+				}
+				return results;
+			}
 		}
-		let bucket = (timestamp - writer.start_timestamp.unwrap()) as usize;
-		writer.events.get_mut(bucket).unwrap().push(tag);
+		for (timestamp, tag) in accepted() {
+			aggregates.add(*timestamp, tag, now);
+		}
+		results
 	}
 	
-	async fn get_aggregate(&self, request: &GetAggregateRequest) -> GetAggregateResponse {
+	async fn get_aggregate(&self, request: &GetAggregateRequest) -> DbResult<GetAggregateResponse> {
 		let reader = self.aggregates.read().await;
-		let start = reader.start_timestamp.unwrap();
-		let start_bucket = (request.time_range.start - start) as usize;
-		let end_bucket = (request.time_range.end - start) as usize;
-		
-		fn eq_or_true(option: &Option<u16>, value: u16) -> bool {
-			option.map(|x| x == value).unwrap_or(true)
-		}
+		let ring = reader.tier(request.granularity.tier());
+		let key = CubeKey::of_request(request);
 		
-		let buckets = reader.events.as_slice()[start_bucket..end_bucket]
-			.into_par_iter()
-			.map(|minute_data|{
-				minute_data.iter().fold(AggregateBucket::default(), |mut result, tag| {
-					let correct_origin = eq_or_true(&request.origin, tag.origin_id);
-					let correct_brand_id = eq_or_true(&request.brand_id, tag.brand_id);
-					let correct_category_id = eq_or_true(&request.category_id, tag.category_id);
-					let correct_action = tag.action == request.action;
-					if correct_origin && correct_brand_id && correct_category_id && correct_action {
-						result.count += 1;
-						result.sum += tag.price as u64;
+		let buckets = (request.time_range.start..request.time_range.end)
+			.map(|bucket| {
+				let mut result = AggregateBucket::default();
+				for cube in request.tier_range(bucket).filter_map(|index| ring.get(index)) {
+					if let Some(cell) = cube.get(&key) {
+						result.merge(cell);
+					}
+					if request.distinct_users {
+						for (_, sketch) in cube.sketches().filter(|(cell, _)| key.covers(cell)) {
+							result.distinct_users.merge(sketch);
+						}
 					}
-					result
-				})
+				}
+				result
 			}).collect();
 		
-		GetAggregateResponse {
+		Ok(GetAggregateResponse {
 			aggregates: buckets,
+		})
+	}
+	
+	async fn get_grouped_aggregate(&self, request: &GetAggregateRequest) -> DbResult<GetGroupedAggregateResponse> {
+		let reader = self.aggregates.read().await;
+		let ring = reader.tier(request.granularity.tier());
+		
+		let groups = (request.time_range.start..request.time_range.end)
+			.map(|bucket| {
+				let mut groups: HashMap<CubeKey, AggregateBucket> = HashMap::new();
+				for cube in request.tier_range(bucket).filter_map(|index| ring.get(index)) {
+					for (key, cell) in cube.groups(request) {
+						groups.entry(*key).or_default().merge(cell);
+					}
+					if request.distinct_users {
+						for (key, sketch) in cube.sketches().filter_map(|(cell, sketch)| cell.group_of(request).map(|key| (key, sketch))) {
+							groups.entry(key).or_default().distinct_users.merge(sketch);
+						}
+					}
+				}
+				AggregateGroup::sorted(groups)
+			}).collect();
+		
+		Ok(GetGroupedAggregateResponse { groups })
+	}
+	
+	async fn get_top(&self, request: &GetTopRequest) -> DbResult<GetTopResponse> {
+		let reader = self.aggregates.read().await;
+		
+		let mut counters: HashMap<u64, TopCounter> = HashMap::new();
+		for (tier, index) in AggregateTier::cover(request.time_range.start..request.time_range.end) {
+			if let Some(cube) = reader.tier(tier).get(index) {
+				for (id, counter) in cube.top(request) {
+					counters.entry(id).or_default().merge(&counter);
+				}
+			}
+		}
+		
+		Ok(GetTopResponse::ranked(counters, request))
+	}
+	
+	async fn get_dictionaries(&self) -> DbResult<Vec<DictionaryFill>> {
+		let mut dictionaries = vec![];
+		for dictionary in Dictionary::ALL {
+			dictionaries.push(DictionaryFill::new(dictionary, self.mapper(dictionary).len().await as u64));
 		}
+		Ok(dictionaries)
 	}
 }
 
 impl Compressor<UserTagEvent> for LocalDB {
-	async fn compress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> UserTagEventCompressedData {
-		UserTagEventCompressedData {
+	async fn compress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> DbResult<UserTagEventCompressedData> {
+		Ok(UserTagEventCompressedData {
 			product_id: match partial.product_id {
-				Partial::Same(x) => self.product_id_map.get_or_insert_id(&x).await as u64,
+				Partial::Same(x) => self.get_or_insert_id(Dictionary::ProductId, &x).await? as u64,
 				Partial::Changed(x) => x,
 			},
 			brand_id: match partial.brand_id {
-				Partial::Same(x) => self.brand_id_map.get_or_insert_id(&x).await as u16,
+				Partial::Same(x) => self.get_or_insert_id(Dictionary::BrandId, &x).await? as u16,
 				Partial::Changed(x) => x,
 			},
 			category_id: match partial.category_id {
-				Partial::Same(x) => self.category_id_map.get_or_insert_id(&x).await as u16,
+				Partial::Same(x) => self.get_or_insert_id(Dictionary::CategoryId, &x).await? as u16,
 				Partial::Changed(x) => x,
 			},
 			country_id: match partial.country_id {
-				Partial::Same(x) => self.country_id_map.get_or_insert_id(&x).await as u8,
+				Partial::Same(x) => self.get_or_insert_id(Dictionary::Country, &x).await? as u8,
 				Partial::Changed(x) => x,
 			},
 			origin_id: match partial.origin_id {
-				Partial::Same(x) => self.origin_id_map.get_or_insert_id(&x).await as u16,
+				Partial::Same(x) => self.get_or_insert_id(Dictionary::OriginId, &x).await? as u16,
 				Partial::Changed(x) => x,
 			},
-		}
+		})
 	}
 }
 
@@ -217,29 +843,29 @@ impl PartialCompressor<UserTagEvent> for LocalDB {
 }
 
 impl Decompressor<UserTagEvent> for LocalDB {
-	async fn decompress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> UserTagEventDecompressedData {
-		UserTagEventDecompressedData {
+	async fn decompress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> DbResult<UserTagEventDecompressedData> {
+		Ok(UserTagEventDecompressedData {
 			product_id: match partial.product_id {
 				Partial::Same(x) => x,
-				Partial::Changed(x) => self.product_id_map.get_string(x as usize).await.unwrap(),
+				Partial::Changed(x) => self.find_string(Dictionary::ProductId, x).await?,
 			},
 			brand_id: match partial.brand_id {
 				Partial::Same(x) => x,
-				Partial::Changed(x) => self.brand_id_map.get_string(x as usize).await.unwrap(),
+				Partial::Changed(x) => self.find_string(Dictionary::BrandId, x as u64).await?,
 			},
 			category_id: match partial.category_id {
 				Partial::Same(x) => x,
-				Partial::Changed(x) => self.category_id_map.get_string(x as usize).await.unwrap(),
+				Partial::Changed(x) => self.find_string(Dictionary::CategoryId, x as u64).await?,
 			},
 			country_id:  match partial.country_id {
 				Partial::Same(x) => x,
-				Partial::Changed(x) => self.country_id_map.get_string(x as usize).await.unwrap(),
+				Partial::Changed(x) => self.find_string(Dictionary::Country, x as u64).await?,
 			},
 			origin_id:  match partial.origin_id {
 				Partial::Same(x) => x,
-				Partial::Changed(x) => self.origin_id_map.get_string(x as usize).await.unwrap(),
+				Partial::Changed(x) => self.find_string(Dictionary::OriginId, x as u64).await?,
 			},
-		}
+		})
 	}
 }
 
@@ -289,27 +915,35 @@ impl PartialDecompressor<UserTagEvent> for LocalDB {
 }
 
 impl Compressor<AggregateTagEvent> for LocalDB {
-	async fn compress_with_partial(&self, partial: PartialAggregateTagEventCompressedData) -> AggregateTagEventCompressedData {
-		AggregateTagEventCompressedData {
+	async fn compress_with_partial(&self, partial: PartialAggregateTagEventCompressedData) -> DbResult<AggregateTagEventCompressedData> {
+		Ok(AggregateTagEventCompressedData {
+			product_id: match partial.product_id {
+				Partial::Same(x) => self.get_or_insert_id(Dictionary::ProductId, &x).await? as u64,
+				Partial::Changed(x) => x,
+			},
 			brand_id: match partial.brand_id {
-				Partial::Same(x) => self.brand_id_map.get_or_insert_id(&x).await as u16,
+				Partial::Same(x) => self.get_or_insert_id(Dictionary::BrandId, &x).await? as u16,
 				Partial::Changed(x) => x,
 			},
 			category_id: match partial.category_id {
-				Partial::Same(x) => self.category_id_map.get_or_insert_id(&x).await as u16,
+				Partial::Same(x) => self.get_or_insert_id(Dictionary::CategoryId, &x).await? as u16,
 				Partial::Changed(x) => x,
 			},
 			origin_id: match partial.origin_id {
-				Partial::Same(x) => self.origin_id_map.get_or_insert_id(&x).await as u16,
+				Partial::Same(x) => self.get_or_insert_id(Dictionary::OriginId, &x).await? as u16,
 				Partial::Changed(x) => x,
 			},
-		}
+		})
 	}
 }
 
 impl PartialCompressor<AggregateTagEvent> for LocalDB {
 	async fn partial_compress_with_partial(&self, partial: PartialAggregateTagEventCompressedData) -> PartialAggregateTagEventCompressedData {
 		PartialAggregateTagEventCompressedData {
+			product_id: match partial.product_id {
+				Partial::Same(x) => self.product_id_map.try_get_id(&x).await.map_changed(|x| x as u64),
+				Partial::Changed(x) => Partial::Changed(x),
+			},
 			brand_id: match partial.brand_id {
 				Partial::Same(x) => self.brand_id_map.try_get_id(&x).await.map_changed(|x| x as u16),
 				Partial::Changed(x) => Partial::Changed(x),
@@ -326,6 +960,9 @@ impl PartialCompressor<AggregateTagEvent> for LocalDB {
 	}
 	
 	async fn update_compression(&self, partial: &PartialAggregateTagEventCompressedData, compressed: &AggregateTagEventCompressedData) {
+		if let Partial::Same(x) = &partial.product_id {
+			self.product_id_map.force_insert_mapping(x, compressed.product_id as usize).await;
+		}
 		if let Partial::Same(x) = &partial.brand_id {
 			self.brand_id_map.force_insert_mapping(x, compressed.brand_id as usize).await;
 		}
@@ -339,34 +976,38 @@ impl PartialCompressor<AggregateTagEvent> for LocalDB {
 }
 
 impl Compressor<GetAggregateRequest> for LocalDB {
-	async fn compress_with_partial(&self, partial: PartialGetAggregateRequestCompressedData) -> GetAggregateRequestCompressedData {
-		let origin = match partial.origin_id {
-			Partial::Same(x) => match x {
-				Some(v) => Some(self.origin_id_map.get_or_insert_id(&v).await as u16),
-				None => None,
+	async fn compress_with_partial(&self, partial: PartialGetAggregateRequestCompressedData) -> DbResult<GetAggregateRequestCompressedData> {
+		let mut has_unknown_value = false;
+		let mut lookup = |value: Partial<String, usize>| match value {
+			Partial::Same(_) => {
+				has_unknown_value = true;
+				None
 			},
+			Partial::Changed(x) => Some(x as u16),
+		};
+		
+		let origin = match partial.origin_id {
+			Partial::Same(Some(v)) => lookup(self.origin_id_map.try_get_id(&v).await),
+			Partial::Same(None) => None,
 			Partial::Changed(x) => x,
 		};
 		let brand_id = match partial.brand_id {
-			Partial::Same(x) => match x {
-				Some(v) => Some(self.brand_id_map.get_or_insert_id(&v).await as u16),
-				None => None,
-			},
+			Partial::Same(Some(v)) => lookup(self.brand_id_map.try_get_id(&v).await),
+			Partial::Same(None) => None,
 			Partial::Changed(x) => x,
 		};
 		let category_id = match partial.category_id {
-			Partial::Same(x) => match x {
-				Some(v) => Some(self.category_id_map.get_or_insert_id(&v).await as u16),
-				None => None,
-			},
+			Partial::Same(Some(v)) => lookup(self.category_id_map.try_get_id(&v).await),
+			Partial::Same(None) => None,
 			Partial::Changed(x) => x,
 		};
 		
-		GetAggregateRequestCompressedData {
+		Ok(GetAggregateRequestCompressedData {
 			origin_id: origin,
 			brand_id,
 			category_id,
-		}
+			has_unknown_value,
+		})
 	}
 }
 
@@ -376,7 +1017,7 @@ impl PartialCompressor<GetAggregateRequest> for LocalDB {
 			brand_id: match partial.brand_id {
 				Partial::Same(x) => match x {
 					Some(v) => self.brand_id_map.try_get_id(&v).await
-						.map_same(|x| Some(x))
+						.map_same(Some)
 						.map_changed(|x| Some(x as u16)),
 					None => Partial::Changed(None),
 				},
@@ -385,7 +1026,7 @@ impl PartialCompressor<GetAggregateRequest> for LocalDB {
 			category_id: match partial.category_id {
 				Partial::Same(x) => match x {
 					Some(v) => self.category_id_map.try_get_id(&v).await
-						.map_same(|x| Some(x))
+						.map_same(Some)
 						.map_changed(|x| Some(x as u16)),
 					None => Partial::Changed(None),
 				},
@@ -394,7 +1035,7 @@ impl PartialCompressor<GetAggregateRequest> for LocalDB {
 			origin_id: match partial.origin_id {
 				Partial::Same(x) => match x {
 					Some(v) => self.origin_id_map.try_get_id(&v).await
-						.map_same(|x| Some(x))
+						.map_same(Some)
 						.map_changed(|x| Some(x as u16)),
 					None => Partial::Changed(None),
 				},
@@ -404,23 +1045,109 @@ impl PartialCompressor<GetAggregateRequest> for LocalDB {
 	}
 	
 	async fn update_compression(&self, partial: &PartialGetAggregateRequestCompressedData, compressed: &GetAggregateRequestCompressedData) {
-		if let Partial::Same(x) = &partial.brand_id {
-			if let Some(x) = x {
-				self.brand_id_map.force_insert_mapping(x, compressed.brand_id.unwrap() as usize).await;
-			}
+		if let (Partial::Same(Some(x)), Some(id)) = (&partial.brand_id, compressed.brand_id) {
+			self.brand_id_map.force_insert_mapping(x, id as usize).await;
 		}
-		if let Partial::Same(x) = &partial.origin_id {
-			if let Some(x) = x {
-				self.origin_id_map.force_insert_mapping(x, compressed.origin_id.unwrap() as usize).await;
-			}
+		if let (Partial::Same(Some(x)), Some(id)) = (&partial.origin_id, compressed.origin_id) {
+			self.origin_id_map.force_insert_mapping(x, id as usize).await;
 		}
-		if let Partial::Same(x) = &partial.category_id {
-			if let Some(x) = x {
-				self.category_id_map.force_insert_mapping(x, compressed.category_id.unwrap() as usize).await;
-			}
+		if let (Partial::Same(Some(x)), Some(id)) = (&partial.category_id, compressed.category_id) {
+			self.category_id_map.force_insert_mapping(x, id as usize).await;
+		}
+	}
+}
+
+impl Decompressor<CubeKey> for LocalDB {
+	async fn decompress_with_partial(&self, partial: PartialCubeKeyDecompressedData) -> DbResult<CubeKeyDecompressedData> {
+		Ok(CubeKeyDecompressedData {
+			origin_id: match partial.origin_id {
+				Partial::Same(x) => x,
+				Partial::Changed(x) => match x {
+					Some(v) => self.origin_id_map.get_string(v as usize).await,
+					None => None,
+				},
+			},
+			brand_id: match partial.brand_id {
+				Partial::Same(x) => x,
+				Partial::Changed(x) => match x {
+					Some(v) => self.brand_id_map.get_string(v as usize).await,
+					None => None,
+				},
+			},
+			category_id: match partial.category_id {
+				Partial::Same(x) => x,
+				Partial::Changed(x) => match x {
+					Some(v) => self.category_id_map.get_string(v as usize).await,
+					None => None,
+				},
+			},
+		})
+	}
+}
+
+impl PartialDecompressor<CubeKey> for LocalDB {
+	async fn partial_decompress_with_partial(&self, partial: PartialCubeKeyDecompressedData) -> PartialCubeKeyDecompressedData {
+		PartialCubeKeyDecompressedData {
+			origin_id: match partial.origin_id {
+				Partial::Changed(Some(v)) => self.origin_id_map.try_get_string(v as usize).await
+					.map_same(Some)
+					.map_changed(|x| Some(x as u16)),
+				x => x,
+			},
+			brand_id: match partial.brand_id {
+				Partial::Changed(Some(v)) => self.brand_id_map.try_get_string(v as usize).await
+					.map_same(Some)
+					.map_changed(|x| Some(x as u16)),
+				x => x,
+			},
+			category_id: match partial.category_id {
+				Partial::Changed(Some(v)) => self.category_id_map.try_get_string(v as usize).await
+					.map_same(Some)
+					.map_changed(|x| Some(x as u16)),
+				x => x,
+			},
+		}
+	}
+	
+	async fn update_compression(&self, partial: &PartialCubeKeyDecompressedData, compressed: &CubeKey) {
+		if let (Partial::Same(Some(x)), Some(id)) = (&partial.origin_id, compressed.origin_id) {
+			self.origin_id_map.force_insert_mapping(x, id as usize).await;
+		}
+		if let (Partial::Same(Some(x)), Some(id)) = (&partial.brand_id, compressed.brand_id) {
+			self.brand_id_map.force_insert_mapping(x, id as usize).await;
+		}
+		if let (Partial::Same(Some(x)), Some(id)) = (&partial.category_id, compressed.category_id) {
+			self.category_id_map.force_insert_mapping(x, id as usize).await;
+		}
+	}
+}
+
+impl Decompressor<TopKey> for LocalDB {
+	async fn decompress_with_partial(&self, partial: PartialTopKeyDecompressedData) -> DbResult<Option<String>> {
+		Ok(match partial.value {
+			Partial::Same(x) => Some(x),
+			Partial::Changed(x) => self.mapper(partial.dimension.dictionary()).get_string(x as usize).await,
+		})
+	}
+}
+
+impl PartialDecompressor<TopKey> for LocalDB {
+	async fn partial_decompress_with_partial(&self, partial: PartialTopKeyDecompressedData) -> PartialTopKeyDecompressedData {
+		PartialTopKeyDecompressedData {
+			dimension: partial.dimension,
+			value: match partial.value {
+				Partial::Same(x) => Partial::Same(x),
+				Partial::Changed(x) => self.mapper(partial.dimension.dictionary()).try_get_string(x as usize).await.map_changed(|x| x as u64),
+			},
+		}
+	}
+	
+	async fn update_compression(&self, partial: &PartialTopKeyDecompressedData, compressed: &TopKey) {
+		if let Partial::Same(x) = &partial.value {
+			self.mapper(partial.dimension.dictionary()).force_insert_mapping(x, compressed.id as usize).await;
 		}
 	}
 }
 
 impl Synced for LocalDB {}
-impl CompressingDB for LocalDB {}
\ No newline at end of file
+impl CompressingDB for LocalDB {}
@@ -2,10 +2,24 @@ mod aerospike;
 // mod surrealdb;
 mod local;
 mod cache;
+mod hot_profiles;
 mod traits;
+mod time_ring;
+mod snapshot;
+mod error;
+mod backend;
+mod write_queue;
+mod wal;
 
 pub use traits::*;
 pub use aerospike::*;
 // pub use surrealdb::*;
 pub use local::*;
 pub use cache::*;
+pub use hot_profiles::*;
+pub use time_ring::*;
+pub use snapshot::*;
+pub use error::*;
+pub use backend::*;
+pub use write_queue::*;
+pub use wal::*;
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// On-disk copy of a part of the `LocalDB` state. Files written with a different `VERSION`
+/// are rejected instead of being misread.
+pub trait Snapshot: Serialize + DeserializeOwned {
+	const VERSION: u32;
+	/// What the snapshot holds, for the error messages
+	const CONTENT: &'static str;
+	
+	/// Reads the snapshot, `None` when the file does not exist yet
+	fn load(path: &Path) -> Result<Option<Self>> {
+		let content = match std::fs::read(path) {
+			Ok(content) => content,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(err.into()),
+		};
+		let header: SnapshotHeader = serde_json::from_slice(&content)?;
+		if header.version != Self::VERSION {
+			bail!("{} snapshot {} has version {}, expected {}", Self::CONTENT, path.display(), header.version, Self::VERSION);
+		}
+		Ok(Some(serde_json::from_slice(&content)?))
+	}
+	
+	/// Writes to a temporary file first, so a crash never leaves a truncated snapshot behind
+	async fn save(&self, path: &Path) -> Result<()> {
+		let temporary = path.with_extension("tmp");
+		tokio::fs::write(&temporary, serde_json::to_vec(self)?).await?;
+		tokio::fs::rename(&temporary, path).await?;
+		Ok(())
+	}
+}
+
+#[derive(Deserialize)]
+struct SnapshotHeader {
+	version: u32,
+}
+
+/// Contents of a single `Mapper`: string to id and id to string, `None` for an id whose value
+/// is not known yet
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub struct MapperContent {
+	pub ids: HashMap<String, usize>,
+	pub values: Vec<Option<String>>,
+}
+
+/// All `LocalDB` dictionaries
+#[derive(Deserialize, Serialize, Default, Debug)]
+pub struct MappingsSnapshot {
+	pub version: u32,
+	pub product_id: MapperContent,
+	pub origin_id: MapperContent,
+	pub brand_id: MapperContent,
+	pub country_id: MapperContent,
+	pub category_id: MapperContent,
+}
+
+impl Snapshot for MappingsSnapshot {
+	const VERSION: u32 = 2;
+	const CONTENT: &'static str = "mappings";
+}
+
+/// Binary on-disk copy of a part of the `LocalDB` state: a magic, a version byte, the content
+/// and a CRC32 of everything before it. Written synchronously, callers run it off the runtime.
+pub trait BinarySnapshot: Sized {
+	const MAGIC: [u8; 4];
+	const VERSION: u8;
+	/// What the snapshot holds, for the error messages
+	const CONTENT: &'static str;
+	
+	fn encode(&self, writer: &mut SnapshotWriter);
+	fn decode(reader: &mut SnapshotReader) -> Result<Self>;
+	
+	/// Reads the snapshot, `None` when the file does not exist yet
+	fn load(path: &Path) -> Result<Option<Self>> {
+		let content = match std::fs::read(path) {
+			Ok(content) => content,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(err.into()),
+		};
+		if content.len() < Self::MAGIC.len() + 1 + 4 || content[..Self::MAGIC.len()] != Self::MAGIC {
+			bail!("{} is not a {} snapshot", path.display(), Self::CONTENT);
+		}
+		let (content, crc) = content.split_at(content.len() - 4);
+		if crc32fast::hash(content).to_le_bytes() != crc {
+			bail!("{} snapshot {} is corrupted", Self::CONTENT, path.display());
+		}
+		let mut reader = SnapshotReader::new(&content[Self::MAGIC.len()..]);
+		let version = reader.u8()?;
+		if version != Self::VERSION {
+			bail!("{} snapshot {} has version {}, expected {}", Self::CONTENT, path.display(), version, Self::VERSION);
+		}
+		let snapshot = Self::decode(&mut reader)?;
+		if !reader.is_empty() {
+			bail!("{} snapshot {} has unexpected bytes at the end", Self::CONTENT, path.display());
+		}
+		Ok(Some(snapshot))
+	}
+	
+	/// Writes and syncs a temporary file first, so a crash never leaves a truncated snapshot behind
+	fn write(&self, path: &Path) -> Result<()> {
+		let mut writer = SnapshotWriter::default();
+		writer.bytes(&Self::MAGIC);
+		writer.u8(Self::VERSION);
+		self.encode(&mut writer);
+		let crc = crc32fast::hash(&writer.bytes);
+		writer.u32(crc);
+		
+		let temporary = path.with_extension("tmp");
+		let mut file = File::create(&temporary)?;
+		file.write_all(&writer.bytes)?;
+		file.sync_all()?;
+		std::fs::rename(&temporary, path)?;
+		// the rename itself has to survive a crash before anything relies on the snapshot
+		let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+		File::open(dir)?.sync_all()?;
+		Ok(())
+	}
+}
+
+/// Little-endian encoding of the values of a `BinarySnapshot` or of a `WalRecord`
+#[derive(Default)]
+pub struct SnapshotWriter {
+	bytes: Vec<u8>,
+}
+
+impl SnapshotWriter {
+	pub fn into_bytes(self) -> Vec<u8> {
+		self.bytes
+	}
+	
+	pub fn u8(&mut self, value: u8) {
+		self.bytes.push(value);
+	}
+	
+	pub fn u32(&mut self, value: u32) {
+		self.bytes.extend_from_slice(&value.to_le_bytes());
+	}
+	
+	pub fn i32(&mut self, value: i32) {
+		self.bytes.extend_from_slice(&value.to_le_bytes());
+	}
+	
+	pub fn u64(&mut self, value: u64) {
+		self.bytes.extend_from_slice(&value.to_le_bytes());
+	}
+	
+	pub fn i64(&mut self, value: i64) {
+		self.bytes.extend_from_slice(&value.to_le_bytes());
+	}
+	
+	/// Number of the items that follow
+	pub fn count(&mut self, count: usize) {
+		self.u64(count as u64);
+	}
+	
+	pub fn bytes(&mut self, value: &[u8]) {
+		self.bytes.extend_from_slice(value);
+	}
+	
+	pub fn string(&mut self, value: &str) {
+		self.count(value.len());
+		self.bytes(value.as_bytes());
+	}
+}
+
+/// Reads back what `SnapshotWriter` wrote, failing instead of panicking on a short input
+pub struct SnapshotReader<'a> {
+	bytes: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+	pub fn new(bytes: &'a [u8]) -> Self {
+		Self { bytes }
+	}
+	
+	/// Whether everything was read
+	pub fn is_empty(&self) -> bool {
+		self.bytes.is_empty()
+	}
+	
+	pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+		if self.bytes.len() < len {
+			bail!("snapshot ends {} bytes early", len - self.bytes.len());
+		}
+		let (value, rest) = self.bytes.split_at(len);
+		self.bytes = rest;
+		Ok(value)
+	}
+	
+	fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+		Ok(self.bytes(N)?.try_into().unwrap())
+	}
+	
+	pub fn u8(&mut self) -> Result<u8> {
+		Ok(u8::from_le_bytes(self.take()?))
+	}
+	
+	pub fn i32(&mut self) -> Result<i32> {
+		Ok(i32::from_le_bytes(self.take()?))
+	}
+	
+	pub fn u64(&mut self) -> Result<u64> {
+		Ok(u64::from_le_bytes(self.take()?))
+	}
+	
+	pub fn i64(&mut self) -> Result<i64> {
+		Ok(i64::from_le_bytes(self.take()?))
+	}
+	
+	pub fn count(&mut self) -> Result<usize> {
+		Ok(self.u64()? as usize)
+	}
+	
+	pub fn string(&mut self) -> Result<String> {
+		let len = self.count()?;
+		Ok(String::from_utf8(self.bytes(len)?.to_vec())?)
+	}
+}
@@ -53,7 +53,7 @@ DEFINE TABLE buy_tags SCHEMALESS;
 DEFINE FIELD tags ON TABLE view_tags TYPE array<object, {MAX_TAGS}>;
 DEFINE FIELD tags ON TABLE buy_tags TYPE array<object, {MAX_TAGS}>;
 ")).await?;
-		
+
 		// Select a specific namespace / database
 		db.use_ns("test").use_db("test").await?;
 		Ok(Self {
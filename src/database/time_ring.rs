@@ -0,0 +1,93 @@
+use std::collections::VecDeque;
+use crate::data::time::TimeRange;
+
+/// Ring buffer of slots indexed by time bucket (e.g. minute). Grows in both directions on
+/// demand and keeps at most `retention` consecutive slots, evicting the oldest ones.
+pub struct TimeRing<T> {
+	start: i64,
+	slots: VecDeque<T>,
+	retention: usize,
+	/// Buckets a new slot may lie past both the newest one and the current bucket
+	max_skew: i64,
+}
+
+impl<T: Default> TimeRing<T> {
+	pub fn new(retention: usize) -> Self {
+		Self {
+			start: 0,
+			slots: VecDeque::new(),
+			retention: retention.max(1),
+			max_skew: i64::MAX,
+		}
+	}
+	
+	/// Rejects buckets more than `max_skew` past both the newest slot and the current bucket,
+	/// so a single far-future timestamp cannot evict everything held
+	pub fn with_max_skew(mut self, max_skew: usize) -> Self {
+		self.max_skew = max_skew.max(1) as i64;
+		self
+	}
+	
+	fn end(&self) -> i64 {
+		self.start + self.slots.len() as i64
+	}
+	
+	/// Buckets currently held, `None` before the first insert
+	pub fn range(&self) -> Option<TimeRange> {
+		if self.slots.is_empty() {
+			return None;
+		}
+		Some(TimeRange {
+			start: self.start,
+			end: self.end(),
+		})
+	}
+	
+	/// Every slot held with its bucket, from the oldest
+	pub fn iter(&self) -> impl Iterator<Item = (i64, &T)> + '_ {
+		self.slots.iter().enumerate().map(|(offset, slot)| (self.start + offset as i64, slot))
+	}
+	
+	pub fn get(&self, index: i64) -> Option<&T> {
+		usize::try_from(index - self.start).ok()
+			.and_then(|offset| self.slots.get(offset))
+	}
+	
+	/// Whether the bucket lies too far past both the newest slot and `now`, the current bucket
+	pub fn is_ahead(&self, index: i64, now: i64) -> bool {
+		index >= self.end().max(now).saturating_add(self.max_skew)
+	}
+	
+	/// Slot of the given bucket, creating it (and any gap before it) if needed. Returns `None`
+	/// when the bucket is already older than the retention window or `is_ahead` of `now`.
+	pub fn get_or_insert(&mut self, index: i64, now: i64) -> Option<&mut T> {
+		if self.is_ahead(index, now) {
+			return None;
+		}
+		let retention = self.retention as i64;
+		if self.slots.is_empty() || index >= self.end() + retention {
+			self.slots.clear();
+			self.start = index;
+		}
+		
+		if index < self.start {
+			if self.end() - index > retention {
+				return None;
+			}
+			while self.start > index {
+				self.slots.push_front(T::default());
+				self.start -= 1;
+			}
+		}
+		
+		while self.end() <= index {
+			self.slots.push_back(T::default());
+		}
+		while self.slots.len() > self.retention {
+			self.slots.pop_front();
+			self.start += 1;
+		}
+		
+		self.slots.get_mut((index - self.start) as usize)
+	}
+}
@@ -1,21 +1,46 @@
 use std::future::Future;
-use crate::data::{AggregateTagEvent, Compress, Cookie, Decompress, UserAction, UserProfile, UserTagEvent};
+use crate::data::{AggregateTagEvent, Compress, Cookie, Decompress, DictionaryFill, UserAction, UserProfile, UserTagEvent};
 use crate::api::*;
+use crate::database::DbResult;
 
 pub trait Database {
-	fn add_user_event(&self, cookie: &Cookie, tag: UserTagEvent, action: UserAction) -> impl Future<Output = ()> + Send;
-	/// Get last MAX_TAGS buy tags and view tags for a given cookie
-	fn get_user_profile(&self, cookie: &Cookie) -> impl Future<Output = UserProfile> + Send;
-	fn add_aggregate_event(&self, timestamp: i64, tag: AggregateTagEvent) -> impl Future<Output = ()> + Send;
-	fn get_aggregate(&self, request: &GetAggregateRequest) -> impl Future<Output = GetAggregateResponse> + Send;
+	fn add_user_event(&self, cookie: &Cookie, tag: UserTagEvent, action: UserAction) -> impl Future<Output = DbResult<()>> + Send;
+	/// Adds many tags of a single cookie at once
+	fn add_user_events(&self, cookie: &Cookie, tags: Vec<(UserTagEvent, UserAction)>) -> impl Future<Output = DbResult<()>> + Send;
+	/// Get last `max_tags` buy tags and view tags for a given cookie, each list sorted by time
+	fn get_user_profile(&self, cookie: &Cookie) -> impl Future<Output = DbResult<UserProfile>> + Send;
+	fn add_aggregate_event(&self, timestamp: i64, tag: AggregateTagEvent) -> impl Future<Output = DbResult<()>> + Send;
+	/// Adds many `(timestamp, tag)` aggregate events at once, with the result of every event
+	fn add_aggregate_events(&self, events: Vec<(i64, AggregateTagEvent)>) -> impl Future<Output = Vec<DbResult<()>>> + Send;
+	fn get_aggregate(&self, request: &GetAggregateRequest) -> impl Future<Output = DbResult<GetAggregateResponse>> + Send;
+	/// Like `get_aggregate`, but split into one group per value of `request.group_by`
+	fn get_grouped_aggregate(&self, request: &GetAggregateRequest) -> impl Future<Output = DbResult<GetGroupedAggregateResponse>> + Send;
+	/// The `request.n` values of `request.dimension` with the highest `request.metric` over the time range
+	fn get_top(&self, request: &GetTopRequest) -> impl Future<Output = DbResult<GetTopResponse>> + Send;
+	/// Number of values in every compression dictionary
+	fn get_dictionaries(&self) -> impl Future<Output = DbResult<Vec<DictionaryFill>>> + Send;
 }
 
 pub trait Compressor<T: Compress> where T::From: Clone {
-	async fn compress(&self, value: &T::From) -> T::CompressedData {
+	async fn compress(&self, value: &T::From) -> DbResult<T::CompressedData> {
 		self.compress_with_partial(T::PartialCompressedData::from(value.clone())).await
 	}
 	
-	async fn compress_with_partial(&self, partial: T::PartialCompressedData) -> T::CompressedData;
+	/// Fails with `DbError::Overflow` when a new value does not fit into its dictionary
+	async fn compress_with_partial(&self, partial: T::PartialCompressedData) -> DbResult<T::CompressedData>;
+	
+	async fn compress_batch(&self, values: &[T::From]) -> Vec<DbResult<T::CompressedData>> {
+		self.compress_batch_with_partial(values.iter().cloned().map(T::PartialCompressedData::from).collect()).await
+	}
+	
+	/// Compresses the values one by one, remote backends resolve them in a single round trip instead
+	async fn compress_batch_with_partial(&self, partials: Vec<T::PartialCompressedData>) -> Vec<DbResult<T::CompressedData>> {
+		let mut compressed = vec![];
+		for partial in partials {
+			compressed.push(self.compress_with_partial(partial).await);
+		}
+		compressed
+	}
 }
 
 pub trait PartialCompressor<T: Compress> {
@@ -24,11 +49,11 @@ pub trait PartialCompressor<T: Compress> {
 }
 
 pub trait Decompressor<T: Decompress> where T: Clone {
-	async fn decompress(&self, value: &T) -> T::DecompressedData {
+	async fn decompress(&self, value: &T) -> DbResult<T::DecompressedData> {
 		self.decompress_with_partial(T::PartialDecompressedData::from(value.clone())).await
 	}
 	
-	async fn decompress_with_partial(&self, partial: T::PartialDecompressedData) -> T::DecompressedData;
+	async fn decompress_with_partial(&self, partial: T::PartialDecompressedData) -> DbResult<T::DecompressedData>;
 }
 
 pub trait PartialDecompressor<T: Decompress> {
@@ -0,0 +1,382 @@
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use tokio::sync::oneshot;
+
+use crate::config::{FsyncPolicy, WalConfig};
+use crate::data::{AggregateTagEvent, BinaryCodec, Cookie, decode_action, Dictionary, UserAction, UserTagEvent};
+use crate::database::{DbError, DbResult, SnapshotReader, SnapshotWriter};
+
+/// Change of the `LocalDB` state, logged before it is applied
+#[derive(Debug)]
+pub enum WalRecord {
+	UserEvents {
+		cookie: Cookie,
+		tags: Vec<(UserTagEvent, UserAction)>,
+	},
+	AggregateEvent {
+		timestamp: i64,
+		tag: AggregateTagEvent,
+	},
+	/// A new dictionary id, logged before any event can refer to it
+	Mapping {
+		dictionary: Dictionary,
+		value: String,
+		id: u64,
+	},
+}
+
+/// kind: u8, then
+/// user events: cookie: string, tags: count, (action: u8, UserTagEvent)*
+/// aggregate event: timestamp: i64, AggregateTagEvent
+/// mapping: dictionary: u8, value: string, id: u64
+impl WalRecord {
+	const USER_EVENTS: u8 = 1;
+	const AGGREGATE_EVENT: u8 = 2;
+	const MAPPING: u8 = 3;
+	
+	fn encode(&self, writer: &mut SnapshotWriter) {
+		match self {
+			WalRecord::UserEvents { cookie, tags } => {
+				writer.u8(Self::USER_EVENTS);
+				writer.string(&cookie.0);
+				writer.count(tags.len());
+				for (tag, action) in tags {
+					writer.u8(*action as u8);
+					writer.bytes(&tag.encode());
+				}
+			},
+			WalRecord::AggregateEvent { timestamp, tag } => {
+				writer.u8(Self::AGGREGATE_EVENT);
+				writer.i64(*timestamp);
+				writer.bytes(&tag.encode());
+			},
+			WalRecord::Mapping { dictionary, value, id } => {
+				writer.u8(Self::MAPPING);
+				writer.u8(Dictionary::ALL.iter().position(|x| x == dictionary).unwrap() as u8);
+				writer.string(value);
+				writer.u64(*id);
+			},
+		}
+	}
+	
+	fn decode(reader: &mut SnapshotReader) -> Result<Self> {
+		Ok(match reader.u8()? {
+			Self::USER_EVENTS => {
+				let cookie = Cookie(reader.string()?);
+				let mut tags = vec![];
+				for _ in 0..reader.count()? {
+					let action = decode_action(reader.u8()?)?;
+					tags.push((UserTagEvent::decode(reader.bytes(UserTagEvent::SIZE)?)?, action));
+				}
+				WalRecord::UserEvents { cookie, tags }
+			},
+			Self::AGGREGATE_EVENT => WalRecord::AggregateEvent {
+				timestamp: reader.i64()?,
+				tag: AggregateTagEvent::decode(reader.bytes(AggregateTagEvent::SIZE)?)?,
+			},
+			Self::MAPPING => {
+				let dictionary = reader.u8()?;
+				WalRecord::Mapping {
+					dictionary: *Dictionary::ALL.get(dictionary as usize).with_context(|| format!("unknown dictionary {}", dictionary))?,
+					value: reader.string()?,
+					id: reader.u64()?,
+				}
+			},
+			kind => bail!("unknown record kind {}", kind),
+		})
+	}
+}
+
+fn storage_error(err: impl Display) -> DbError {
+	DbError::Storage(format!("write-ahead log: {}", err))
+}
+
+/// Segment the records are appended to
+struct Segment {
+	id: u64,
+	file: BufWriter<File>,
+	bytes: u64,
+	/// Appended to since the last sync
+	dirty: bool,
+	synced: Instant,
+	/// A write failed halfway, the next record goes to a new segment
+	broken: bool,
+}
+
+/// Request to the writer thread, answered once it is done
+enum WalCommand {
+	/// Encoded records, written together
+	Append(Vec<Vec<u8>>, oneshot::Sender<DbResult<()>>),
+	Sync(oneshot::Sender<DbResult<()>>),
+	Rotate(oneshot::Sender<DbResult<u64>>),
+}
+
+/// Owns the current segment on the thread of the log
+struct WalWriter {
+	config: WalConfig,
+	segment: Segment,
+}
+
+impl WalWriter {
+	/// Group commit: the appends queued while the previous ones were written are written one
+	/// by one and then synced once, before any of them is answered
+	fn run(mut self, commands: Receiver<WalCommand>) {
+		while let Ok(command) = commands.recv() {
+			let mut appended = vec![];
+			for command in std::iter::once(command).chain(commands.try_iter()) {
+				match command {
+					WalCommand::Append(payloads, done) => {
+						let written = self.write(&payloads);
+						appended.push((done, written));
+					},
+					// ordered after the appends before them
+					WalCommand::Sync(done) => {
+						self.commit(std::mem::take(&mut appended));
+						let _ = done.send(self.sync());
+					},
+					WalCommand::Rotate(done) => {
+						self.commit(std::mem::take(&mut appended));
+						let _ = done.send(self.next_segment().map(|_| self.segment.id));
+					},
+				}
+			}
+			self.commit(appended);
+		}
+	}
+	
+	fn write(&mut self, payloads: &[Vec<u8>]) -> DbResult<()> {
+		if self.segment.broken || self.segment.bytes >= self.config.segment_bytes {
+			self.next_segment()?;
+		}
+		let segment = &mut self.segment;
+		let written = payloads.iter()
+			.try_for_each(|payload| {
+				segment.file.write_all(&(payload.len() as u32).to_le_bytes())?;
+				segment.file.write_all(&crc32fast::hash(payload).to_le_bytes())?;
+				segment.file.write_all(payload)
+			})
+			.and_then(|_| segment.file.flush());
+		if let Err(err) = written {
+			segment.broken = true;
+			return Err(storage_error(err));
+		}
+		segment.bytes += payloads.iter().map(|payload| 8 + payload.len() as u64).sum::<u64>();
+		segment.dirty = true;
+		Ok(())
+	}
+	
+	/// Syncs the appends written as the fsync policy says and answers them
+	fn commit(&mut self, appended: Vec<(oneshot::Sender<DbResult<()>>, DbResult<()>)>) {
+		if appended.is_empty() {
+			return;
+		}
+		let synced = match self.config.fsync {
+			FsyncPolicy::Always => self.sync(),
+			FsyncPolicy::Periodic if self.segment.synced.elapsed() >= self.config.fsync_interval() => self.sync(),
+			FsyncPolicy::Periodic | FsyncPolicy::Never => Ok(()),
+		};
+		for (done, written) in appended {
+			let _ = done.send(written.and(synced.clone()));
+		}
+	}
+	
+	fn sync(&mut self) -> DbResult<()> {
+		let segment = &mut self.segment;
+		if segment.dirty {
+			segment.file.get_ref().sync_data().map_err(storage_error)?;
+			segment.dirty = false;
+		}
+		segment.synced = Instant::now();
+		Ok(())
+	}
+	
+	fn next_segment(&mut self) -> DbResult<()> {
+		if !self.segment.broken {
+			self.sync()?;
+		}
+		self.segment = Wal::create_segment(&self.config.dir, self.segment.id + 1).map_err(storage_error)?;
+		Ok(())
+	}
+}
+
+/// Append-only log of the `LocalDB` changes, split into numbered segment files.
+/// Every record is `length: u32, crc: u32, payload`, the CRC32 being of the payload.
+/// The segments are written and synced by a thread of their own, so the handlers only wait.
+pub struct Wal {
+	dir: PathBuf,
+	/// `None` only while dropped, which stops the writer
+	commands: Option<Sender<WalCommand>>,
+	writer: Option<JoinHandle<()>>,
+}
+
+impl Wal {
+	/// Starts a new segment after the existing ones and `first`, so a segment a crash may have
+	/// torn is never appended to
+	pub fn open(config: &WalConfig, first: u64) -> Result<Self> {
+		std::fs::create_dir_all(&config.dir)?;
+		let id = Self::segments(&config.dir)?.last()
+			.map(|(id, _)| id + 1)
+			.unwrap_or_default()
+			.max(first);
+		let writer = WalWriter {
+			config: config.clone(),
+			segment: Self::create_segment(&config.dir, id)?,
+		};
+		let (commands, received) = std::sync::mpsc::channel();
+		let writer = std::thread::Builder::new()
+			.name(String::from("wal-writer"))
+			.spawn(move || writer.run(received))?;
+		Ok(Self {
+			dir: config.dir.clone(),
+			commands: Some(commands),
+			writer: Some(writer),
+		})
+	}
+	
+	fn segment_path(dir: &Path, id: u64) -> PathBuf {
+		dir.join(format!("{:020}.wal", id))
+	}
+	
+	fn create_segment(dir: &Path, id: u64) -> Result<Segment> {
+		let file = OpenOptions::new().write(true).create_new(true).open(Self::segment_path(dir, id))?;
+		File::open(dir)?.sync_all()?;
+		Ok(Segment {
+			id,
+			file: BufWriter::new(file),
+			bytes: 0,
+			dirty: false,
+			synced: Instant::now(),
+			broken: false,
+		})
+	}
+	
+	/// Segment files of the directory with their ids, oldest first
+	pub fn segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+		let mut segments = vec![];
+		for entry in std::fs::read_dir(dir)? {
+			let path = entry?.path();
+			if path.extension().is_some_and(|extension| extension == "wal") {
+				if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) {
+					segments.push((id, path));
+				}
+			}
+		}
+		segments.sort();
+		Ok(segments)
+	}
+	
+	/// Hands the records of the segments from `first` on to `apply`, in the order they were logged.
+	/// A torn or corrupted record ends its segment, the following ones are still replayed.
+	pub fn replay(dir: &Path, first: u64, mut apply: impl FnMut(WalRecord)) -> Result<usize> {
+		if !dir.exists() {
+			return Ok(0);
+		}
+		let mut replayed = 0;
+		for (id, path) in Self::segments(dir)? {
+			if id < first {
+				continue;
+			}
+			let content = std::fs::read(&path)?;
+			let mut rest = content.as_slice();
+			while !rest.is_empty() {
+				match Self::next_record(&mut rest) {
+					Ok(record) => {
+						apply(record);
+						replayed += 1;
+					},
+					Err(err) => {
+						eprintln!("Skipping the {} last bytes of write-ahead log segment {}: {:#}", rest.len(), path.display(), err);
+						break;
+					},
+				}
+			}
+		}
+		Ok(replayed)
+	}
+	
+	fn next_record(rest: &mut &[u8]) -> Result<WalRecord> {
+		if rest.len() < 8 {
+			bail!("record header is torn");
+		}
+		let length = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+		let crc = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+		if rest.len() < 8 + length {
+			bail!("record is torn");
+		}
+		let payload = &rest[8..8 + length];
+		if crc32fast::hash(payload) != crc {
+			bail!("record is corrupted");
+		}
+		let mut reader = SnapshotReader::new(payload);
+		let record = WalRecord::decode(&mut reader)?;
+		if !reader.is_empty() {
+			bail!("record has unexpected bytes at the end");
+		}
+		*rest = &rest[8 + length..];
+		Ok(record)
+	}
+	
+	async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<DbResult<T>>) -> WalCommand) -> DbResult<T> {
+		let (done, answer) = oneshot::channel();
+		self.commands.as_ref()
+			.and_then(|commands| commands.send(command(done)).ok())
+			.ok_or_else(|| storage_error("the writer thread is gone"))?;
+		answer.await.map_err(|_| storage_error("the writer thread is gone"))?
+	}
+	
+	/// Writes the record and syncs it as the fsync policy says
+	pub async fn append(&self, record: &WalRecord) -> DbResult<()> {
+		self.append_all(std::slice::from_ref(record)).await
+	}
+	
+	/// Writes the records one after another, with a single sync if the policy calls for one
+	pub async fn append_all(&self, records: &[WalRecord]) -> DbResult<()> {
+		let payloads = records.iter()
+			.map(|record| {
+				let mut writer = SnapshotWriter::default();
+				record.encode(&mut writer);
+				writer.into_bytes()
+			})
+			.collect();
+		self.request(|done| WalCommand::Append(payloads, done)).await
+	}
+	
+	/// Syncs what was appended since the last sync, for the periodic policy
+	pub async fn sync(&self) -> DbResult<()> {
+		self.request(WalCommand::Sync).await
+	}
+	
+	/// Moves on to a new segment and returns its id, every record logged so far is in the older ones
+	pub async fn rotate(&self) -> DbResult<u64> {
+		self.request(WalCommand::Rotate).await
+	}
+	
+	/// Deletes the segments before `first`, once a snapshot holds their records
+	pub fn truncate(&self, first: u64) -> Result<()> {
+		for (id, path) in Self::segments(&self.dir)? {
+			if id < first {
+				std::fs::remove_file(path)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Waits for the writer to answer what was sent before
+impl Drop for Wal {
+	fn drop(&mut self) {
+		self.commands = None;
+		if let Some(writer) = self.writer.take() {
+			if writer.join().is_err() {
+				eprintln!("Write-ahead log thread panicked");
+			}
+		}
+	}
+}
@@ -0,0 +1,529 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tokio::sync::Notify;
+
+use crate::config::{OverflowPolicy, WriteQueueConfig};
+use crate::data::{AggregateTagEvent, Cookie, UserAction, UserTagEvent};
+use crate::database::{DbError, DbResult, SyncedDB};
+use crate::metrics::METRICS;
+
+/// Remote write deferred by `CachedDB`
+#[derive(Debug)]
+pub enum Write {
+	UserEvents {
+		cookie: Cookie,
+		tags: Vec<(UserTagEvent, UserAction)>,
+		/// Sequence numbers of the tags, given by `WriteQueue::push`
+		seqs: Vec<u64>,
+	},
+	/// Aggregate events of a single minute
+	AggregateEvents {
+		timestamp: i64,
+		tags: Vec<AggregateTagEvent>,
+	},
+}
+
+impl Write {
+	/// Number of events, the unit of the pending and lost counters
+	pub fn events(&self) -> usize {
+		match self {
+			Write::UserEvents { tags, .. } => tags.len(),
+			Write::AggregateEvents { tags, .. } => tags.len(),
+		}
+	}
+	
+	fn method(&self) -> &'static str {
+		match self {
+			Write::UserEvents { .. } => "add_user_events",
+			Write::AggregateEvents { .. } => "add_aggregate_events",
+		}
+	}
+	
+	/// The writes of a cookie always land in the same shard
+	fn shard(&self, shards: usize) -> usize {
+		match self {
+			Write::UserEvents { cookie, .. } => {
+				let mut hasher = DefaultHasher::new();
+				cookie.hash(&mut hasher);
+				(hasher.finish() % shards as u64) as usize
+			},
+			Write::AggregateEvents { timestamp, .. } => timestamp.rem_euclid(shards as i64) as usize,
+		}
+	}
+}
+
+/// Joins the user events of every cookie and the aggregate events of every minute into a single
+/// write, keeping their order
+fn merge_batch(batch: Vec<Write>) -> Vec<Write> {
+	let mut merged: Vec<Write> = vec![];
+	let mut cookies: HashMap<Cookie, usize> = HashMap::new();
+	let mut minutes: HashMap<i64, usize> = HashMap::new();
+	for write in batch {
+		match write {
+			Write::UserEvents { cookie, tags, seqs } => match cookies.get(&cookie) {
+				Some(index) => {
+					if let Write::UserEvents { tags: merged_tags, seqs: merged_seqs, .. } = &mut merged[*index] {
+						merged_tags.extend(tags);
+						merged_seqs.extend(seqs);
+					}
+				},
+				None => {
+					cookies.insert(cookie.clone(), merged.len());
+					merged.push(Write::UserEvents { cookie, tags, seqs });
+				},
+			},
+			Write::AggregateEvents { timestamp, tags } => match minutes.get(&timestamp) {
+				Some(index) => {
+					if let Write::AggregateEvents { tags: merged_tags, .. } = &mut merged[*index] {
+						merged_tags.extend(tags);
+					}
+				},
+				None => {
+					minutes.insert(timestamp, merged.len());
+					merged.push(Write::AggregateEvents { timestamp, tags });
+				},
+			},
+		}
+	}
+	merged
+}
+
+/// Unflushed user events of a cookie, with the number of its writes started and finished
+#[derive(Default)]
+struct CookieEvents {
+	events: Vec<(u64, UserTagEvent, UserAction)>,
+	started: u64,
+	finished: u64,
+	/// Profile reads comparing the counters, the entry is kept while there are any
+	watchers: usize,
+}
+
+impl CookieEvents {
+	fn is_unused(&self) -> bool {
+		self.events.is_empty() && self.watchers == 0
+	}
+}
+
+/// Unflushed user events of a cookie at one moment, with their sequence numbers
+#[derive(Clone, Default)]
+pub struct UnflushedSnapshot {
+	pub events: Vec<(u64, UserTagEvent, UserAction)>,
+	started: u64,
+	finished: u64,
+}
+
+impl UnflushedSnapshot {
+	/// Whether no write of the cookie ran from this moment until the later one, so a remote read
+	/// made in between holds exactly the events flushed before and none of the unflushed ones
+	pub fn settled_until(&self, later: &UnflushedSnapshot) -> bool {
+		self.started == self.finished && later.started == self.started && later.finished == self.finished
+	}
+}
+
+/// User events queued but not written yet, by cookie, so profile reads can include them.
+/// Identical events are told apart by their sequence numbers.
+#[derive(Default)]
+struct UnflushedEvents {
+	cookies: DashMap<Cookie, CookieEvents>,
+	next_seq: AtomicU64,
+}
+
+impl UnflushedEvents {
+	/// Numbers the user events of the write
+	fn add(&self, write: &mut Write) {
+		if let Write::UserEvents { cookie, tags, seqs } = write {
+			let mut cookie_events = self.cookies.entry(cookie.clone()).or_default();
+			let first = self.next_seq.fetch_add(tags.len() as u64, Ordering::Relaxed);
+			*seqs = (first..first + tags.len() as u64).collect();
+			cookie_events.events.extend(seqs.iter().zip(tags.iter()).map(|(seq, (tag, action))| (*seq, *tag, *action)));
+		}
+	}
+	
+	/// The write is handed to the remote database
+	fn start(&self, write: &Write) {
+		if let Write::UserEvents { cookie, .. } = write {
+			if let Some(mut cookie_events) = self.cookies.get_mut(cookie) {
+				cookie_events.started += 1;
+			}
+		}
+	}
+	
+	/// Counts the write as finished too if it was started
+	fn remove(&self, write: &Write, started: bool) {
+		let Write::UserEvents { cookie, seqs, .. } = write else {
+			return;
+		};
+		if let Some(mut cookie_events) = self.cookies.get_mut(cookie) {
+			cookie_events.events.retain(|(seq, _, _)| !seqs.contains(seq));
+			if started {
+				cookie_events.finished += 1;
+			}
+		}
+		self.cookies.remove_if(cookie, |_, cookie_events| cookie_events.is_unused());
+	}
+	
+	fn watch(&self, cookie: &Cookie) {
+		self.cookies.entry(cookie.clone()).or_default().watchers += 1;
+	}
+	
+	fn unwatch(&self, cookie: &Cookie) {
+		if let Some(mut cookie_events) = self.cookies.get_mut(cookie) {
+			cookie_events.watchers -= 1;
+		}
+		self.cookies.remove_if(cookie, |_, cookie_events| cookie_events.is_unused());
+	}
+	
+	fn snapshot(&self, cookie: &Cookie) -> UnflushedSnapshot {
+		self.cookies.get(cookie)
+			.map(|cookie_events| UnflushedSnapshot {
+				events: cookie_events.events.clone(),
+				started: cookie_events.started,
+				finished: cookie_events.finished,
+			})
+			.unwrap_or_default()
+	}
+}
+
+/// Keeps the write counters of a cookie while a profile read compares them, see
+/// `UnflushedSnapshot::settled_until`
+pub struct UnflushedWatch<'a> {
+	unflushed: &'a UnflushedEvents,
+	cookie: Cookie,
+}
+
+impl UnflushedWatch<'_> {
+	pub fn snapshot(&self) -> UnflushedSnapshot {
+		self.unflushed.snapshot(&self.cookie)
+	}
+}
+
+impl Drop for UnflushedWatch<'_> {
+	fn drop(&mut self) {
+		self.unflushed.unwatch(&self.cookie);
+	}
+}
+
+/// Bounded queue with many producers, drained by the single writer of the shard
+pub struct WriteShard {
+	queue: Mutex<VecDeque<Write>>,
+	capacity: usize,
+	overflow: OverflowPolicy,
+	closed: AtomicBool,
+	/// Wakes the writer once something is queued or the shard is closed
+	queued: Notify,
+	/// Wakes the producers waiting for room
+	freed: Notify,
+}
+
+impl WriteShard {
+	pub fn new(capacity: usize, overflow: OverflowPolicy) -> Self {
+		Self {
+			queue: Default::default(),
+			capacity,
+			overflow,
+			closed: AtomicBool::new(false),
+			queued: Notify::new(),
+			freed: Notify::new(),
+		}
+	}
+	
+	/// Queues the write as the overflow policy allows, returns the write dropped to make room for it.
+	/// A rejected write is handed back with the error.
+	pub async fn push(&self, write: Write) -> Result<Option<Write>, (DbError, Write)> {
+		loop {
+			let freed = self.freed.notified();
+			tokio::pin!(freed);
+			// registers before the check, so room made in between is not missed
+			freed.as_mut().enable();
+			{
+				let mut queue = self.queue.lock().unwrap();
+				if self.closed.load(Ordering::Acquire) {
+					return Err((DbError::Overloaded(String::from("write queue is closed")), write));
+				}
+				if queue.len() < self.capacity {
+					queue.push_back(write);
+					self.queued.notify_one();
+					return Ok(None);
+				}
+				match self.overflow {
+					OverflowPolicy::Block => {},
+					OverflowPolicy::DropOldest => {
+						let dropped = queue.pop_front();
+						queue.push_back(write);
+						self.queued.notify_one();
+						return Ok(dropped);
+					},
+					OverflowPolicy::Reject => {
+						return Err((DbError::Overloaded(format!("write queue is full with {} writes", self.capacity)), write));
+					},
+				}
+			}
+			freed.await;
+		}
+	}
+	
+	/// Up to `max` of the oldest writes, waits while there are none.
+	/// `None` once the shard is closed and drained.
+	pub async fn take_batch(&self, max: usize) -> Option<Vec<Write>> {
+		loop {
+			let queued = self.queued.notified();
+			tokio::pin!(queued);
+			queued.as_mut().enable();
+			{
+				let mut queue = self.queue.lock().unwrap();
+				if !queue.is_empty() {
+					let taken = max.min(queue.len());
+					let batch = queue.drain(..taken).collect();
+					self.freed.notify_waiters();
+					return Some(batch);
+				}
+				if self.closed.load(Ordering::Acquire) {
+					return None;
+				}
+			}
+			queued.await;
+		}
+	}
+	
+	/// Rejects new writes, the queued ones are still handed to the writer
+	pub fn close(&self) {
+		let _queue = self.queue.lock().unwrap();
+		self.closed.store(true, Ordering::Release);
+		self.queued.notify_one();
+		self.freed.notify_waiters();
+	}
+}
+
+/// State shared by the queue and its writers
+#[derive(Default)]
+struct WriteCounters {
+	/// Events queued or being written
+	pending: AtomicUsize,
+	/// Events dropped on overflow or failed after every retry
+	lost: AtomicU64,
+	unflushed: UnflushedEvents,
+}
+
+impl WriteCounters {
+	/// The write is done with, written or not
+	fn finish(&self, write: &Write, started: bool) {
+		self.unflushed.remove(write, started);
+		self.pending.fetch_sub(write.events(), Ordering::Relaxed);
+	}
+	
+	fn lose(&self, reason: &str, events: usize) {
+		self.lost.fetch_add(events as u64, Ordering::Relaxed);
+		METRICS.record_lost_events(reason, events);
+	}
+}
+
+/// Drains one shard into the remote database
+struct Writer<T: SyncedDB> {
+	shard: Arc<WriteShard>,
+	remote_db: Arc<T>,
+	counters: Arc<WriteCounters>,
+	config: WriteQueueConfig,
+}
+
+impl<T: SyncedDB> Writer<T> {
+	async fn run(self) {
+		while let Some(batch) = self.shard.take_batch(self.config.batch_size).await {
+			for write in merge_batch(batch) {
+				let events = write.events();
+				self.counters.unflushed.start(&write);
+				if let Err((err, lost)) = self.write_with_retries(&write).await {
+					eprintln!("Failed to write {} of {} events with {}: {}", lost, events, write.method(), err);
+					METRICS.record_db_error(write.method(), &err);
+					self.counters.lose("failed", lost);
+				}
+				self.counters.finish(&write, true);
+			}
+		}
+	}
+	
+	/// A timed out write may have been applied anyway. A user event repeated shows twice in its
+	/// profile until it ages out, so user events are repeated on any transient error. An
+	/// aggregate event repeated would stay counted twice, so only the ones surely not applied are.
+	/// Fails with the last error and the number of events given up on.
+	async fn write_with_retries(&self, write: &Write) -> Result<(), (DbError, usize)> {
+		let mut backoff = Backoff::new(&self.config);
+		match write {
+			Write::UserEvents { cookie, tags, .. } => loop {
+				match self.remote_db.add_user_events(cookie, tags.clone()).await {
+					Err(err) if err.is_transient() && backoff.wait().await => {},
+					result => return result.map_err(|err| (err, tags.len())),
+				}
+			},
+			Write::AggregateEvents { timestamp, tags } => {
+				let mut tags = tags.clone();
+				let mut lost: Option<(DbError, usize)> = None;
+				loop {
+					let events = tags.iter().map(|tag| (*timestamp, tag.clone())).collect();
+					let results = self.remote_db.add_aggregate_events(events).await;
+					let mut unapplied = vec![];
+					let mut unapplied_err = None;
+					for (tag, result) in tags.into_iter().zip(results) {
+						match result {
+							Ok(()) => {},
+							Err(err) if err.is_unapplied() => {
+								unapplied.push(tag);
+								unapplied_err = Some(err);
+							},
+							Err(err) => lost = Some((err, lost.map_or(0, |(_, count)| count) + 1)),
+						}
+					}
+					tags = unapplied;
+					match unapplied_err {
+						Some(_) if backoff.wait().await => {},
+						Some(err) => return Err((err, lost.map_or(0, |(_, count)| count) + tags.len())),
+						None => return lost.map_or(Ok(()), Err),
+					}
+				}
+			},
+		}
+	}
+}
+
+/// Delays between the tries of a write, doubling each time
+struct Backoff {
+	delay: Duration,
+	max_delay: Duration,
+	retries_left: u32,
+}
+
+impl Backoff {
+	fn new(config: &WriteQueueConfig) -> Self {
+		Self {
+			delay: config.initial_backoff(),
+			max_delay: config.max_backoff(),
+			retries_left: config.max_retries,
+		}
+	}
+	
+	/// Waits before the next try, false once the retries are used up
+	async fn wait(&mut self) -> bool {
+		if self.retries_left == 0 {
+			return false;
+		}
+		tokio::time::sleep(self.delay).await;
+		self.delay = (self.delay * 2).min(self.max_delay);
+		self.retries_left -= 1;
+		true
+	}
+}
+
+/// Write-behind pipeline of `CachedDB`, one bounded shard and one writer thread per shard.
+/// The writers block on the synchronous Aerospike client, so each one runs its own runtime.
+pub struct WriteQueue {
+	shards: Vec<Arc<WriteShard>>,
+	counters: Arc<WriteCounters>,
+	writers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl WriteQueue {
+	/// Fails when a writer thread or its runtime cannot be created, the writers started already
+	/// stop once the queue is dropped
+	pub fn new<T: SyncedDB>(config: &WriteQueueConfig, remote_db: Arc<T>) -> std::io::Result<Self> {
+		let queue = Self {
+			shards: (0..config.shards)
+				.map(|_| Arc::new(WriteShard::new(config.capacity, config.overflow)))
+				.collect(),
+			counters: Default::default(),
+			writers: Default::default(),
+		};
+		for (index, shard) in queue.shards.iter().enumerate() {
+			let writer = Writer {
+				shard: shard.clone(),
+				remote_db: remote_db.clone(),
+				counters: queue.counters.clone(),
+				config: config.clone(),
+			};
+			let runtime = tokio::runtime::Builder::new_current_thread()
+				.enable_all()
+				.build()?;
+			let handle = std::thread::Builder::new()
+				.name(format!("writer-{}", index))
+				.spawn(move || runtime.block_on(writer.run()))?;
+			queue.writers.lock().unwrap().push(handle);
+		}
+		Ok(queue)
+	}
+	
+	/// Returns the sequence numbers given to the user events of the write.
+	/// Fails with `DbError::Overloaded` when the shard is full and the policy is to reject.
+	pub async fn push(&self, mut write: Write) -> DbResult<Vec<u64>> {
+		let shard = &self.shards[write.shard(self.shards.len())];
+		// a write is unflushed before it is queued, so the writer cannot finish it earlier
+		self.counters.pending.fetch_add(write.events(), Ordering::Relaxed);
+		self.counters.unflushed.add(&mut write);
+		let seqs = match &write {
+			Write::UserEvents { seqs, .. } => seqs.clone(),
+			Write::AggregateEvents { .. } => vec![],
+		};
+		match shard.push(write).await {
+			Ok(None) => Ok(seqs),
+			Ok(Some(dropped)) => {
+				self.counters.finish(&dropped, false);
+				self.counters.lose("dropped", dropped.events());
+				Ok(seqs)
+			},
+			Err((err, rejected)) => {
+				self.counters.finish(&rejected, false);
+				Err(err)
+			},
+		}
+	}
+	
+	/// Tracks the user events of the cookie queued and not written yet until the watch is dropped
+	pub fn watch(&self, cookie: &Cookie) -> UnflushedWatch<'_> {
+		self.counters.unflushed.watch(cookie);
+		UnflushedWatch {
+			unflushed: &self.counters.unflushed,
+			cookie: cookie.clone(),
+		}
+	}
+	
+	/// Events queued or being written
+	pub fn pending(&self) -> usize {
+		self.counters.pending.load(Ordering::Relaxed)
+	}
+	
+	/// Events dropped on overflow or failed after every retry
+	pub fn lost(&self) -> u64 {
+		self.counters.lost.load(Ordering::Relaxed)
+	}
+	
+	/// Stops accepting writes and waits until every queued one is written or given up on
+	pub async fn flush(&self) {
+		for shard in &self.shards {
+			shard.close();
+		}
+		let writers = std::mem::take(&mut *self.writers.lock().unwrap());
+		let joined = tokio::task::spawn_blocking(move || {
+			for writer in writers {
+				if writer.join().is_err() {
+					eprintln!("Writer thread panicked");
+				}
+			}
+		}).await;
+		if let Err(err) = joined {
+			eprintln!("Failed to wait for the writers: {}", err);
+		}
+	}
+}
+
+/// Writers of a queue dropped without a flush still write what is queued and then stop
+impl Drop for WriteQueue {
+	fn drop(&mut self) {
+		for shard in &self.shards {
+			shard.close();
+		}
+	}
+}
@@ -1,24 +1,137 @@
+use std::collections::HashMap;
+
 use actix_web::{post, web, HttpResponse, Responder, Result};
 use actix_web::http::StatusCode;
+use serde::Serialize;
 use crate::api::ApiUserTag;
 
 use crate::AppState;
 use crate::data::{AGGREGATE_BUCKET, AggregateTagEvent, Compress, Cookie, UserAction, UserTagEvent};
-use crate::database::Database;
-use crate::endpoints::utils::IntoHttpError;
+use crate::database::{Compressor, Database};
+use crate::endpoints::utils::{compression_status, db_error_status, IntoDbHttpError, IntoHttpError};
+
+#[derive(Serialize)]
+struct BatchItemFailure {
+	index: usize,
+	status: u16,
+	error: String,
+	/// The profile got the tag, only the aggregates did not count it, so the item is not to be sent again
+	partial: bool,
+}
+
+impl BatchItemFailure {
+	fn new(index: usize, status: StatusCode, error: impl ToString) -> Self {
+		Self {
+			index,
+			status: status.as_u16(),
+			error: error.to_string(),
+			partial: false,
+		}
+	}
+	
+	fn partial(self) -> Self {
+		Self { partial: true, ..self }
+	}
+}
+
+#[derive(Serialize)]
+struct BatchResponse {
+	accepted: usize,
+	failed: Vec<BatchItemFailure>,
+}
+
+/// Items of a JSON array or of newline delimited JSON, each one parsed on its own
+fn parse_batch(body: &str) -> anyhow::Result<Vec<anyhow::Result<ApiUserTag>>> {
+	let parse = |item: serde_json::Result<ApiUserTag>| -> anyhow::Result<ApiUserTag> { Ok(item?) };
+	if body.trim_start().starts_with('[') {
+		let items: Vec<serde_json::Value> = serde_json::from_str(body)?;
+		Ok(items.into_iter().map(|item| parse(serde_json::from_value(item))).collect())
+	} else {
+		Ok(body.lines()
+			.filter(|line| !line.trim().is_empty())
+			.map(|line| parse(serde_json::from_str(line)))
+			.collect())
+	}
+}
 
 #[post("/user_tags")]
 pub async fn add_user_tags(data: web::Data<AppState>, req_body: String) -> Result<impl Responder> {
 	let user_tag: ApiUserTag = serde_json::from_str(&req_body)?;
 	
-	let tag = UserTagEvent::compress(&user_tag, data.database.as_ref()).await.map_error(StatusCode::BAD_REQUEST)?;
-	let aggregate_tag = AggregateTagEvent::compress(&user_tag, data.database.as_ref()).await.map_error(StatusCode::BAD_REQUEST)?;
-
+	let tag = UserTagEvent::compress(&user_tag, data.database.as_ref()).await.map_db_error()?;
+	let aggregate_tag = AggregateTagEvent::compress(&user_tag, data.database.as_ref()).await.map_db_error()?;
+	
 	let cookie = Cookie(user_tag.cookie);
 	let action = UserAction::try_from(user_tag.action.as_ref()).map_error(StatusCode::BAD_REQUEST)?;
 	
-	data.database.add_user_event(&cookie, tag, action).await;
-	data.database.add_aggregate_event(tag.time / AGGREGATE_BUCKET, aggregate_tag).await;
+	data.database.add_user_event(&cookie, tag, action).await.map_db_error()?;
+	data.database.add_aggregate_event(tag.time / AGGREGATE_BUCKET, aggregate_tag).await.map_db_error()?;
 	
 	Ok(HttpResponse::Ok().status(StatusCode::NO_CONTENT).finish())
-}
\ No newline at end of file
+}
+
+/// Accepts a JSON array or NDJSON of tags, see `add_batch`
+#[post("/user_tags/batch")]
+pub async fn add_user_tags_batch(data: web::Data<AppState>, req_body: String) -> Result<impl Responder> {
+	add_batch(data.database.as_ref(), &req_body).await
+}
+
+/// Dictionary values of all tags are compressed in one pass and the profile writes are grouped
+/// by cookie. The aggregates count only the tags their profile got, in one call. Failed tags
+/// are reported by their index.
+pub async fn add_batch<D: Database + Compressor<UserTagEvent>>(database: &D, req_body: &str) -> Result<HttpResponse> {
+	let items = parse_batch(req_body).map_error(StatusCode::BAD_REQUEST)?;
+	
+	let mut failed = vec![];
+	let mut indexes = vec![];
+	let mut actions = vec![];
+	let mut user_tags = vec![];
+	for (index, item) in items.into_iter().enumerate() {
+		match item.and_then(|tag| Ok((UserAction::try_from(tag.action.as_str())?, tag))) {
+			Ok((action, tag)) => {
+				indexes.push(index);
+				actions.push(action);
+				user_tags.push(tag);
+			},
+			Err(err) => failed.push(BatchItemFailure::new(index, StatusCode::BAD_REQUEST, err)),
+		}
+	}
+	
+	let tags = UserTagEvent::compress_batch(&user_tags, database).await;
+	
+	let mut by_cookie: HashMap<Cookie, Vec<(usize, UserTagEvent, UserAction)>> = HashMap::new();
+	for (((index, action), user_tag), tag) in indexes.into_iter().zip(actions).zip(user_tags).zip(tags) {
+		match tag {
+			Ok(tag) => by_cookie.entry(Cookie(user_tag.cookie)).or_default().push((index, tag, action)),
+			Err(err) => failed.push(BatchItemFailure::new(index, compression_status(&err), err)),
+		}
+	}
+	
+	let mut written = vec![];
+	let mut aggregate_events = vec![];
+	for (cookie, writes) in by_cookie {
+		let tags = writes.iter().map(|(_, tag, action)| (*tag, *action)).collect();
+		match database.add_user_events(&cookie, tags).await {
+			Ok(()) => for (index, tag, action) in writes {
+				written.push(index);
+				aggregate_events.push((tag.time / AGGREGATE_BUCKET, AggregateTagEvent::of_user_tag(&tag, action, &cookie)));
+			},
+			Err(err) => failed.extend(writes.iter().map(|(index, _, _)| BatchItemFailure::new(*index, db_error_status(&err), &err))),
+		}
+	}
+	let aggregate_results = database.add_aggregate_events(aggregate_events).await;
+	
+	let mut accepted = 0;
+	for (index, result) in written.into_iter().zip(aggregate_results) {
+		match result {
+			Ok(()) => accepted += 1,
+			Err(err) => failed.push(BatchItemFailure::new(index, db_error_status(&err), err).partial()),
+		}
+	}
+	
+	failed.sort_by_key(|failure| failure.index);
+	Ok(HttpResponse::Ok().json(BatchResponse {
+		accepted,
+		failed,
+	}))
+}
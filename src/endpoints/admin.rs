@@ -0,0 +1,101 @@
+use actix_web::{get, HttpResponse, Responder, Result, web};
+use actix_web::http::StatusCode;
+use serde::Serialize;
+
+use crate::AppState;
+use crate::config::Backend;
+use crate::data::{AGGREGATE_BUCKET, DictionaryFill};
+use crate::data::time::timestamp_to_str;
+use crate::database::{BackendDB, Database};
+use crate::endpoints::utils::{IntoDbHttpError, IntoHttpError};
+use crate::metrics::METRICS;
+
+#[derive(Serialize)]
+struct ReadyResponse {
+	dictionaries: Vec<DictionaryFill>,
+}
+
+/// First and last minute of the aggregates held in memory
+#[derive(Serialize)]
+struct MinuteRange {
+	first: String,
+	last: String,
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+	backend: Backend,
+	/// Sizes of the in-memory dictionaries
+	dictionaries: Option<Vec<DictionaryFill>>,
+	/// Cookies with a profile, only known to the local backend, the cache holds a subset
+	cookies: Option<usize>,
+	/// Only known to the local backend, the others keep the aggregates in Aerospike
+	aggregate_minutes: Option<MinuteRange>,
+	/// Events queued for a remote write by `CachedDB` and not written yet
+	pending_writes: usize,
+	/// Events `CachedDB` gave up writing
+	lost_writes: u64,
+}
+
+/// The process is up, says nothing about the database
+#[get("/health")]
+pub async fn health() -> impl Responder {
+	HttpResponse::Ok().body("OK")
+}
+
+/// Whether requests can be served, Aerospike has to be reachable and the dictionaries readable.
+/// The dictionaries are read from memory when the backend keeps them there, so a probe does not
+/// wait on Aerospike.
+#[get("/ready")]
+pub async fn ready(data: web::Data<AppState>) -> impl Responder {
+	if let Some(remote_db) = data.database.remote_db() {
+		if !remote_db.is_connected() {
+			return HttpResponse::ServiceUnavailable().body("Aerospike is not connected");
+		}
+	}
+	let fills = match data.database.local_db() {
+		Some(local_db) => local_db.get_dictionaries().await,
+		None => data.database.get_dictionaries().await,
+	};
+	match fills {
+		Ok(fills) => HttpResponse::Ok().json(ReadyResponse { dictionaries: fills }),
+		Err(err) => HttpResponse::ServiceUnavailable().body(format!("Dictionaries are not loaded: {}", err)),
+	}
+}
+
+#[get("/admin/status")]
+pub async fn status(data: web::Data<AppState>) -> Result<impl Responder> {
+	let mut response = StatusResponse {
+		backend: data.database.backend(),
+		dictionaries: None,
+		cookies: None,
+		aggregate_minutes: None,
+		pending_writes: data.database.pending_writes(),
+		lost_writes: data.database.lost_writes(),
+	};
+	if let Some(local_db) = data.database.local_db() {
+		response.dictionaries = Some(local_db.get_dictionaries().await.map_db_error()?);
+	}
+	if let BackendDB::Local(local_db) = data.database.as_ref() {
+		response.cookies = Some(local_db.cookie_count());
+		response.aggregate_minutes = local_db.aggregate_minutes().await.map(|minutes| MinuteRange {
+			first: timestamp_to_str(minutes.start * AGGREGATE_BUCKET),
+			last: timestamp_to_str((minutes.end - 1) * AGGREGATE_BUCKET),
+		});
+	}
+	Ok(HttpResponse::Ok().json(response))
+}
+
+/// Fill level of every compression dictionary, new values are rejected once one is full
+#[get("/admin/dictionaries")]
+pub async fn dictionaries(data: web::Data<AppState>) -> Result<impl Responder> {
+	Ok(HttpResponse::Ok().json(data.database.get_dictionaries().await.map_db_error()?))
+}
+
+/// Prometheus text exposition of the request, database and cache metrics
+#[get("/metrics")]
+pub async fn export_metrics(data: web::Data<AppState>) -> Result<impl Responder> {
+	METRICS.set_pending_writes(data.database.pending_writes());
+	let body = METRICS.encode().map_error(StatusCode::INTERNAL_SERVER_ERROR)?;
+	Ok(HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(body))
+}
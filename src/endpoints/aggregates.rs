@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::str::from_utf8;
 
 use actix_web::{HttpRequest, HttpResponse, post, Responder, Result, web};
@@ -10,7 +12,7 @@ use crate::api::*;
 use crate::AppState;
 use crate::data::*;
 use crate::database::Database;
-use crate::endpoints::utils::IntoHttpError;
+use crate::endpoints::utils::{IntoDbHttpError, IntoHttpError};
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct GetAggregateApiRequest {
@@ -19,6 +21,10 @@ pub struct GetAggregateApiRequest {
 	pub origin: Option<String>,
 	pub brand_id: Option<String>,
 	pub category_id: Option<String>,
+	/// One of `1m` (default), `5m`, `1h`, `1d`
+	pub bucket: Option<String>,
+	/// Comma separated dimensions, e.g. `brand_id,origin`
+	pub group_by: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -28,9 +34,29 @@ struct GetAggregateApiResponse {
 }
 
 #[derive(EnumString, IntoStaticStr)]
+#[strum(ascii_case_insensitive)]
 enum AggregateRequestType {
 	Count,
 	Sum,
+	Avg,
+	Min,
+	Max,
+	#[strum(serialize = "DISTINCT_USERS", to_string = "DistinctUsers")]
+	DistinctUsers,
+}
+
+impl AggregateRequestType {
+	/// Avg, min and max of an empty bucket are left empty
+	fn value(&self, bucket: &AggregateBucket) -> String {
+		match self {
+			AggregateRequestType::Count => bucket.count.to_string(),
+			AggregateRequestType::Sum => bucket.sum.to_string(),
+			AggregateRequestType::Avg => bucket.avg().map(|x| x.to_string()).unwrap_or_default(),
+			AggregateRequestType::Min => bucket.min.map(|x| x.to_string()).unwrap_or_default(),
+			AggregateRequestType::Max => bucket.max.map(|x| x.to_string()).unwrap_or_default(),
+			AggregateRequestType::DistinctUsers => bucket.distinct_users.estimate().to_string(),
+		}
+	}
 }
 
 #[post("/aggregates")]
@@ -39,7 +65,7 @@ pub async fn aggregates(
 	_req_body: String,
 	request: web::Query<GetAggregateApiRequest>,
 	aggregates_query_string: HttpRequest) -> Result<impl Responder> {
-
+	
 	let request_types: Vec<AggregateRequestType> =
 		DuplicateQS::parse(aggregates_query_string.query_string().as_bytes())
 			.values(b"aggregates")
@@ -47,58 +73,87 @@ pub async fn aggregates(
 			.map_error(StatusCode::BAD_REQUEST)?
 			.iter()
 			.map(|x| {
-				let bytes: &[u8] = x.as_ref().unwrap();
-				from_utf8(bytes).unwrap().try_into().unwrap()
+				let bytes: &[u8] = x.as_ref().map(|x| x.as_ref()).unwrap_or_default();
+				AggregateRequestType::try_from(from_utf8(bytes).unwrap_or_default())
 			})
-			.collect();
+			.collect::<Result<_, _>>()
+			.map_error(StatusCode::BAD_REQUEST)?;
 	
-	let get_aggregate_request = GetAggregateRequest::compress(&request, data.database.as_ref())
+	let mut get_aggregate_request = GetAggregateRequest::compress(&request, data.database.as_ref())
 		.await
-		.map_error(StatusCode::BAD_REQUEST)?;
+		.map_db_error()?;
+	get_aggregate_request.distinct_users = request_types.iter().any(|x| matches!(x, AggregateRequestType::DistinctUsers));
 	
-	let response = data.database.get_aggregate(&get_aggregate_request).await;
-	
-	
-	let mut columns = vec![String::from("1m_bucket"), String::from("action")];
+	let granularity = get_aggregate_request.granularity;
+	let group_by = &get_aggregate_request.group_by;
+	let filter = |dimension: AggregateDimension| match dimension {
+		AggregateDimension::Origin => request.origin.as_ref(),
+		AggregateDimension::BrandId => request.brand_id.as_ref(),
+		AggregateDimension::CategoryId => request.category_id.as_ref(),
+	};
+	// filtered dimensions first, then the grouped ones in the order they were asked for
+	let mut dimensions: Vec<AggregateDimension> = AggregateDimension::ALL.into_iter()
+		.filter(|dimension| filter(*dimension).is_some() && !group_by.contains(dimension))
+		.collect();
+	dimensions.extend(group_by.iter().copied());
 	
-	if get_aggregate_request.origin.is_some() {
-		columns.push(String::from("origin"));
-	}
-	if get_aggregate_request.brand_id.is_some() {
-		columns.push(String::from("brand_id"));
-	}
-	if get_aggregate_request.category_id.is_some() {
-		columns.push(String::from("category_id"));
+	let mut columns = vec![format!("{}_bucket", Into::<&'static str>::into(granularity)), String::from("action")];
+	for dimension in dimensions.iter() {
+		columns.push(Into::<&'static str>::into(dimension).to_string());
 	}
 	for aggregate_type in request_types.iter() {
 		columns.push(Into::<&'static str>::into(aggregate_type).to_string());
 	}
-
-	let rows = response.aggregates.iter()
-		.enumerate()
-		.map(|(i, value)| {
-			let mut row = Vec::new();
-			let minute_datetime = chrono::DateTime::from_timestamp_millis(get_aggregate_request.time_range.start + (i as i64) * 60 * 1000).unwrap();
-			row.push(minute_datetime.format("%Y-%m-%dT%H:%M:%S").to_string());
-			row.push(request.action.clone());
-			if let Some(value) = &request.origin {
-				row.push(value.clone());
-			}
-			if let Some(value) = &request.brand_id {
-				row.push(value.clone());
-			}
-			if let Some(value) = &request.category_id {
-				row.push(value.clone());
+	
+	let row = |i: usize, dimension_values: Vec<String>, value: &AggregateBucket| {
+		let mut row = Vec::new();
+		let bucket_datetime = chrono::DateTime::from_timestamp_millis((get_aggregate_request.time_range.start + i as i64) * granularity.millis()).unwrap();
+		row.push(bucket_datetime.format("%Y-%m-%dT%H:%M:%S").to_string());
+		row.push(request.action.clone());
+		row.extend(dimension_values);
+		for aggregate_type in request_types.iter() {
+			row.push(aggregate_type.value(value));
+		}
+		row
+	};
+	
+	let rows = if group_by.is_empty() {
+		let response = match get_aggregate_request.has_unknown_value {
+			true => GetAggregateResponse::empty(&get_aggregate_request),
+			false => data.database.get_aggregate(&get_aggregate_request).await.map_db_error()?,
+		};
+		let dimension_values: Vec<String> = dimensions.iter()
+			.filter_map(|dimension| filter(*dimension).cloned())
+			.collect();
+		response.aggregates.iter()
+			.enumerate()
+			.map(|(i, value)| row(i, dimension_values.clone(), value))
+			.collect()
+	} else {
+		let response = match get_aggregate_request.has_unknown_value {
+			true => GetGroupedAggregateResponse { groups: vec![] },
+			false => data.database.get_grouped_aggregate(&get_aggregate_request).await.map_db_error()?,
+		};
+		let mut names: HashMap<CubeKey, CubeKeyDecompressedData> = HashMap::new();
+		for group in response.groups.iter().flatten() {
+			if let Entry::Vacant(entry) = names.entry(group.key) {
+				entry.insert(group.key.decompress(data.database.as_ref(), ()).await.map_db_error()?);
 			}
-			for aggregate_type in request_types.iter() {
-				row.push(match aggregate_type {
-					AggregateRequestType::Count => value.count,
-					AggregateRequestType::Sum => value.sum,
-				}.to_string());
+		}
+		let mut rows = vec![];
+		for (i, groups) in response.groups.iter().enumerate() {
+			for group in groups {
+				let dimension_values = dimensions.iter()
+					.map(|dimension| match group_by.contains(dimension) {
+						true => names[&group.key].get(*dimension).cloned().unwrap_or_default(),
+						false => filter(*dimension).cloned().unwrap_or_default(),
+					})
+					.collect();
+				rows.push(row(i, dimension_values, &group.bucket));
 			}
-			row
-		})
-		.collect();
+		}
+		rows
+	};
 	
 	let response = GetAggregateApiResponse {
 		columns,
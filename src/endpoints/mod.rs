@@ -1,8 +1,12 @@
 mod add_user_tags;
 mod user_profiles;
 mod aggregates;
+mod top;
+mod admin;
 mod utils;
 
 pub use add_user_tags::*;
 pub use user_profiles::*;
-pub use aggregates::*;
\ No newline at end of file
+pub use aggregates::*;
+pub use top::*;
+pub use admin::*;
\ No newline at end of file
@@ -0,0 +1,55 @@
+use actix_web::{HttpResponse, post, Responder, Result, web};
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::api::*;
+use crate::AppState;
+use crate::data::*;
+use crate::database::Database;
+use crate::endpoints::utils::{IntoDbHttpError, IntoHttpError};
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct GetTopApiRequest {
+	pub time_range: String,
+	pub action: String,
+	/// One of `product_id`, `origin`, `brand_id`, `category_id`
+	pub dimension: String,
+	/// `count` (default) or `sum`
+	pub metric: Option<String>,
+	/// Number of values returned, 10 by default
+	pub n: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct GetTopApiResponse {
+	columns: Vec<String>,
+	rows: Vec<Vec<String>>,
+}
+
+#[post("/top")]
+pub async fn top(
+	data: web::Data<AppState>,
+	_req_body: String,
+	request: web::Query<GetTopApiRequest>) -> Result<impl Responder> {
+	
+	let get_top_request = GetTopRequest::try_from(&request.into_inner()).map_error(StatusCode::BAD_REQUEST)?;
+	let response = data.database.get_top(&get_top_request).await.map_db_error()?;
+	
+	let columns = vec![
+		Into::<&'static str>::into(get_top_request.dimension).to_string(),
+		Into::<&'static str>::into(get_top_request.metric).to_string(),
+	];
+	
+	let mut rows = vec![];
+	for (key, counter) in response.top.iter() {
+		let value = key.decompress(data.database.as_ref(), ()).await.map_db_error()?.unwrap_or_default();
+		rows.push(vec![value, counter.value(get_top_request.metric).to_string()]);
+	}
+	
+	let response = GetTopApiResponse {
+		columns,
+		rows,
+	};
+	
+	Ok(HttpResponse::Ok().json(response))
+}
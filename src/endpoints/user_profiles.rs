@@ -6,8 +6,8 @@ use crate::api::*;
 use crate::AppState;
 use crate::data::*;
 use crate::data::time::*;
-use crate::database::{Database, Decompressor};
-use crate::endpoints::utils::IntoHttpError;
+use crate::database::{Database, DbResult, Decompressor};
+use crate::endpoints::utils::{IntoDbHttpError, IntoHttpError};
 
 #[derive(Deserialize, Serialize)]
 struct UserProfileApiRequest {
@@ -22,18 +22,13 @@ struct UserProfileApiResponse {
 	buys: Vec<ApiUserTag>,
 }
 
-async fn filter_tags<T: Decompressor<UserTagEvent>>(decompressor: &T, mut user_tags: Vec<UserTagEvent>, request: &GetUserProfileRequest, action: UserAction) -> Vec<ApiUserTag> {
-	user_tags.sort();
-	let filtered_tags: Vec<UserTagEvent> = user_tags.into_iter()
-		.filter(|tag| -> bool {
-			request.time_range.within(tag.time)
-		})
-		.collect();
+/// Newest `request.limit` tags within the time range, `user_tags` has to be sorted by time
+async fn filter_tags<T: Decompressor<UserTagEvent>>(decompressor: &T, user_tags: &[UserTagEvent], request: &GetUserProfileRequest, action: UserAction) -> DbResult<Vec<ApiUserTag>> {
 	let mut tags = vec![];
-	for tag in filtered_tags {
-		tags.push(tag.decompress(decompressor, (request.cookie.clone(), action)).await);
+	for tag in tags_within(user_tags, &request.time_range).iter().rev().take(request.limit) {
+		tags.push(tag.decompress(decompressor, (request.cookie.clone(), action)).await?);
 	}
-	tags.into_iter().rev().take(request.limit).collect()
+	Ok(tags)
 }
 
 #[post("/user_profiles/{cookie}")]
@@ -43,16 +38,16 @@ pub async fn user_profiles(data: web::Data<AppState>, _req_body: String, cookie:
 		time_range: TimeRange::new(info.time_range.as_str()).map_error(StatusCode::BAD_REQUEST)?,
 		limit: match info.limit {
 			Some(limit) => limit as usize,
-			None => MAX_TAGS,
+			None => data.max_tags,
 		},
 	};
 	
 	// get the user tags
-	let user_profile = data.database.get_user_profile(&request.cookie).await;
+	let user_profile = data.database.get_user_profile(&request.cookie).await.map_db_error()?;
 	let response = 	UserProfileApiResponse {
 		cookie: request.cookie.0.clone(),
-		views: filter_tags(data.database.as_ref(), user_profile.view_events, &request, UserAction::VIEW).await,
-		buys: filter_tags(data.database.as_ref(), user_profile.buy_events, &request, UserAction::BUY).await,
+		views: filter_tags(data.database.as_ref(), &user_profile.view_events, &request, UserAction::VIEW).await.map_db_error()?,
+		buys: filter_tags(data.database.as_ref(), &user_profile.buy_events, &request, UserAction::BUY).await.map_db_error()?,
 	};
 
 	Ok(HttpResponse::Ok().json(response))
@@ -3,6 +3,8 @@ use actix_web::error::InternalError;
 use actix_web::http::StatusCode;
 use actix_web::{Error, Result};
 
+use crate::database::{DbError, DbResult};
+
 pub trait IntoHttpError<T, E: Display + Debug + 'static> {
 	fn map_error(self, status_code: StatusCode) -> Result<T>;
 }
@@ -14,4 +16,45 @@ impl<T, E: Display + Debug + 'static> IntoHttpError<T, E> for anyhow::Result<T,
 			Err(err) => Err(Error::from(InternalError::new(err, status_code))),
 		}
 	}
-}
\ No newline at end of file
+}
+
+pub trait IntoDbHttpError<T> {
+	fn map_db_error(self) -> Result<T>;
+}
+
+/// Unreachable or overloaded backend is reported as 503, a full dictionary or a timestamp out of
+/// range as a bad request and anything else the database failed at as 500
+pub fn db_error_status(err: &DbError) -> StatusCode {
+	match err {
+		DbError::Timeout(_) | DbError::Unavailable(_) | DbError::Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
+		DbError::NotFound(_) | DbError::Protocol(_) | DbError::Storage(_) | DbError::Partial(_) => StatusCode::INTERNAL_SERVER_ERROR,
+		DbError::Overflow(_) | DbError::OutOfRange(_) => StatusCode::BAD_REQUEST,
+	}
+}
+
+/// Compression fails either in the database or on a malformed value, which is a bad request
+pub fn compression_status(err: &anyhow::Error) -> StatusCode {
+	err.downcast_ref::<DbError>()
+		.map(db_error_status)
+		.unwrap_or(StatusCode::BAD_REQUEST)
+}
+
+impl<T> IntoDbHttpError<T> for DbResult<T> {
+	fn map_db_error(self) -> Result<T> {
+		let status_code = match &self {
+			Err(err) => db_error_status(err),
+			Ok(_) => StatusCode::OK,
+		};
+		self.map_error(status_code)
+	}
+}
+
+impl<T> IntoDbHttpError<T> for anyhow::Result<T> {
+	fn map_db_error(self) -> Result<T> {
+		let status_code = match &self {
+			Err(err) => compression_status(err),
+			Ok(_) => StatusCode::OK,
+		};
+		self.map_error(status_code)
+	}
+}
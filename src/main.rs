@@ -1,38 +1,139 @@
 use std::sync::Arc;
+use std::time::Duration;
 
-use actix_web::{App, HttpServer, web};
+use actix_web::{App, HttpServer, middleware, web};
+use clap::Parser;
 
 use endpoints::*;
 
-use crate::database::{AerospikeDB, CachedDB, LocalDB};
+use crate::config::{Args, Backend, Config, FsyncPolicy};
+use crate::database::{AerospikeDB, BackendDB, CachedDB, LocalDB};
+use crate::shutdown::ShutdownCoordinator;
 
 mod endpoints;
 mod database;
 mod data;
+#[cfg(test)]
 mod tests;
 pub mod api;
 mod compression;
+mod config;
+mod metrics;
+mod shutdown;
+
+const MAPPINGS_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct AppState {
-	pub database: Arc<CachedDB<LocalDB, AerospikeDB>>,
-	// pub database: Arc<LocalDB>,
+	pub database: Arc<BackendDB>,
+	/// Default limit of `/user_profiles`
+	pub max_tags: usize,
+}
+
+/// Prints the reason and exits, used for failures the server cannot start with
+fn fail(message: String) -> ! {
+	eprintln!("{}", message);
+	std::process::exit(2)
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-	// let database = Arc::new(LocalDB::new());
-	let database = Arc::new(CachedDB::new(LocalDB::new(), AerospikeDB::new()));
+	let config = Config::load(Args::parse())
+		.unwrap_or_else(|err| fail(format!("Invalid configuration: {:#}", err)));
 	
-	HttpServer::new(move || {
+	let local_db = || LocalDB::with_aggregate_retention(config.retention.into())
+		.with_max_tags(config.max_tags);
+	// the profiles are restored for the local backend only, the dictionaries from one file only
+	let restored_db = || local_db().with_snapshot(config.snapshot.path.clone());
+	let remote_db = || AerospikeDB::new(&config.aerospike, config.max_tags)
+		.unwrap_or_else(|err| fail(format!("Failed to connect to Aerospike at {}: {}", config.aerospike.hosts, err)));
+	let database = Arc::new(match config.backend {
+		// the remote database of the other backends is durable on its own
+		Backend::Local if config.wal.enabled => BackendDB::Local(restored_db().with_wal(&config.wal)
+			.unwrap_or_else(|err| fail(format!("Failed to open the write-ahead log in {}: {:#}", config.wal.dir.display(), err)))),
+		Backend::Local => BackendDB::Local(restored_db()),
+		Backend::CachedAerospike => BackendDB::CachedAerospike(Box::new(CachedDB::new(local_db().with_mappings_snapshot(config.mappings_snapshot.clone()), remote_db(), &config.write_queue)
+			.unwrap_or_else(|err| fail(format!("Failed to start the remote writers: {}", err)))
+			.with_max_tags(config.max_tags)
+			.with_local_profiles(&config.cache))),
+		Backend::Aerospike => BackendDB::Aerospike(remote_db()),
+	});
+	
+	let snapshot_task = database.local_db().is_some().then(|| {
+		let snapshot_database = database.clone();
+		let snapshot_interval = config.snapshot.interval();
+		let wal_sync = (config.wal.fsync == FsyncPolicy::Periodic).then(|| config.wal.fsync_interval());
+		tokio::spawn(async move {
+			let Some(local_db) = snapshot_database.local_db() else {
+				return;
+			};
+			let mut mappings_interval = tokio::time::interval(MAPPINGS_SNAPSHOT_INTERVAL);
+			let mut snapshot_interval = tokio::time::interval(snapshot_interval);
+			// records appended before a quiet period are synced too, never ticks without the periodic policy
+			let mut wal_sync_interval = tokio::time::interval(wal_sync.unwrap_or(MAPPINGS_SNAPSHOT_INTERVAL));
+			// the first ticks complete immediately
+			mappings_interval.tick().await;
+			snapshot_interval.tick().await;
+			wal_sync_interval.tick().await;
+			loop {
+				tokio::select! {
+					_ = mappings_interval.tick() => if let Err(err) = local_db.save_mappings().await {
+						eprintln!("Failed to save mappings snapshot: {}", err);
+					},
+					_ = snapshot_interval.tick() => if let Err(err) = local_db.save_snapshot().await {
+						eprintln!("Failed to save local database snapshot: {}", err);
+					},
+					_ = wal_sync_interval.tick(), if wal_sync.is_some() => if let Err(err) = local_db.sync_wal().await {
+						eprintln!("Failed to sync the write-ahead log: {}", err);
+					},
+				}
+			}
+		})
+	});
+	
+	let server_database = database.clone();
+	let max_tags = config.max_tags;
+	let mut server = HttpServer::new(move || {
 		App::new()
 			.app_data(web::Data::new(AppState { 
-				database: database.clone()
+				database: server_database.clone(),
+				max_tags,
 			}))
 			.service(add_user_tags)
+			.service(add_user_tags_batch)
 			.service(user_profiles)
 			.service(aggregates)
-	}).bind(("10.112.103.101", 8083))
+			.service(top)
+			.service(dictionaries)
+			.service(health)
+			.service(ready)
+			.service(status)
+			.service(export_metrics)
+			.wrap(middleware::from_fn(metrics::track_requests))
+	});
+	if let Some(workers) = config.server.workers {
+		server = server.workers(workers);
+	}
+	// on SIGTERM or SIGINT the server stops accepting and gives the requests in flight this long
+	server = server.shutdown_timeout(config.shutdown.grace_period_secs);
+	server.bind(config.server.bind_address)
 		.expect("Creation of server failed")
 		.run()
-		.await
+		.await?;
+	
+	// the final snapshot must not race a periodic one
+	if let Some(snapshot_task) = snapshot_task {
+		snapshot_task.abort();
+	}
+	let report = ShutdownCoordinator::new(database, config.shutdown.clone()).run().await;
+	if config.shutdown.report_lost_events {
+		let drained = if report.drained { "drained" } else { "timed out" };
+		eprintln!("Shutdown {}, {} events lost", drained, report.lost_events);
+		if report.lost_events > 0 {
+			std::process::exit(3);
+		}
+	}
+	if !report.persisted {
+		return Err(std::io::Error::other("Failed to persist the local database"));
+	}
+	Ok(())
 }
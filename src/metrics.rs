@@ -0,0 +1,150 @@
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+use crate::compression::PartialFields;
+use crate::database::{DbError, DbResult};
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Which way a `CachedDB` dictionary lookup goes
+#[derive(Clone, Copy)]
+pub enum Lookup {
+	/// Value to id, the fields still `Same` after the local pass are misses
+	Compress,
+	/// Id to value, the fields still `Changed` after the local pass are misses
+	Decompress,
+}
+
+impl Lookup {
+	fn label(&self) -> &'static str {
+		match self {
+			Lookup::Compress => "compress",
+			Lookup::Decompress => "decompress",
+		}
+	}
+}
+
+pub struct Metrics {
+	registry: Registry,
+	http_requests: IntCounterVec,
+	http_duration: HistogramVec,
+	db_duration: HistogramVec,
+	db_errors: IntCounterVec,
+	dictionary_lookups: IntCounterVec,
+	pending_writes: IntGauge,
+	lost_events: IntCounterVec,
+}
+
+impl Metrics {
+	fn new() -> Self {
+		let http_requests = IntCounterVec::new(
+			Opts::new("http_requests_total", "Requests served, by route pattern, method and status code"),
+			&["endpoint", "method", "status"],
+		).unwrap();
+		let http_duration = HistogramVec::new(
+			HistogramOpts::new("http_request_duration_seconds", "Time spent serving a request, by route pattern"),
+			&["endpoint"],
+		).unwrap();
+		let db_duration = HistogramVec::new(
+			HistogramOpts::new("db_call_duration_seconds", "Time spent in a `Database` call, by method"),
+			&["method"],
+		).unwrap();
+		let db_errors = IntCounterVec::new(
+			Opts::new("db_call_errors_total", "Failed `Database` calls, background writes included, by method and error kind"),
+			&["method", "kind"],
+		).unwrap();
+		let dictionary_lookups = IntCounterVec::new(
+			Opts::new("dictionary_cache_lookups_total", "Dictionary values looked up in the `CachedDB` in-memory cache, by direction and result"),
+			&["direction", "result"],
+		).unwrap();
+		let pending_writes = IntGauge::new("pending_background_writes", "Events queued for a remote write by `CachedDB` and not written yet").unwrap();
+		let lost_events = IntCounterVec::new(
+			Opts::new("lost_background_events_total", "Events `CachedDB` gave up writing, dropped on a full queue or failed after every retry"),
+			&["reason"],
+		).unwrap();
+		
+		let registry = Registry::new();
+		registry.register(Box::new(http_requests.clone())).unwrap();
+		registry.register(Box::new(http_duration.clone())).unwrap();
+		registry.register(Box::new(db_duration.clone())).unwrap();
+		registry.register(Box::new(db_errors.clone())).unwrap();
+		registry.register(Box::new(dictionary_lookups.clone())).unwrap();
+		registry.register(Box::new(pending_writes.clone())).unwrap();
+		registry.register(Box::new(lost_events.clone())).unwrap();
+		Self {
+			registry,
+			http_requests,
+			http_duration,
+			db_duration,
+			db_errors,
+			dictionary_lookups,
+			pending_writes,
+			lost_events,
+		}
+	}
+	
+	pub fn set_pending_writes(&self, pending_writes: usize) {
+		self.pending_writes.set(pending_writes as i64);
+	}
+	
+	pub fn record_db_error(&self, method: &str, err: &DbError) {
+		self.db_errors.with_label_values(&[method, err.kind()]).inc();
+	}
+	
+	pub fn record_lost_events(&self, reason: &str, events: usize) {
+		self.lost_events.with_label_values(&[reason]).inc_by(events as u64);
+	}
+	
+	/// Counts the dictionary fields of a partial left by the local pass of `CachedDB`
+	pub fn record_lookups(&self, lookup: Lookup, partial: &impl PartialFields) {
+		let fields = partial.same_fields();
+		let misses = fields.iter()
+			.filter(|same| match lookup {
+				Lookup::Compress => **same,
+				Lookup::Decompress => !**same,
+			})
+			.count();
+		self.dictionary_lookups.with_label_values(&[lookup.label(), "hit"]).inc_by((fields.len() - misses) as u64);
+		self.dictionary_lookups.with_label_values(&[lookup.label(), "miss"]).inc_by(misses as u64);
+	}
+	
+	/// Prometheus text exposition of every metric
+	pub fn encode(&self) -> anyhow::Result<String> {
+		let mut buffer = vec![];
+		TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+		Ok(String::from_utf8(buffer)?)
+	}
+}
+
+/// Runs a `Database` call, recording its latency and its error kind under `method`
+pub async fn observe_db_call<T>(method: &'static str, call: impl Future<Output = DbResult<T>>) -> DbResult<T> {
+	let start = Instant::now();
+	let result = call.await;
+	METRICS.db_duration.with_label_values(&[method]).observe(start.elapsed().as_secs_f64());
+	if let Err(err) = &result {
+		METRICS.record_db_error(method, err);
+	}
+	result
+}
+
+/// Middleware counting every request and its latency under the route pattern it matched,
+/// so `/user_profiles/{cookie}` is a single series
+pub async fn track_requests(request: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+	let start = Instant::now();
+	let method = request.method().to_string();
+	let endpoint = request.match_pattern().unwrap_or_else(|| String::from("unmatched"));
+	let response = next.call(request).await;
+	let status = match &response {
+		Ok(response) => response.status(),
+		Err(err) => err.as_response_error().status_code(),
+	};
+	METRICS.http_requests.with_label_values(&[&endpoint, &method, status.as_str()]).inc();
+	METRICS.http_duration.with_label_values(&[&endpoint]).observe(start.elapsed().as_secs_f64());
+	response
+}
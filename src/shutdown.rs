@@ -0,0 +1,69 @@
+use std::sync::Arc;
+
+use crate::config::ShutdownConfig;
+use crate::database::BackendDB;
+
+/// What the shutdown managed to do
+pub struct ShutdownReport {
+	/// Every queued write finished before the deadline
+	pub drained: bool,
+	/// Events dropped or failed while running, plus the ones still queued at the deadline
+	pub lost_events: u64,
+	/// Every `LocalDB` snapshot was saved
+	pub persisted: bool,
+}
+
+/// Runs the steps after the server stopped accepting requests and finished the ones in flight:
+/// drains the write-behind queue until the deadline, then persists the `LocalDB` snapshots
+pub struct ShutdownCoordinator {
+	database: Arc<BackendDB>,
+	config: ShutdownConfig,
+}
+
+impl ShutdownCoordinator {
+	pub fn new(database: Arc<BackendDB>, config: ShutdownConfig) -> Self {
+		Self {
+			database,
+			config,
+		}
+	}
+	
+	pub async fn run(self) -> ShutdownReport {
+		let drained = self.drain().await;
+		let mut lost_events = self.database.lost_writes();
+		if !drained {
+			// the writers are abandoned with the process
+			lost_events += self.database.pending_writes() as u64;
+		}
+		let persisted = self.persist().await;
+		ShutdownReport {
+			drained,
+			lost_events,
+			persisted,
+		}
+	}
+	
+	async fn drain(&self) -> bool {
+		tokio::time::timeout(self.config.drain_timeout(), self.database.flush()).await.is_ok()
+	}
+	
+	async fn persist(&self) -> bool {
+		let Some(local_db) = self.database.local_db() else {
+			return true;
+		};
+		let mut persisted = true;
+		if let Err(err) = local_db.sync_wal().await {
+			eprintln!("Failed to sync the write-ahead log: {}", err);
+			persisted = false;
+		}
+		if let Err(err) = local_db.save_mappings().await {
+			eprintln!("Failed to save mappings snapshot: {}", err);
+			persisted = false;
+		}
+		if let Err(err) = local_db.save_snapshot().await {
+			eprintln!("Failed to save local database snapshot: {}", err);
+			persisted = false;
+		}
+		persisted
+	}
+}
@@ -1,64 +1,1398 @@
-#[cfg(test)]
-mod tests {
-	use std::env;
-	use std::time::Instant;
-	use aerospike::{as_bin, as_key, as_val, Bins, Client, ClientPolicy, MapPolicy, MapReturnType, ReadPolicy, WritePolicy};
-	use aerospike::operations;
-	use aerospike::operations::maps;
-	
-	#[test]
-	fn test_aerospike() {
-		let cpolicy = ClientPolicy::default();
-		let hosts = env::var("AEROSPIKE_HOSTS")
-			.unwrap_or(String::from("127.0.0.1:3000"));
-		let client = Client::new(&cpolicy, &hosts)
-			.expect("Failed to connect to cluster");
-		let now = Instant::now();
-		let rpolicy = ReadPolicy::default();
-		let wpolicy = WritePolicy::default();
-		let key = as_key!("test", "test", "test");
-		
-		let bins = [
-			as_bin!("int", 999),
-			as_bin!("str", "Hello, World!"),
-		];
-		client.put(&wpolicy, &key, &bins).unwrap();
-		let rec = client.get(&rpolicy, &key, Bins::All);
-		println!("Record: {}", rec.unwrap());
-		
-		client.touch(&wpolicy, &key).unwrap();
-		let rec = client.get(&rpolicy, &key, Bins::All);
-		println!("Record: {}", rec.unwrap());
-		
-		let rec = client.get(&rpolicy, &key, Bins::None);
-		println!("Record Header: {}", rec.unwrap());
-		
-		let exists = client.exists(&wpolicy, &key).unwrap();
-		println!("exists: {}", exists);
-		
-		let bin = as_bin!("int", "123");
-		let ops = &vec![operations::put(&bin), operations::get()];
-		let op_rec = client.operate(&wpolicy, &key, ops);
-		println!("operate: {}", op_rec.unwrap());
-		
-		let existed = client.delete(&wpolicy, &key).unwrap();
-		println!("existed (should be true): {}", existed);
-		
-		let existed = client.delete(&wpolicy, &key).unwrap();
-		println!("existed (should be false): {}", existed);
-		
-		let mpolicy = MapPolicy::default();
-		let bin_name = "bin";
-		let (k, v) = (as_val!("c"), as_val!(3));
-		let op = maps::put(&mpolicy, bin_name, &k, &v);
-		let _rec = client.operate(&wpolicy, &key, &[op]).unwrap();
-		
-		let key_c: aerospike::Value = as_val!("c");
-		let val = maps::get_by_key(bin_name, &key_c, MapReturnType::Value);
-		let rec = client.operate(&wpolicy, &key, &[val]).unwrap();
-		println!("operate: {}", rec);
-		
-		
-		println!("total time: {:?}", now.elapsed());
-	}
-}
\ No newline at end of file
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use actix_web::{App, web};
+use aerospike::{as_bin, as_key, as_val, Bins, Client, ClientPolicy, MapPolicy, MapReturnType, ReadPolicy, Value, WritePolicy};
+use aerospike::operations;
+use aerospike::operations::{maps, Operation, OperationBin, OperationData};
+use aerospike::operations::cdt::CdtArgument;
+use aerospike::operations::lists::{CdtListOpType, ListOrderType};
+
+use crate::config::{Args, Backend, CacheConfig, Config, FsyncPolicy, OverflowPolicy, ShutdownConfig, WalConfig, WriteQueueConfig};
+use crate::api::{ApiUserTag, GetAggregateRequest, GetAggregateResponse, GetGroupedAggregateResponse, GetTopRequest, GetTopResponse, MAX_TAGS};
+use crate::data::{tags_within, AGGREGATE_BUCKET, Compress, Decompress, ProductInfo, AggregateDimension, Cookie, AggregateTagEvent, AggregateTier, BinaryCodec, Device, Dictionary, IdSpaceOverflow, Partial, PartialAggregateTagEventCompressedData, PartialCubeKeyDecompressedData, PartialFields, PartialTopKeyDecompressedData, PartialUserTagEventCompressedData, TopKey, UserTagEvent, UserTagEventCompressedData, cookie_fingerprint, CubeKey, DictionaryFill, DistinctSketch, Granularity, TopDimension, TopMetric, UserAction, UserProfile};
+use crate::data::time::TimeRange;
+use crate::metrics::{METRICS, observe_db_call};
+use crate::shutdown::ShutdownCoordinator;
+use crate::AppState;
+use crate::endpoints::{add_batch, add_user_tags, add_user_tags_batch, aggregates, health, ready, status};
+use crate::database::{AggregateRetention, CachedDB, Compressor, Database, BackendDB, DbError, DbResult, decode_profile_entry, Decompressor, LocalDB, MappingsSnapshot, profile_append_operations, profile_entry, retrieve_value_from_mapping_result, Snapshot, Synced, SyncedDB, TimeRing, Wal, WalRecord, Write, WriteQueue, WriteShard};
+
+fn aggregate_tag(origin_id: u16, brand_id: u16, price: i32, action: UserAction) -> AggregateTagEvent {
+	AggregateTagEvent {
+		product_id: 0,
+		origin_id,
+		brand_id,
+		category_id: 0,
+		timestamp: 0,
+		price,
+		action,
+		cookie_fingerprint: price as u64,
+	}
+}
+
+fn aggregate_request(start: i64, end: i64, action: UserAction, origin: Option<u16>, brand_id: Option<u16>) -> GetAggregateRequest {
+	GetAggregateRequest {
+		time_range: TimeRange { start, end },
+		granularity: Granularity::Minute,
+		action,
+		origin,
+		brand_id,
+		category_id: None,
+		group_by: vec![],
+		has_unknown_value: false,
+		distinct_users: true,
+	}
+}
+
+fn user_tag(time: i64) -> UserTagEvent {
+	UserTagEvent {
+		product_id: time as u64,
+		brand_id: 1,
+		category_id: 2,
+		country_id: 3,
+		origin_id: 4,
+		time,
+		price: 100,
+		device: Device::PC,
+	}
+}
+
+/// In-process stand-in for an Aerospike record of profile lists. Like the server, it applies
+/// all operations of a single `operate` call under the record lock. Knows only the list
+/// operations `profile_append_operations` sends.
+#[derive(Default)]
+struct ProfileRecordStandIn {
+	bins: std::sync::Mutex<HashMap<String, Vec<Value>>>,
+}
+
+impl ProfileRecordStandIn {
+	/// Ordering of `[-time, blob]` entries in a list with `ListOrderType::Ordered`
+	fn order(value: &Value) -> (i64, Vec<u8>) {
+		match value {
+			Value::List(entry) => match (&entry[0], &entry[1]) {
+				(Value::Int(time), Value::Blob(bytes)) => (*time, bytes.clone()),
+				other => panic!("Unexpected profile entry {:?}", other),
+			},
+			other => panic!("Unexpected profile entry {:?}", other),
+		}
+	}
+	
+	fn operate(&self, operations: &[Operation]) {
+		let mut bins = self.bins.lock().unwrap();
+		for operation in operations {
+			let (OperationBin::Name(bin), OperationData::CdtListOp(cdt)) = (&operation.bin, &operation.data) else {
+				panic!("Unexpected operation on a profile record");
+			};
+			let list = bins.entry(bin.to_string()).or_default();
+			match &cdt.args[..] {
+				[CdtArgument::List(entries), CdtArgument::Byte(order), ..] if cdt.op == CdtListOpType::AppendItems as u8 => {
+					assert_eq!(*order, ListOrderType::Ordered as u8);
+					for entry in entries.iter() {
+						let position = list.partition_point(|x| Self::order(x) <= Self::order(entry));
+						list.insert(position, entry.clone());
+					}
+				},
+				[CdtArgument::Byte(_), CdtArgument::Int(index)] if cdt.op == CdtListOpType::RemoveByIndexRange as u8 => {
+					list.truncate(*index as usize);
+				},
+				_ => panic!("Unexpected list operation {}", cdt.op),
+			}
+		}
+	}
+}
+
+fn api_user_tag(cookie: &str, country: &str, action: &str) -> ApiUserTag {
+	ApiUserTag {
+		product_info: ProductInfo {
+			product_id: String::from("product"),
+			brand_id: String::from("brand"),
+			category_id: String::from("category"),
+			price: 10,
+		},
+		time: String::from("2022-03-22T12:15:00.000Z"),
+		cookie: String::from(cookie),
+		country: String::from(country),
+		device: String::from("PC"),
+		action: String::from(action),
+		origin: String::from("origin"),
+	}
+}
+
+#[test]
+fn test_aerospike() {
+	let cpolicy = ClientPolicy::default();
+	let hosts = env::var("AEROSPIKE_HOSTS")
+		.unwrap_or(String::from("127.0.0.1:3000"));
+	let client = Client::new(&cpolicy, &hosts)
+		.expect("Failed to connect to cluster");
+	let now = Instant::now();
+	let rpolicy = ReadPolicy::default();
+	let wpolicy = WritePolicy::default();
+	let key = as_key!("test", "test", "test");
+	
+	let bins = [
+		as_bin!("int", 999),
+		as_bin!("str", "Hello, World!"),
+	];
+	client.put(&wpolicy, &key, &bins).unwrap();
+	let rec = client.get(&rpolicy, &key, Bins::All);
+	println!("Record: {}", rec.unwrap());
+	
+	client.touch(&wpolicy, &key).unwrap();
+	let rec = client.get(&rpolicy, &key, Bins::All);
+	println!("Record: {}", rec.unwrap());
+	
+	let rec = client.get(&rpolicy, &key, Bins::None);
+	println!("Record Header: {}", rec.unwrap());
+	
+	let exists = client.exists(&wpolicy, &key).unwrap();
+	println!("exists: {}", exists);
+	
+	let bin = as_bin!("int", "123");
+	let ops = &[operations::put(&bin), operations::get()];
+	let op_rec = client.operate(&wpolicy, &key, ops);
+	println!("operate: {}", op_rec.unwrap());
+	
+	let existed = client.delete(&wpolicy, &key).unwrap();
+	println!("existed (should be true): {}", existed);
+	
+	let existed = client.delete(&wpolicy, &key).unwrap();
+	println!("existed (should be false): {}", existed);
+	
+	let mpolicy = MapPolicy::default();
+	let bin_name = "bin";
+	let (k, v) = (as_val!("c"), as_val!(3));
+	let op = maps::put(&mpolicy, bin_name, &k, &v);
+	let _rec = client.operate(&wpolicy, &key, &[op]).unwrap();
+	
+	let key_c: aerospike::Value = as_val!("c");
+	let val = maps::get_by_key(bin_name, &key_c, MapReturnType::Value);
+	let rec = client.operate(&wpolicy, &key, &[val]).unwrap();
+	println!("operate: {}", rec);
+	
+	
+	println!("total time: {:?}", now.elapsed());
+}
+
+#[tokio::test]
+async fn test_local_aggregates() {
+	let db = LocalDB::new();
+	db.add_aggregate_event(100, aggregate_tag(1, 1, 10, UserAction::BUY)).await.unwrap();
+	db.add_aggregate_event(100, aggregate_tag(2, 1, 20, UserAction::BUY)).await.unwrap();
+	db.add_aggregate_event(100, aggregate_tag(1, 2, 40, UserAction::VIEW)).await.unwrap();
+	db.add_aggregate_event(102, aggregate_tag(1, 2, 80, UserAction::BUY)).await.unwrap();
+	
+	let all_buys = db.get_aggregate(&aggregate_request(100, 103, UserAction::BUY, None, None)).await.unwrap();
+	let counts: Vec<(u64, u64)> = all_buys.aggregates.iter().map(|x| (x.count, x.sum)).collect();
+	assert_eq!(counts, vec![(2, 30), (0, 0), (1, 80)]);
+	
+	let origin_buys = db.get_aggregate(&aggregate_request(100, 103, UserAction::BUY, Some(1), None)).await.unwrap();
+	let counts: Vec<(u64, u64)> = origin_buys.aggregates.iter().map(|x| (x.count, x.sum)).collect();
+	assert_eq!(counts, vec![(1, 10), (0, 0), (1, 80)]);
+	
+	let brand_views = db.get_aggregate(&aggregate_request(100, 101, UserAction::VIEW, Some(1), Some(2))).await.unwrap();
+	assert_eq!(brand_views.aggregates[0].count, 1);
+	assert_eq!(brand_views.aggregates[0].sum, 40);
+	
+	let first_minute = &all_buys.aggregates[0];
+	assert_eq!(first_minute.min, Some(10));
+	assert_eq!(first_minute.max, Some(20));
+	assert_eq!(first_minute.avg(), Some(15.0));
+	assert_eq!(first_minute.distinct_users.estimate(), 2);
+}
+
+#[test]
+fn test_distinct_sketch() {
+	let mut sketch = DistinctSketch::default();
+	for i in 0..10000 {
+		sketch.insert(cookie_fingerprint(format!("cookie-{}", i % 5000).as_str()));
+	}
+	let estimate = sketch.estimate() as f64;
+	assert!((estimate - 5000.0).abs() / 5000.0 < 0.3, "estimate {} too far from 5000", estimate);
+	
+	// a smaller sketch, as kept by the coarse Aerospike tiers, caps the merge
+	let small = DistinctSketch::with_capacity(sketch.fingerprints().to_vec(), 32);
+	let mut merged = DistinctSketch::default();
+	merged.merge(&small);
+	merged.merge(&sketch);
+	assert_eq!(merged.fingerprints(), &sketch.fingerprints()[..32]);
+	let estimate = merged.estimate() as f64;
+	assert!((estimate - 5000.0).abs() / 5000.0 < 0.6, "estimate {} too far from 5000", estimate);
+	assert_eq!(DistinctSketch::with_capacity(vec![3, 1, 2], 32).estimate(), 3);
+}
+
+#[test]
+fn test_time_ring() {
+	let mut ring: TimeRing<u32> = TimeRing::new(5);
+	assert!(ring.range().is_none());
+	*ring.get_or_insert(10, 0).unwrap() += 1;
+	*ring.get_or_insert(8, 0).unwrap() += 1;
+	*ring.get_or_insert(12, 0).unwrap() += 1;
+	assert_eq!(ring.range().map(|range| (range.start, range.end)), Some((8, 13)));
+	assert_eq!(ring.get(8), Some(&1));
+	assert_eq!(ring.get(9), Some(&0));
+	assert_eq!(ring.get(7), None);
+	
+	// moving forward evicts minutes past the retention window
+	*ring.get_or_insert(13, 0).unwrap() += 1;
+	assert_eq!(ring.get(8), None);
+	assert_eq!(ring.get(10), Some(&1));
+	assert!(ring.get_or_insert(8, 0).is_none());
+	
+	// a jump far ahead drops everything that was held
+	*ring.get_or_insert(1000, 0).unwrap() += 1;
+	assert_eq!(ring.get(13), None);
+	assert_eq!(ring.get(1000), Some(&1));
+	assert_eq!(ring.range().map(|range| (range.start, range.end)), Some((1000, 1001)));
+}
+
+#[tokio::test]
+async fn test_time_ring_skew() {
+	let mut ring: TimeRing<u32> = TimeRing::new(5).with_max_skew(10);
+	*ring.get_or_insert(100, 100).unwrap() += 1;
+	// past both the newest bucket and the clock
+	assert!(ring.is_ahead(111, 100));
+	assert!(!ring.is_ahead(110, 100));
+	assert!(ring.get_or_insert(1000, 100).is_none());
+	assert_eq!(ring.get(100), Some(&1));
+	assert!(ring.get_or_insert(109, 100).is_some());
+	// the clock moved on while nothing was written
+	*ring.get_or_insert(1000, 995).unwrap() += 1;
+	assert_eq!(ring.range().map(|range| (range.start, range.end)), Some((1000, 1001)));
+	
+	let db = LocalDB::new();
+	let tag = aggregate_tag(1, 1, 10, UserAction::BUY);
+	db.add_aggregate_event(10, tag.clone()).await.unwrap();
+	let far = chrono::Utc::now().timestamp_millis() / AGGREGATE_BUCKET + 24 * 60;
+	assert!(matches!(db.add_aggregate_event(far, tag).await, Err(DbError::OutOfRange(_))));
+	let buckets = db.get_aggregate(&aggregate_request(10, 11, UserAction::BUY, None, None)).await.unwrap().aggregates;
+	assert_eq!(buckets[0].count, 1);
+}
+
+#[tokio::test]
+async fn test_local_aggregate_events_batch() {
+	let db = LocalDB::new();
+	let far = chrono::Utc::now().timestamp_millis() / AGGREGATE_BUCKET + 24 * 60;
+	let events = [10, far, 11, 10].map(|minute| (minute, aggregate_tag(1, 1, 10, UserAction::BUY)));
+	let results = db.add_aggregate_events(events.to_vec()).await;
+	assert_eq!(results.iter().map(|result| result.is_ok()).collect::<Vec<_>>(), vec![true, false, true, true]);
+	let buckets = db.get_aggregate(&aggregate_request(10, 12, UserAction::BUY, None, None)).await.unwrap().aggregates;
+	assert_eq!(buckets.iter().map(|bucket| bucket.count).collect::<Vec<_>>(), vec![2, 1]);
+}
+
+#[tokio::test]
+async fn test_local_aggregates_out_of_range() {
+	let db = LocalDB::with_aggregate_retention(AggregateRetention { minutes: 10, hours: 1, days: 1, ..Default::default() });
+	let empty = db.get_aggregate(&aggregate_request(0, 3, UserAction::BUY, None, None)).await.unwrap();
+	assert_eq!(empty.aggregates.len(), 3);
+	
+	db.add_aggregate_event(50, aggregate_tag(1, 1, 10, UserAction::BUY)).await.unwrap();
+	db.add_aggregate_event(45, aggregate_tag(1, 1, 10, UserAction::BUY)).await.unwrap();
+	let response = db.get_aggregate(&aggregate_request(40, 60, UserAction::BUY, None, None)).await.unwrap();
+	let counts: u64 = response.aggregates.iter().map(|x| x.count).sum();
+	assert_eq!(response.aggregates.len(), 20);
+	assert_eq!(counts, 2);
+}
+
+#[tokio::test]
+async fn test_local_aggregate_granularity() {
+	let db = LocalDB::with_aggregate_retention(AggregateRetention { minutes: 2000, hours: 100, days: 10, ..Default::default() });
+	for minute in [0, 4, 5, 59, 60, 1500] {
+		db.add_aggregate_event(minute, aggregate_tag(1, 1, 10, UserAction::BUY)).await.unwrap();
+	}
+	let expected = [
+		(Granularity::FiveMinutes, vec![2, 1]),
+		(Granularity::Hour, vec![4, 1]),
+		(Granularity::Day, vec![5, 1]),
+	];
+	for (granularity, counts) in expected {
+		let mut request = aggregate_request(0, 2, UserAction::BUY, None, None);
+		request.granularity = granularity;
+		let response = db.get_aggregate(&request).await.unwrap();
+		assert_eq!(response.aggregates.iter().map(|x| x.count).collect::<Vec<u64>>(), counts);
+	}
+}
+
+#[tokio::test]
+async fn test_local_grouped_aggregates() {
+	let db = LocalDB::new();
+	db.add_aggregate_event(10, aggregate_tag(1, 7, 10, UserAction::BUY)).await.unwrap();
+	db.add_aggregate_event(10, aggregate_tag(2, 7, 20, UserAction::BUY)).await.unwrap();
+	db.add_aggregate_event(10, aggregate_tag(2, 8, 40, UserAction::BUY)).await.unwrap();
+	db.add_aggregate_event(11, aggregate_tag(1, 8, 80, UserAction::VIEW)).await.unwrap();
+	
+	let mut request = aggregate_request(10, 12, UserAction::BUY, None, None);
+	request.group_by = vec![AggregateDimension::BrandId];
+	let response = db.get_grouped_aggregate(&request).await.unwrap();
+	let groups: Vec<(Option<u16>, Option<u16>, u64)> = response.groups[0].iter()
+		.map(|group| (group.key.origin_id, group.key.brand_id, group.bucket.sum))
+		.collect();
+	assert_eq!(groups, vec![(None, Some(7), 30), (None, Some(8), 40)]);
+	assert!(response.groups[1].is_empty());
+	
+	request.origin = Some(2);
+	request.group_by = vec![AggregateDimension::BrandId, AggregateDimension::Origin];
+	let response = db.get_grouped_aggregate(&request).await.unwrap();
+	let groups: Vec<(Option<u16>, Option<u16>, u64)> = response.groups[0].iter()
+		.map(|group| (group.key.origin_id, group.key.brand_id, group.bucket.sum))
+		.collect();
+	assert_eq!(groups, vec![(Some(2), Some(7), 20), (Some(2), Some(8), 40)]);
+}
+
+#[tokio::test]
+async fn test_local_distinct_users() {
+	let db = LocalDB::new();
+	for (origin_id, brand_id, cookie_fingerprint) in [(1, 7, 1), (2, 7, 1), (2, 8, 1), (2, 8, 2)] {
+		let tag = AggregateTagEvent { cookie_fingerprint, ..aggregate_tag(origin_id, brand_id, 10, UserAction::BUY) };
+		db.add_aggregate_event(10, tag).await.unwrap();
+	}
+	
+	let mut request = aggregate_request(10, 11, UserAction::BUY, None, None);
+	let response = db.get_aggregate(&request).await.unwrap();
+	assert_eq!((response.aggregates[0].count, response.aggregates[0].distinct_users.estimate()), (4, 2));
+	
+	request.group_by = vec![AggregateDimension::BrandId];
+	let response = db.get_grouped_aggregate(&request).await.unwrap();
+	let groups: Vec<(Option<u16>, u64)> = response.groups[0].iter()
+		.map(|group| (group.key.brand_id, group.bucket.distinct_users.estimate()))
+		.collect();
+	assert_eq!(groups, vec![(Some(7), 1), (Some(8), 2)]);
+	
+	// only the full cells keep sketches, merged when distinct users are asked for
+	request.group_by = vec![];
+	request.distinct_users = false;
+	let response = db.get_aggregate(&request).await.unwrap();
+	assert_eq!((response.aggregates[0].count, response.aggregates[0].distinct_users.estimate()), (4, 0));
+}
+
+#[test]
+fn test_cube_key_encoding() {
+	let tag = aggregate_tag(3, u16::MAX, 1, UserAction::BUY);
+	for key in CubeKey::combinations(&tag) {
+		assert_eq!(CubeKey::decode(key.encode()), key);
+	}
+}
+
+#[test]
+fn test_aggregate_tier_cover() {
+	let day = AggregateTier::Day.minutes();
+	assert_eq!(AggregateTier::cover(0..0), vec![]);
+	assert_eq!(AggregateTier::cover(58..122), vec![
+		(AggregateTier::Minute, 58),
+		(AggregateTier::Minute, 59),
+		(AggregateTier::Hour, 1),
+		(AggregateTier::Minute, 120),
+		(AggregateTier::Minute, 121),
+	]);
+	assert_eq!(AggregateTier::cover(day - 60..2 * day + 1), vec![
+		(AggregateTier::Hour, 23),
+		(AggregateTier::Day, 1),
+		(AggregateTier::Minute, 2 * day),
+	]);
+}
+
+#[tokio::test]
+async fn test_local_top() {
+	let db = LocalDB::new();
+	for (minute, product_id, brand_id, price) in [(10, 1, 7, 10), (10, 2, 7, 20), (70, 2, 8, 30), (70, 3, 8, 100), (200, 3, 8, 100)] {
+		db.add_aggregate_event(minute, AggregateTagEvent { product_id, ..aggregate_tag(0, brand_id, price, UserAction::BUY) }).await.unwrap();
+	}
+	db.add_aggregate_event(10, AggregateTagEvent { product_id: 1, ..aggregate_tag(0, 7, 5, UserAction::VIEW) }).await.unwrap();
+	
+	let mut request = GetTopRequest {
+		time_range: TimeRange { start: 0, end: 120 },
+		action: UserAction::BUY,
+		dimension: TopDimension::ProductId,
+		metric: TopMetric::Count,
+		n: 10,
+	};
+	
+	let response = db.get_top(&request).await.unwrap();
+	let counts: Vec<(u64, u64)> = response.top.iter().map(|(key, counter)| (key.id, counter.count)).collect();
+	assert_eq!(counts, vec![(2, 2), (1, 1), (3, 1)]);
+	
+	request.metric = TopMetric::Sum;
+	request.n = 2;
+	let response = db.get_top(&request).await.unwrap();
+	let sums: Vec<(u64, u64)> = response.top.iter().map(|(key, counter)| (key.id, counter.sum)).collect();
+	assert_eq!(sums, vec![(3, 100), (2, 50)]);
+	
+	request.dimension = TopDimension::BrandId;
+	request.time_range = TimeRange { start: 0, end: 24 * 60 };
+	let response = db.get_top(&request).await.unwrap();
+	let sums: Vec<(u64, u64)> = response.top.iter().map(|(key, counter)| (key.id, counter.sum)).collect();
+	assert_eq!(sums, vec![(8, 230), (7, 30)]);
+}
+
+#[tokio::test]
+async fn test_local_mappings_snapshot() {
+	let path = env::temp_dir().join(format!("rtb_mappings_{}.json", std::process::id()));
+	let _ = std::fs::remove_file(&path);
+	
+	let db = LocalDB::with_aggregate_retention(AggregateRetention::default()).with_mappings_snapshot(&path);
+	let compressed = Compressor::<AggregateTagEvent>::compress_with_partial(&db, PartialAggregateTagEventCompressedData {
+		product_id: Partial::Same(String::from("product")),
+		origin_id: Partial::Same(String::from("origin")),
+		brand_id: Partial::Same(String::from("brand")),
+		category_id: Partial::Same(String::from("category")),
+	}).await.unwrap();
+	db.save_mappings().await.unwrap();
+	
+	let restored = LocalDB::with_aggregate_retention(AggregateRetention::default()).with_mappings_snapshot(&path);
+	let brand = TopKey { dimension: TopDimension::BrandId, id: compressed.brand_id as u64 };
+	assert_eq!(restored.decompress(&brand).await.unwrap(), Some(String::from("brand")));
+	let product = TopKey { dimension: TopDimension::ProductId, id: compressed.product_id };
+	assert_eq!(restored.decompress(&product).await.unwrap(), Some(String::from("product")));
+	
+	let mut snapshot = MappingsSnapshot::load(&path).unwrap().unwrap();
+	snapshot.version = MappingsSnapshot::VERSION + 1;
+	snapshot.save(&path).await.unwrap();
+	assert!(MappingsSnapshot::load(&path).is_err());
+	let cold = LocalDB::with_aggregate_retention(AggregateRetention::default()).with_mappings_snapshot(&path);
+	assert_eq!(cold.decompress(&brand).await.unwrap(), None);
+	
+	std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_local_id_space_overflow() {
+	let db = LocalDB::with_aggregate_retention(AggregateRetention::default());
+	let tag = |country: usize| PartialUserTagEventCompressedData {
+		product_id: Partial::Same(String::from("product")),
+		brand_id: Partial::Same(String::from("brand")),
+		category_id: Partial::Same(String::from("category")),
+		country_id: Partial::Same(format!("country {}", country)),
+		origin_id: Partial::Same(String::from("origin")),
+	};
+	for country in 0..256 {
+		let compressed = Compressor::<UserTagEvent>::compress_with_partial(&db, tag(country)).await.unwrap();
+		assert_eq!(compressed.country_id as usize, country);
+	}
+	
+	let err = Compressor::<UserTagEvent>::compress_with_partial(&db, tag(256)).await.unwrap_err();
+	assert!(matches!(err, DbError::Overflow(IdSpaceOverflow { dictionary: Dictionary::Country })));
+	// known values still compress once the dictionary is full
+	assert!(Compressor::<UserTagEvent>::compress_with_partial(&db, tag(7)).await.is_ok());
+	
+	let fill: Vec<(Dictionary, u64)> = db.get_dictionaries().await.unwrap().iter()
+		.map(|fill| (fill.dictionary, fill.size))
+		.collect();
+	assert_eq!(fill, vec![
+		(Dictionary::ProductId, 1),
+		(Dictionary::OriginId, 1),
+		(Dictionary::BrandId, 1),
+		(Dictionary::Country, 256),
+		(Dictionary::CategoryId, 1),
+	]);
+}
+
+#[test]
+fn test_binary_codec() {
+	let tag = UserTagEvent {
+		product_id: u64::MAX - 1,
+		brand_id: 513,
+		category_id: u16::MAX,
+		country_id: 255,
+		origin_id: 7,
+		time: -1,
+		price: i32::MIN,
+		device: Device::TV,
+	};
+	let bytes = tag.encode();
+	assert_eq!(bytes.len(), UserTagEvent::SIZE);
+	assert_eq!(bytes[0], UserTagEvent::VERSION);
+	assert_eq!(UserTagEvent::decode(&bytes).unwrap(), tag);
+	
+	let mut other_version = bytes.clone();
+	other_version[0] = UserTagEvent::VERSION + 1;
+	assert!(UserTagEvent::decode(&other_version).is_err());
+	assert!(UserTagEvent::decode(&bytes[..bytes.len() - 1]).is_err());
+	assert!(UserTagEvent::decode(&[]).is_err());
+	
+	let aggregate = AggregateTagEvent { product_id: 42, ..aggregate_tag(3, 4, 1000, UserAction::BUY) };
+	let bytes = aggregate.encode();
+	assert_eq!(bytes.len(), AggregateTagEvent::SIZE);
+	assert_eq!(bytes[0], AggregateTagEvent::VERSION);
+	let decoded = AggregateTagEvent::decode(&bytes).unwrap();
+	assert_eq!(decoded.encode(), bytes);
+	assert_eq!((decoded.product_id, decoded.brand_id, decoded.price, decoded.action), (42, 4, 1000, UserAction::BUY));
+	
+	let mut other_version = bytes.clone();
+	other_version[0] = AggregateTagEvent::VERSION + 1;
+	assert!(AggregateTagEvent::decode(&other_version).is_err());
+	assert!(AggregateTagEvent::decode(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn test_profile_entry() {
+	assert_eq!(decode_profile_entry(&profile_entry(&user_tag(4))), Some(user_tag(4)));
+	let legacy = Value::String(serde_json::to_string(&user_tag(5)).unwrap());
+	assert_eq!(decode_profile_entry(&legacy), Some(user_tag(5)));
+	assert_eq!(decode_profile_entry(&Value::Blob(user_tag(6).encode())), Some(user_tag(6)));
+}
+
+#[test]
+fn test_mapping_result_positions() {
+	// append result and index of every value of the bin, then the result of the capacity trim
+	let brands = Value::List(vec![
+		Value::Int(3), Value::List(vec![Value::Int(2)]),
+		Value::Int(4), Value::List(vec![Value::Int(3)]),
+		Value::Nil,
+	]);
+	let countries = Value::List(vec![Value::Int(257), Value::List(vec![Value::Int(256)]), Value::Nil]);
+	let bins = [("brand_id", brands), ("country", countries), ("origin_id", Value::Int(1))]
+		.map(|(bin, value)| (String::from(bin), value));
+	let record = aerospike::Record::new(None, bins.into_iter().collect(), 1, 0);
+	
+	assert_eq!(retrieve_value_from_mapping_result("brand_id", 0, &record).unwrap(), 2);
+	assert_eq!(retrieve_value_from_mapping_result("brand_id", 1, &record).unwrap(), 3);
+	assert_eq!(retrieve_value_from_mapping_result("country", 0, &record).unwrap(), 256);
+	assert!(matches!(retrieve_value_from_mapping_result("country", 1, &record), Err(DbError::Protocol(_))));
+	assert!(matches!(retrieve_value_from_mapping_result("origin_id", 0, &record), Err(DbError::Protocol(_))));
+	assert!(matches!(retrieve_value_from_mapping_result("category_id", 0, &record), Err(DbError::Protocol(_))));
+}
+
+#[test]
+fn test_profile_concurrent_append() {
+	const WRITERS: i64 = 8;
+	const TAGS_PER_WRITER: i64 = 100;
+	let record = ProfileRecordStandIn::default();
+	
+	std::thread::scope(|scope| {
+		for writer in 0..WRITERS {
+			let record = &record;
+			scope.spawn(move || {
+				for i in 0..TAGS_PER_WRITER {
+					// writers interleave in time and half of them send their tags newest first,
+					// together with an old tag that is trimmed right away
+					let i = if writer % 2 == 0 { i } else { TAGS_PER_WRITER - 1 - i };
+					let mut entries = vec![profile_entry(&user_tag(i * WRITERS + writer))];
+					if writer % 2 == 1 {
+						entries.push(profile_entry(&user_tag(-i)));
+					}
+					let bins = [("view_tags", entries), ("buy_tags", vec![])];
+					record.operate(&profile_append_operations(&bins, MAX_TAGS));
+				}
+			});
+		}
+	});
+	
+	let bins = record.bins.into_inner().unwrap();
+	let times: Vec<i64> = bins["view_tags"].iter()
+		.map(|entry| decode_profile_entry(entry).unwrap().time)
+		.collect();
+	let total = WRITERS * TAGS_PER_WRITER;
+	assert_eq!(times, (total - MAX_TAGS as i64..total).rev().collect::<Vec<i64>>());
+	// no operations for a bin without new entries
+	assert!(!bins.contains_key("buy_tags"));
+}
+
+/// Drives the operations `add_user_event` sends against a server, at `AEROSPIKE_HOSTS`
+#[cfg(feature = "live-aerospike")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_aerospike_profile_concurrent_append() {
+	const WRITERS: i64 = 8;
+	const TAGS_PER_WRITER: i64 = 100;
+	let config = crate::config::AerospikeConfig {
+		hosts: env::var("AEROSPIKE_HOSTS").unwrap_or(String::from("127.0.0.1:3000")),
+		..Default::default()
+	};
+	let db = Arc::new(crate::database::AerospikeDB::new(&config, MAX_TAGS).unwrap());
+	let cookie = Cookie(format!("concurrent_append_{}_{}", std::process::id(), chrono::Utc::now().timestamp_millis()));
+	
+	let writers: Vec<_> = (0..WRITERS).map(|writer| {
+		let (db, cookie) = (db.clone(), cookie.clone());
+		tokio::spawn(async move {
+			for i in 0..TAGS_PER_WRITER {
+				// writers interleave in time and half of them send their tags newest first
+				let i = if writer % 2 == 0 { i } else { TAGS_PER_WRITER - 1 - i };
+				db.add_user_event(&cookie, user_tag(i * WRITERS + writer), UserAction::VIEW).await.unwrap();
+			}
+		})
+	}).collect();
+	for writer in writers {
+		writer.await.unwrap();
+	}
+	
+	let times: Vec<i64> = db.get_user_profile(&cookie).await.unwrap().view_events.iter().map(|tag| tag.time).collect();
+	let total = WRITERS * TAGS_PER_WRITER;
+	assert_eq!(times, (total - MAX_TAGS as i64..total).collect::<Vec<i64>>());
+	
+	// a legacy bin, appended oldest first, is trimmed to the newest entries by a read
+	let client = Client::new(&ClientPolicy::default(), &config.hosts).unwrap();
+	let key = as_key!(config.namespace.as_str(), config.sets.tags.as_str(), &cookie.0);
+	let legacy: Vec<Value> = (0..MAX_TAGS as i64 + 5)
+		.map(|time| Value::String(serde_json::to_string(&user_tag(time)).unwrap()))
+		.collect();
+	client.put(&WritePolicy::default(), &key, &[as_bin!("buy", legacy)]).unwrap();
+	let times: Vec<i64> = db.get_user_profile(&cookie).await.unwrap().buy_events.iter().map(|tag| tag.time).collect();
+	assert_eq!(times, (5..MAX_TAGS as i64 + 5).collect::<Vec<i64>>());
+	let record = client.get(&ReadPolicy::default(), &key, Bins::All).unwrap();
+	assert!(matches!(record.bins.get("buy"), Some(Value::List(list)) if list.len() == MAX_TAGS));
+	client.delete(&WritePolicy::default(), &key).unwrap();
+}
+
+#[tokio::test]
+async fn test_local_profile_time_order() {
+	let db = LocalDB::with_aggregate_retention(AggregateRetention::default());
+	let cookie = Cookie(String::from("cookie"));
+	let total = MAX_TAGS as i64 + 50;
+	// out of order ingestion: even times first, then odd ones
+	for time in (0..total).step_by(2).chain((1..total).step_by(2)) {
+		db.add_user_event(&cookie, user_tag(time), UserAction::VIEW).await.unwrap();
+	}
+	db.add_user_event(&cookie, user_tag(0), UserAction::VIEW).await.unwrap();
+	
+	let profile = db.get_user_profile(&cookie).await.unwrap();
+	assert!(profile.buy_events.is_empty());
+	let times: Vec<i64> = profile.view_events.iter().map(|tag| tag.time).collect();
+	assert_eq!(times, (total - MAX_TAGS as i64..total).collect::<Vec<i64>>());
+	
+	let within = tags_within(&profile.view_events, &TimeRange { start: 100, end: 103 });
+	assert_eq!(within.iter().map(|tag| tag.time).collect::<Vec<i64>>(), vec![100, 101, 102]);
+	assert!(tags_within(&profile.view_events, &TimeRange { start: 300, end: 400 }).is_empty());
+	assert!(tags_within(&profile.view_events, &TimeRange { start: 120, end: 110 }).is_empty());
+}
+
+#[tokio::test]
+async fn test_local_batch_compression() {
+	let db = LocalDB::with_aggregate_retention(AggregateRetention::default());
+	let api_tag = |country: usize, cookie: &str| api_user_tag(cookie, &format!("country {}", country), "VIEW");
+	let mut api_tags: Vec<ApiUserTag> = (0..256).map(|country| api_tag(country, "first")).collect();
+	api_tags.push(api_tag(256, "first"));
+	api_tags.push(api_tag(3, "second"));
+	
+	let tags = UserTagEvent::compress_batch(&api_tags, &db).await;
+	assert_eq!(tags.len(), api_tags.len());
+	assert_eq!(tags[3].as_ref().unwrap().country_id, 3);
+	let err = tags[256].as_ref().unwrap_err();
+	assert!(matches!(err.downcast_ref::<DbError>(), Some(DbError::Overflow(IdSpaceOverflow { dictionary: Dictionary::Country }))));
+	assert_eq!(tags[257].as_ref().unwrap(), tags[3].as_ref().unwrap());
+	
+	let cookie = Cookie(String::from("first"));
+	let events: Vec<(UserTagEvent, UserAction)> = tags.into_iter()
+		.take(256)
+		.map(|tag| (tag.unwrap(), UserAction::VIEW))
+		.collect();
+	db.add_user_events(&cookie, events).await.unwrap();
+	let profile = db.get_user_profile(&cookie).await.unwrap();
+	assert_eq!(profile.view_events.len(), MAX_TAGS);
+	assert!(profile.buy_events.is_empty());
+}
+
+#[tokio::test]
+async fn test_local_backend() {
+	let db = BackendDB::Local(LocalDB::with_aggregate_retention(AggregateRetention::default()));
+	assert!(db.local_db().is_some());
+	let api_tag = api_user_tag("cookie", "PL", "BUY");
+	
+	let cookie = Cookie(api_tag.cookie.clone());
+	let tag = UserTagEvent::compress(&api_tag, &db).await.unwrap();
+	db.add_user_event(&cookie, tag, UserAction::BUY).await.unwrap();
+	let profile = db.get_user_profile(&cookie).await.unwrap();
+	assert!(profile.view_events.is_empty());
+	let decompressed = profile.buy_events[0].decompress(&db, (cookie, UserAction::BUY)).await.unwrap();
+	assert_eq!(decompressed, api_tag);
+	
+	let dictionaries = db.get_dictionaries().await.unwrap();
+	assert!(dictionaries.iter().all(|fill| fill.size == 1));
+}
+
+#[tokio::test]
+async fn test_local_status() {
+	let db = LocalDB::new();
+	assert_eq!(db.cookie_count(), 0);
+	assert!(db.aggregate_minutes().await.is_none());
+	
+	db.add_aggregate_event(100, aggregate_tag(1, 1, 10, UserAction::BUY)).await.unwrap();
+	db.add_aggregate_event(104, aggregate_tag(1, 1, 10, UserAction::VIEW)).await.unwrap();
+	let minutes = db.aggregate_minutes().await.unwrap();
+	assert_eq!((minutes.start, minutes.end), (100, 105));
+	
+	db.add_user_event(&Cookie(String::from("first")), user_tag(0), UserAction::VIEW).await.unwrap();
+	db.add_user_event(&Cookie(String::from("second")), user_tag(0), UserAction::BUY).await.unwrap();
+	db.add_user_event(&Cookie(String::from("first")), user_tag(1), UserAction::BUY).await.unwrap();
+	assert_eq!(db.cookie_count(), 2);
+}
+
+#[test]
+fn test_lookup_classification() {
+	let partial = PartialTopKeyDecompressedData { dimension: TopDimension::BrandId, value: Partial::Changed(3) };
+	assert_eq!(partial.same_fields(), vec![false]);
+	let partial = PartialCubeKeyDecompressedData {
+		origin_id: Partial::Same(Some(String::from("origin"))),
+		brand_id: Partial::Changed(None),
+		category_id: Partial::Changed(Some(2)),
+	};
+	assert_eq!(partial.same_fields(), vec![true, false]);
+}
+
+#[tokio::test]
+async fn test_metrics() {
+	let result: DbResult<()> = observe_db_call("test_metrics", async { Err(DbError::NotFound(String::from("cookie"))) }).await;
+	assert!(result.is_err());
+	let exposition = METRICS.encode().unwrap();
+	assert!(exposition.contains("db_call_errors_total{kind=\"not_found\",method=\"test_metrics\"} 1"));
+	assert!(exposition.contains("db_call_duration_seconds_count{method=\"test_metrics\"} 1"));
+}
+
+fn aggregate_write(timestamp: i64) -> Write {
+	Write::AggregateEvents { timestamp, tags: vec![aggregate_tag(1, 1, 10, UserAction::VIEW)] }
+}
+
+fn write_timestamps(batch: Vec<Write>) -> Vec<i64> {
+	batch.into_iter()
+		.map(|write| match write {
+			Write::AggregateEvents { timestamp, .. } => timestamp,
+			Write::UserEvents { .. } => -1,
+		})
+		.collect()
+}
+
+#[tokio::test]
+async fn test_write_shard_overflow() {
+	let shard = WriteShard::new(2, OverflowPolicy::DropOldest);
+	assert!(shard.push(aggregate_write(1)).await.unwrap().is_none());
+	assert!(shard.push(aggregate_write(2)).await.unwrap().is_none());
+	let dropped = shard.push(aggregate_write(3)).await.unwrap().unwrap();
+	assert_eq!(write_timestamps(vec![dropped]), vec![1]);
+	assert_eq!(write_timestamps(shard.take_batch(10).await.unwrap()), vec![2, 3]);
+	
+	let shard = WriteShard::new(1, OverflowPolicy::Reject);
+	shard.push(aggregate_write(1)).await.unwrap();
+	assert!(matches!(shard.push(aggregate_write(2)).await, Err((DbError::Overloaded(_), _))));
+	
+	let shard = Arc::new(WriteShard::new(1, OverflowPolicy::Block));
+	shard.push(aggregate_write(1)).await.unwrap();
+	let blocked_shard = shard.clone();
+	let blocked = tokio::spawn(async move { blocked_shard.push(aggregate_write(2)).await.map(|dropped| dropped.is_none()).map_err(|(err, _)| err) });
+	tokio::time::sleep(Duration::from_millis(20)).await;
+	assert!(!blocked.is_finished());
+	assert_eq!(write_timestamps(shard.take_batch(10).await.unwrap()), vec![1]);
+	assert!(blocked.await.unwrap().unwrap());
+	
+	shard.close();
+	assert!(matches!(shard.push(aggregate_write(3)).await, Err((DbError::Overloaded(_), _))));
+	assert_eq!(write_timestamps(shard.take_batch(10).await.unwrap()), vec![2]);
+	assert!(shard.take_batch(10).await.is_none());
+}
+
+#[tokio::test]
+async fn test_write_queue_flush() {
+	let remote_db = Arc::new(GatedDB::open(LocalDB::new()));
+	let config = WriteQueueConfig { shards: 2, batch_size: 4, ..Default::default() };
+	let queue = WriteQueue::new(&config, remote_db.clone()).unwrap();
+	let cookie = Cookie(String::from("cookie"));
+	for time in 0..10 {
+		queue.push(Write::UserEvents { cookie: cookie.clone(), tags: vec![(user_tag(time), UserAction::VIEW)], seqs: vec![] }).await.unwrap();
+	}
+	queue.push(aggregate_write(100)).await.unwrap();
+	queue.flush().await;
+	
+	assert_eq!(queue.pending(), 0);
+	assert_eq!(queue.lost(), 0);
+	let profile = remote_db.get_user_profile(&cookie).await.unwrap();
+	let times: Vec<i64> = profile.view_events.iter().map(|tag| tag.time).collect();
+	assert_eq!(times, (0..10).collect::<Vec<i64>>());
+	assert!(remote_db.inner.aggregate_minutes().await.is_some());
+	assert!(matches!(queue.push(aggregate_write(101)).await, Err(DbError::Overloaded(_))));
+}
+
+/// Remote database whose user event writes wait until the test lets them through
+struct GatedDB {
+	inner: LocalDB,
+	gate: tokio::sync::Semaphore,
+	/// Aggregate writes fail with this error when set, the first `aggregate_failures` of them
+	aggregate_error: Option<DbError>,
+	aggregate_failures: usize,
+	aggregate_calls: AtomicUsize,
+}
+
+impl GatedDB {
+	fn new(inner: LocalDB) -> Self {
+		Self { inner, gate: tokio::sync::Semaphore::new(0), aggregate_error: None, aggregate_failures: usize::MAX, aggregate_calls: AtomicUsize::new(0) }
+	}
+	
+	fn open(inner: LocalDB) -> Self {
+		let db = Self::new(inner);
+		db.gate.add_permits(tokio::sync::Semaphore::MAX_PERMITS);
+		db
+	}
+}
+
+impl Synced for GatedDB {}
+impl SyncedDB for GatedDB {}
+
+impl Database for GatedDB {
+	async fn add_user_event(&self, cookie: &Cookie, tag: UserTagEvent, action: UserAction) -> DbResult<()> {
+		self.add_user_events(cookie, vec![(tag, action)]).await
+	}
+	
+	async fn add_user_events(&self, cookie: &Cookie, tags: Vec<(UserTagEvent, UserAction)>) -> DbResult<()> {
+		self.gate.acquire().await.unwrap().forget();
+		self.inner.add_user_events(cookie, tags).await
+	}
+	
+	async fn get_user_profile(&self, cookie: &Cookie) -> DbResult<UserProfile> {
+		self.inner.get_user_profile(cookie).await
+	}
+	
+	async fn add_aggregate_event(&self, timestamp: i64, tag: AggregateTagEvent) -> DbResult<()> {
+		self.inner.add_aggregate_event(timestamp, tag).await
+	}
+	
+	async fn add_aggregate_events(&self, events: Vec<(i64, AggregateTagEvent)>) -> Vec<DbResult<()>> {
+		let calls = self.aggregate_calls.fetch_add(1, Ordering::Relaxed);
+		match &self.aggregate_error {
+			Some(err) if calls < self.aggregate_failures => events.iter().map(|_| Err(err.clone())).collect(),
+			_ => self.inner.add_aggregate_events(events).await,
+		}
+	}
+	
+	async fn get_aggregate(&self, request: &GetAggregateRequest) -> DbResult<GetAggregateResponse> {
+		self.inner.get_aggregate(request).await
+	}
+	
+	async fn get_grouped_aggregate(&self, request: &GetAggregateRequest) -> DbResult<GetGroupedAggregateResponse> {
+		self.inner.get_grouped_aggregate(request).await
+	}
+	
+	async fn get_top(&self, request: &GetTopRequest) -> DbResult<GetTopResponse> {
+		self.inner.get_top(request).await
+	}
+	
+	async fn get_dictionaries(&self) -> DbResult<Vec<DictionaryFill>> {
+		self.inner.get_dictionaries().await
+	}
+}
+
+impl Compressor<UserTagEvent> for GatedDB {
+	async fn compress_with_partial(&self, partial: PartialUserTagEventCompressedData) -> DbResult<UserTagEventCompressedData> {
+		Compressor::<UserTagEvent>::compress_with_partial(&self.inner, partial).await
+	}
+}
+
+/// Calls made, events lost and whether the events were written when the first `failures` aggregate
+/// writes fail with the error, with 3 retries
+async fn aggregate_calls(error: DbError, failures: usize) -> (usize, u64, bool) {
+	let remote_db = Arc::new(GatedDB { aggregate_error: Some(error), aggregate_failures: failures, ..GatedDB::open(LocalDB::new()) });
+	let config = WriteQueueConfig { max_retries: 3, initial_backoff_ms: 1, ..Default::default() };
+	let queue = WriteQueue::new(&config, remote_db.clone()).unwrap();
+	queue.push(aggregate_write(1)).await.unwrap();
+	queue.flush().await;
+	(remote_db.aggregate_calls.load(Ordering::Relaxed), queue.lost(), remote_db.inner.aggregate_minutes().await.is_some())
+}
+
+#[tokio::test]
+async fn test_write_queue_retries() {
+	assert!(DbError::Timeout(String::from("timeout")).is_transient());
+	assert!(!DbError::Timeout(String::from("timeout")).is_unapplied());
+	assert!(DbError::Unavailable(String::from("busy")).is_transient());
+	assert!(DbError::Unavailable(String::from("busy")).is_unapplied());
+	assert!(!DbError::Protocol(String::from("protocol")).is_transient());
+	
+	// a timed out aggregate write may have been counted, so it is not repeated
+	assert_eq!(aggregate_calls(DbError::Timeout(String::from("timeout")), 1).await, (1, 1, false));
+	// one the database did not take is repeated until it is written or the retries are used up
+	assert_eq!(aggregate_calls(DbError::Unavailable(String::from("busy")), 1).await, (2, 0, true));
+	assert_eq!(aggregate_calls(DbError::Unavailable(String::from("busy")), usize::MAX).await, (4, 1, false));
+}
+
+#[tokio::test]
+async fn test_write_queue_drop() {
+	let remote_db = Arc::new(GatedDB::open(LocalDB::new()));
+	let queue = WriteQueue::new(&WriteQueueConfig::default(), remote_db.clone()).unwrap();
+	let cookie = Cookie(String::from("cookie"));
+	queue.push(Write::UserEvents { cookie: cookie.clone(), tags: vec![(user_tag(1), UserAction::VIEW)], seqs: vec![] }).await.unwrap();
+	drop(queue);
+	// the writers drain their closed shards and release the remote database
+	while Arc::strong_count(&remote_db) > 1 {
+		tokio::time::sleep(Duration::from_millis(1)).await;
+	}
+	assert_eq!(profile_times(&remote_db.inner.get_user_profile(&cookie).await.unwrap()), vec![1]);
+}
+
+fn gated_cached_db(max_tags: usize, cache: CacheConfig) -> CachedDB<LocalDB, GatedDB> {
+	let remote_db = GatedDB::new(LocalDB::new().with_max_tags(max_tags));
+	// a single writer, so a write queued behind a stuck one is not started
+	let write_queue = WriteQueueConfig { shards: 1, ..Default::default() };
+	CachedDB::new(LocalDB::new(), remote_db, &write_queue)
+		.unwrap()
+		.with_max_tags(max_tags)
+		.with_local_profiles(&cache)
+}
+
+fn local_profiles() -> CacheConfig {
+	CacheConfig { local_profiles: true, ..Default::default() }
+}
+
+fn profile_times(profile: &UserProfile) -> Vec<i64> {
+	profile.view_events.iter().map(|tag| tag.time).collect()
+}
+
+#[tokio::test]
+async fn test_cached_read_your_writes() {
+	let db = gated_cached_db(3, CacheConfig::default());
+	let cookie = Cookie(String::from("cookie"));
+	for time in [5, 1, 4, 2] {
+		db.add_user_event(&cookie, user_tag(time), UserAction::VIEW).await.unwrap();
+	}
+	assert_eq!(db.pending_writes(), 4);
+	assert!(db.remote_db().inner.get_user_profile(&cookie).await.unwrap().view_events.is_empty());
+	assert_eq!(profile_times(&db.get_user_profile(&cookie).await.unwrap()), vec![2, 4, 5]);
+	
+	db.remote_db().gate.add_permits(1);
+	while db.pending_writes() > 0 {
+		tokio::time::sleep(Duration::from_millis(1)).await;
+	}
+	// written events are not merged twice
+	assert_eq!(profile_times(&db.get_user_profile(&cookie).await.unwrap()), vec![2, 4, 5]);
+	db.flush().await;
+}
+
+#[tokio::test]
+async fn test_cached_identical_events() {
+	let db = gated_cached_db(MAX_TAGS, CacheConfig::default());
+	let (cookie, stuck) = (Cookie(String::from("cookie")), Cookie(String::from("stuck")));
+	db.add_user_event(&cookie, user_tag(1), UserAction::VIEW).await.unwrap();
+	db.remote_db().gate.add_permits(1);
+	while db.pending_writes() > 0 {
+		tokio::time::sleep(Duration::from_millis(1)).await;
+	}
+	db.add_user_event(&stuck, user_tag(1), UserAction::VIEW).await.unwrap();
+	// an equal event still queued is another one and not the flushed one
+	db.add_user_event(&cookie, user_tag(1), UserAction::VIEW).await.unwrap();
+	assert_eq!(profile_times(&db.get_user_profile(&cookie).await.unwrap()), vec![1, 1]);
+	
+	db.remote_db().gate.add_permits(2);
+	db.flush().await;
+	assert_eq!(profile_times(&db.get_user_profile(&cookie).await.unwrap()), vec![1, 1]);
+}
+
+#[tokio::test]
+async fn test_cached_local_profiles() {
+	let db = gated_cached_db(MAX_TAGS, local_profiles());
+	let (cookie, stuck) = (Cookie(String::from("cookie")), Cookie(String::from("stuck")));
+	// a cookie with a write running is not cached, no write of this one is started here
+	db.add_user_event(&stuck, user_tag(0), UserAction::VIEW).await.unwrap();
+	db.add_user_event(&cookie, user_tag(1), UserAction::VIEW).await.unwrap();
+	assert_eq!(profile_times(&db.get_user_profile(&cookie).await.unwrap()), vec![1]);
+	assert!(db.cached_profile(&cookie).is_none());
+	assert_eq!(profile_times(&db.get_user_profile(&cookie).await.unwrap()), vec![1]);
+	
+	// read twice, the cookie is hot now and its writes go to the cached profile as well
+	db.add_user_event(&cookie, user_tag(1), UserAction::VIEW).await.unwrap();
+	assert_eq!(profile_times(&db.cached_profile(&cookie).unwrap()), vec![1, 1]);
+	assert_eq!(profile_times(&db.get_user_profile(&cookie).await.unwrap()), vec![1, 1]);
+	
+	db.remote_db().gate.add_permits(3);
+	db.flush().await;
+	assert_eq!(profile_times(&db.remote_db().inner.get_user_profile(&cookie).await.unwrap()), vec![1, 1]);
+}
+
+#[tokio::test]
+async fn test_cached_local_profiles_bound() {
+	let db = gated_cached_db(MAX_TAGS, CacheConfig { max_profiles: 1, ..local_profiles() });
+	let (first, second) = (Cookie(String::from("first")), Cookie(String::from("second")));
+	for _ in 0..2 {
+		db.get_user_profile(&first).await.unwrap();
+	}
+	assert!(db.cached_profile(&first).is_some());
+	// the least recently read cookie makes room
+	db.get_user_profile(&second).await.unwrap();
+	assert!(db.cached_profile(&first).is_none());
+	
+	let db = gated_cached_db(MAX_TAGS, CacheConfig { profile_ttl_secs: 0, ..local_profiles() });
+	for _ in 0..2 {
+		db.get_user_profile(&first).await.unwrap();
+	}
+	// forgotten before every read, so never read often enough
+	assert!(db.cached_profile(&first).is_none());
+	db.flush().await;
+	
+	// a tag written past this instance shows once the cached profile is too old, however
+	// often it is read
+	for max_age in [60, 0] {
+		let db = gated_cached_db(MAX_TAGS, CacheConfig { profile_max_age_secs: max_age, ..local_profiles() });
+		for _ in 0..2 {
+			db.get_user_profile(&first).await.unwrap();
+		}
+		assert!(db.cached_profile(&first).is_some());
+		db.remote_db().inner.add_user_event(&first, user_tag(1), UserAction::VIEW).await.unwrap();
+		let times = profile_times(&db.get_user_profile(&first).await.unwrap());
+		assert_eq!(times, if max_age == 0 { vec![1] } else { vec![] });
+		db.flush().await;
+	}
+}
+
+#[tokio::test]
+async fn test_shutdown() {
+	let snapshot = env::temp_dir().join(format!("rtb_shutdown_{}.snapshot", std::process::id()));
+	let _ = std::fs::remove_file(&snapshot);
+	let local_db = || LocalDB::new().with_snapshot(&snapshot);
+	
+	let db = local_db();
+	let cookie = Cookie(String::from("cookie"));
+	db.add_user_event(&cookie, user_tag(1), UserAction::BUY).await.unwrap();
+	let report = ShutdownCoordinator::new(Arc::new(BackendDB::Local(db)), ShutdownConfig::default()).run().await;
+	assert!(report.drained && report.persisted);
+	assert_eq!(report.lost_events, 0);
+	
+	let restored = local_db();
+	assert_eq!(restored.get_user_profile(&cookie).await.unwrap().buy_events, vec![user_tag(1)]);
+	
+	std::fs::remove_file(&snapshot).unwrap();
+}
+
+#[tokio::test]
+async fn test_local_snapshot() {
+	let path = env::temp_dir().join(format!("rtb_local_{}.snapshot", std::process::id()));
+	let _ = std::fs::remove_file(&path);
+	let retention = || AggregateRetention { minutes: 3, hours: 2, days: 1, ..Default::default() };
+	
+	let db = LocalDB::with_aggregate_retention(retention()).with_snapshot(&path);
+	let cookie = Cookie(String::from("cookie"));
+	db.add_user_events(&cookie, vec![(user_tag(1), UserAction::VIEW), (user_tag(2), UserAction::BUY)]).await.unwrap();
+	for (minute, product_id, price) in [(0, 1, 10), (1, 2, 20), (1, 2, 30), (4, 3, 40)] {
+		db.add_aggregate_event(minute, AggregateTagEvent { product_id, ..aggregate_tag(1, 7, price, UserAction::BUY) }).await.unwrap();
+	}
+	let compressed = Compressor::<AggregateTagEvent>::compress_with_partial(&db, PartialAggregateTagEventCompressedData {
+		product_id: Partial::Same(String::from("product")),
+		origin_id: Partial::Same(String::from("origin")),
+		brand_id: Partial::Same(String::from("brand")),
+		category_id: Partial::Same(String::from("category")),
+	}).await.unwrap();
+	db.save_snapshot().await.unwrap();
+	// written after the snapshot, so not restored
+	db.add_user_event(&cookie, user_tag(3), UserAction::VIEW).await.unwrap();
+	
+	let restored = LocalDB::with_aggregate_retention(retention()).with_snapshot(&path);
+	let profile = restored.get_user_profile(&cookie).await.unwrap();
+	assert_eq!(profile.view_events, vec![user_tag(1)]);
+	assert_eq!(profile.buy_events, vec![user_tag(2)]);
+	
+	// the first minutes were evicted before the snapshot, the hour still counts them
+	let buckets = restored.get_aggregate(&aggregate_request(0, 5, UserAction::BUY, Some(1), None)).await.unwrap().aggregates;
+	assert_eq!(buckets.iter().map(|bucket| bucket.count).collect::<Vec<_>>(), vec![0, 0, 0, 0, 1]);
+	assert_eq!((buckets[4].sum, buckets[4].min, buckets[4].max, buckets[4].distinct_users.estimate()), (40, Some(40), Some(40), 1));
+	let top = GetTopRequest {
+		time_range: TimeRange { start: 0, end: 60 },
+		action: UserAction::BUY,
+		dimension: TopDimension::ProductId,
+		metric: TopMetric::Sum,
+		n: 10,
+	};
+	let ranked = restored.get_top(&top).await.unwrap().top;
+	assert_eq!(ranked.iter().map(|(key, counter)| (key.id, counter.sum)).collect::<Vec<_>>(), vec![(2, 50), (3, 40), (1, 10)]);
+	
+	let brand = TopKey { dimension: TopDimension::BrandId, id: compressed.brand_id as u64 };
+	assert_eq!(restored.decompress(&brand).await.unwrap(), Some(String::from("brand")));
+	let sizes = |fills: Vec<DictionaryFill>| fills.iter().map(|fill| fill.size).collect::<Vec<_>>();
+	assert_eq!(sizes(restored.get_dictionaries().await.unwrap()), sizes(db.get_dictionaries().await.unwrap()));
+	
+	// a corrupted snapshot is ignored
+	let mut content = std::fs::read(&path).unwrap();
+	let middle = content.len() / 2;
+	content[middle] ^= 1;
+	std::fs::write(&path, &content).unwrap();
+	assert_eq!(LocalDB::new().with_snapshot(&path).cookie_count(), 0);
+	
+	std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_wal() {
+	let dir = env::temp_dir().join(format!("rtb_wal_{}", std::process::id()));
+	let snapshot = env::temp_dir().join(format!("rtb_wal_{}.snapshot", std::process::id()));
+	let _ = std::fs::remove_dir_all(&dir);
+	let _ = std::fs::remove_file(&snapshot);
+	let config = WalConfig {
+		dir: dir.clone(),
+		fsync: FsyncPolicy::Always,
+		..Default::default()
+	};
+	// dropping a database without saving it is a crash
+	let open = || LocalDB::new().with_snapshot(&snapshot).with_wal(&config).unwrap();
+	let cookie = Cookie(String::from("cookie"));
+	let sold = |db: LocalDB| async move {
+		let request = aggregate_request(0, 2, UserAction::BUY, None, None);
+		let counts = db.get_aggregate(&request).await.unwrap().aggregates.iter().map(|bucket| bucket.count).collect::<Vec<_>>();
+		(db, counts)
+	};
+	
+	let db = open();
+	db.add_user_events(&cookie, vec![(user_tag(1), UserAction::VIEW), (user_tag(2), UserAction::BUY)]).await.unwrap();
+	db.add_aggregate_event(0, aggregate_tag(1, 7, 10, UserAction::BUY)).await.unwrap();
+	let compressed = Compressor::<AggregateTagEvent>::compress_with_partial(&db, PartialAggregateTagEventCompressedData {
+		product_id: Partial::Same(String::from("product")),
+		origin_id: Partial::Same(String::from("origin")),
+		brand_id: Partial::Same(String::from("brand")),
+		category_id: Partial::Same(String::from("category")),
+	}).await.unwrap();
+	drop(db);
+	
+	let db = open();
+	let profile = db.get_user_profile(&cookie).await.unwrap();
+	assert_eq!((profile.view_events, profile.buy_events), (vec![user_tag(1)], vec![user_tag(2)]));
+	let brand = TopKey { dimension: TopDimension::BrandId, id: compressed.brand_id as u64 };
+	assert_eq!(db.decompress(&brand).await.unwrap(), Some(String::from("brand")));
+	let (db, counts) = sold(db).await;
+	assert_eq!(counts, vec![1, 0]);
+	
+	// the snapshot drops the segments it holds, the events after it are replayed once
+	db.save_snapshot().await.unwrap();
+	assert_eq!(Wal::segments(&dir).unwrap().len(), 1);
+	db.add_aggregate_event(1, aggregate_tag(1, 7, 20, UserAction::BUY)).await.unwrap();
+	// equal to an event of the snapshot and to each other, still all of them replayed
+	for time in [1, 3, 3] {
+		db.add_user_event(&cookie, user_tag(time), UserAction::VIEW).await.unwrap();
+	}
+	drop(db);
+	let (db, counts) = sold(open()).await;
+	assert_eq!(counts, vec![1, 1]);
+	assert_eq!(db.get_user_profile(&cookie).await.unwrap().view_events, vec![user_tag(1), user_tag(1), user_tag(3), user_tag(3)]);
+	drop(db);
+	
+	// a torn record ends the replay of its segment
+	let (_, last) = Wal::segments(&dir).unwrap().pop().unwrap();
+	std::fs::write(&last, [7, 0, 0, 0, 1]).unwrap();
+	let (db, counts) = sold(open()).await;
+	assert_eq!(counts, vec![1, 1]);
+	drop(db);
+	
+	std::fs::remove_dir_all(&dir).unwrap();
+	std::fs::remove_file(&snapshot).unwrap();
+}
+
+#[tokio::test]
+async fn test_wal_group_commit() {
+	let dir = env::temp_dir().join(format!("rtb_wal_group_{}", std::process::id()));
+	let _ = std::fs::remove_dir_all(&dir);
+	let config = WalConfig {
+		dir: dir.clone(),
+		fsync: FsyncPolicy::Always,
+		segment_bytes: 256,
+		..Default::default()
+	};
+	let wal = Wal::open(&config, 0).unwrap();
+	let cookie = Cookie(String::from("cookie"));
+	let records: Vec<WalRecord> = (0..50)
+		.map(|time| WalRecord::UserEvents { cookie: cookie.clone(), tags: vec![(user_tag(time), UserAction::VIEW)] })
+		.collect();
+	let appends = records.iter().map(|record| wal.append(record));
+	assert!(futures::future::join_all(appends).await.into_iter().all(|appended| appended.is_ok()));
+	let segment = wal.rotate().await.unwrap();
+	wal.sync().await.unwrap();
+	drop(wal);
+	
+	let mut times = vec![];
+	Wal::replay(&dir, 0, |record| if let WalRecord::UserEvents { tags, .. } = record {
+		times.extend(tags.iter().map(|(tag, _)| tag.time));
+	}).unwrap();
+	times.sort();
+	assert_eq!(times, (0..50).collect::<Vec<i64>>());
+	assert_eq!(Wal::replay(&dir, segment, |_| {}).unwrap(), 0);
+	
+	std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_drain_deadline() {
+	let db = gated_cached_db(MAX_TAGS, CacheConfig::default());
+	let cookie = Cookie(String::from("cookie"));
+	db.add_user_events(&cookie, vec![(user_tag(1), UserAction::VIEW), (user_tag(2), UserAction::BUY)]).await.unwrap();
+	assert!(tokio::time::timeout(Duration::from_millis(20), db.flush()).await.is_err());
+	// what the coordinator reports as lost
+	assert_eq!(db.pending_writes(), 2);
+	assert!(matches!(db.add_user_event(&cookie, user_tag(3), UserAction::VIEW).await, Err(DbError::Overloaded(_))));
+	db.remote_db().gate.add_permits(1);
+}
+
+#[tokio::test]
+async fn test_db_errors() {
+	let timeout = aerospike::Error::from_kind(aerospike::ErrorKind::Timeout(String::from("deadline")));
+	assert!(matches!(DbError::from(timeout), DbError::Timeout(_)));
+	let busy = aerospike::Error::from_kind(aerospike::ErrorKind::ServerError(aerospike::ResultCode::KeyBusy));
+	assert!(matches!(DbError::from(busy), DbError::Unavailable(_)));
+	let unreachable = aerospike::Error::from_kind(aerospike::ErrorKind::Connection(String::from("refused")));
+	assert!(matches!(DbError::from(unreachable), DbError::Unavailable(_)));
+	let missing = aerospike::Error::from_kind(aerospike::ErrorKind::ServerError(aerospike::ResultCode::KeyNotFoundError));
+	assert!(matches!(DbError::from(missing), DbError::NotFound(_)));
+	let invalid = aerospike::Error::from_kind(aerospike::ErrorKind::BadResponse(String::from("garbage")));
+	assert!(matches!(DbError::from(invalid), DbError::Protocol(_)));
+	
+	// ids that were never handed out are reported instead of panicking
+	let db = LocalDB::with_aggregate_retention(AggregateRetention::default());
+	let err = Decompressor::<UserTagEvent>::decompress(&db, &user_tag(0)).await.unwrap_err();
+	assert!(matches!(err, DbError::NotFound(_)));
+}
+
+#[test]
+fn test_config() {
+	let config = Config::from_toml(r#"
+		backend = "local"
+		max_tags = 50
+		[server]
+		bind_address = "127.0.0.1:9000"
+		[aerospike.sets]
+		tags = "profiles"
+		[retention]
+		days = 30
+		[write_queue]
+		overflow = "drop-oldest"
+	"#).unwrap();
+	assert_eq!(config.backend, Backend::Local);
+	assert_eq!(config.max_tags, 50);
+	assert_eq!(config.server.bind_address.port(), 9000);
+	assert_eq!(config.aerospike.sets.tags, "profiles");
+	assert_eq!(config.aerospike.sets.minute, "minute_tags");
+	assert_eq!(config.retention.days, 30);
+	assert_eq!(config.retention.minutes, AggregateRetention::default().minutes);
+	assert_eq!(config.write_queue.overflow, OverflowPolicy::DropOldest);
+	assert_eq!(config.write_queue.shards, WriteQueueConfig::default().shards);
+	
+	// flags and environment override the file
+	let path = env::temp_dir().join(format!("config_test_{}.toml", std::process::id()));
+	std::fs::write(&path, "max_tags = 50\n[aerospike]\nnamespace = \"file\"\n").unwrap();
+	let config = Config::load(Args {
+		config: Some(path.clone()),
+		max_tags: Some(70),
+		backend: Some(Backend::Aerospike),
+		..Default::default()
+	}).unwrap();
+	assert_eq!(config.max_tags, 70);
+	assert_eq!(config.backend, Backend::Aerospike);
+	assert_eq!(config.aerospike.namespace, "file");
+	std::fs::remove_file(&path).unwrap();
+	
+	assert!(Config::from_toml("max_tag = 10").is_err());
+	assert!(Config::from_toml("backend = \"surreal\"").is_err());
+	assert!(Config::from_toml("[server]\nbind_address = \"nowhere\"").is_err());
+	assert!(Config::from_toml("max_tags = 0").unwrap().validate().is_err());
+	assert!(Config::from_toml("[retention]\nhours = 0").unwrap().validate().is_err());
+	assert!(Config::from_toml("[snapshot]\npath = \"mappings.json\"").unwrap().validate().is_err());
+	assert!(Config::from_toml("[snapshot]\ninterval_secs = 0").unwrap().validate().is_err());
+	assert!(Config::from_toml("[wal]\nfsync = \"periodic\"\nfsync_interval_ms = 0").unwrap().validate().is_err());
+	assert!(Config::from_toml("[wal]\nfsync = \"never\"\nfsync_interval_ms = 0").unwrap().validate().is_ok());
+	assert!(Config::from_toml("[write_queue]\nshards = 0").unwrap().validate().is_err());
+	assert!(Config::from_toml("[write_queue]\ninitial_backoff_ms = 10000").unwrap().validate().is_err());
+	assert!(Config::from_toml("[aerospike.sets]\nhour = \"minute_tags\"").unwrap().validate().is_err());
+	assert!(Config::from_toml("[aerospike]\ntier_shards = 0").unwrap().validate().is_err());
+	assert!(Config::load(Args { config: Some(PathBuf::from("/nonexistent/config.toml")), ..Default::default() }).is_err());
+}
+
+#[tokio::test]
+async fn test_mapper_force_insert_mapping() {
+	let mapper = crate::database::Mapper::default();
+	mapper.force_insert_mapping("first", 0).await;
+	mapper.force_insert_mapping("third", 2).await;
+	assert_eq!(mapper.get_string(0).await, Some(String::from("first")));
+	assert_eq!(mapper.get_string(1).await, None);
+	assert_eq!(mapper.get_string(2).await, Some(String::from("third")));
+	// the gap is still resolved remotely and is neither counted nor saved as a value
+	assert!(matches!(mapper.try_get_string(1).await, Partial::Changed(1)));
+	assert_eq!(mapper.len().await, 2);
+	assert_eq!(mapper.content().await.values, vec![Some(String::from("first")), None, Some(String::from("third"))]);
+	
+	let restored = crate::database::Mapper::from(mapper.content().await);
+	assert_eq!(restored.get_string(1).await, None);
+	restored.force_insert_mapping("second", 1).await;
+	assert!(matches!(restored.try_get_string(1).await, Partial::Same(value) if value == "second"));
+}
+
+#[actix_web::test]
+async fn test_aggregates_group_by_order() {
+	let state = AppState { database: Arc::new(BackendDB::Local(LocalDB::new())), max_tags: MAX_TAGS };
+	let app = actix_web::test::init_service(App::new()
+		.app_data(web::Data::new(state))
+		.service(add_user_tags)
+		.service(aggregates)).await;
+	let tag = serde_json::to_string(&api_user_tag("cookie", "PL", "VIEW")).unwrap();
+	let request = actix_web::test::TestRequest::post().uri("/user_tags").set_payload(tag).to_request();
+	assert!(actix_web::test::call_service(&app, request).await.status().is_success());
+	
+	let uri = "/aggregates?time_range=2022-03-22T12:15:00_2022-03-22T12:16:00&action=VIEW&category_id=category&group_by=brand_id,origin&aggregates=COUNT";
+	let request = actix_web::test::TestRequest::post().uri(uri).to_request();
+	let response: serde_json::Value = actix_web::test::call_and_read_body_json(&app, request).await;
+	assert_eq!(response["columns"], serde_json::json!(["1m_bucket", "action", "category_id", "brand_id", "origin", "Count"]));
+	assert_eq!(response["rows"], serde_json::json!([["2022-03-22T12:15:00", "VIEW", "category", "brand", "origin", "1"]]));
+}
+
+#[actix_web::test]
+async fn test_add_user_tags_batch() {
+	let state = AppState { database: Arc::new(BackendDB::Local(LocalDB::new())), max_tags: MAX_TAGS };
+	let app = actix_web::test::init_service(App::new()
+		.app_data(web::Data::new(state))
+		.service(add_user_tags_batch)).await;
+	let tag = |cookie: &str, action: &str| serde_json::to_value(api_user_tag(cookie, "PL", action)).unwrap();
+	let ahead = ApiUserTag { time: String::from("2999-01-01T00:00:00.000Z"), ..api_user_tag("ahead", "PL", "VIEW") };
+	let items = vec![tag("first", "VIEW"), serde_json::json!({ "cookie": "malformed" }), tag("second", "CLICK"), serde_json::to_value(ahead).unwrap(), tag("second", "BUY")];
+	let failures = |response: &serde_json::Value| -> Vec<(u64, u64, bool)> {
+		response["failed"].as_array().unwrap().iter()
+			.map(|failure| (failure["index"].as_u64().unwrap(), failure["status"].as_u64().unwrap(), failure["partial"].as_bool().unwrap()))
+			.collect()
+	};
+	
+	let array = serde_json::to_string(&items).unwrap();
+	let ndjson = items.iter().map(|item| item.to_string()).collect::<Vec<String>>().join("\n");
+	for body in [array, ndjson] {
+		let request = actix_web::test::TestRequest::post().uri("/user_tags/batch").set_payload(body).to_request();
+		let response: serde_json::Value = actix_web::test::call_and_read_body_json(&app, request).await;
+		assert_eq!(response["accepted"], 2);
+		// the tag too far ahead is in the profile, only its aggregates are missing
+		assert_eq!(failures(&response), vec![(1, 400, false), (2, 400, false), (3, 400, true)]);
+	}
+	let request = actix_web::test::TestRequest::post().uri("/user_tags/batch").set_payload("[{").to_request();
+	assert_eq!(actix_web::test::call_service(&app, request).await.status(), actix_web::http::StatusCode::BAD_REQUEST);
+	
+	// the writer is stuck at the closed gate, so the write queue stays full once filled
+	let write_queue = WriteQueueConfig { shards: 1, capacity: 1, overflow: OverflowPolicy::Reject, ..Default::default() };
+	let db = web::Data::new(CachedDB::new(LocalDB::new(), GatedDB::new(LocalDB::new()), &write_queue).unwrap());
+	while db.add_user_event(&Cookie(String::from("filler")), user_tag(1), UserAction::VIEW).await.is_ok() {}
+	let app = actix_web::test::init_service(App::new()
+		.app_data(db.clone())
+		.route("/user_tags/batch", web::post().to(|db: web::Data<CachedDB<LocalDB, GatedDB>>, body: String| async move {
+			add_batch(db.as_ref(), &body).await
+		}))).await;
+	let body = [tag("first", "VIEW"), serde_json::json!({})].map(|item| item.to_string()).join("\n");
+	let request = actix_web::test::TestRequest::post().uri("/user_tags/batch").set_payload(body).to_request();
+	let response: serde_json::Value = actix_web::test::call_and_read_body_json(&app, request).await;
+	assert_eq!(response["accepted"], 0);
+	assert_eq!(failures(&response), vec![(0, 503, false), (1, 400, false)]);
+	db.remote_db().gate.add_permits(tokio::sync::Semaphore::MAX_PERMITS);
+}
+
+#[actix_web::test]
+async fn test_admin_endpoints() {
+	let state = AppState { database: Arc::new(BackendDB::Local(LocalDB::new())), max_tags: MAX_TAGS };
+	let app = actix_web::test::init_service(App::new()
+		.app_data(web::Data::new(state))
+		.service(add_user_tags)
+		.service(health)
+		.service(ready)
+		.service(status)).await;
+	let get = |uri: &str| actix_web::test::TestRequest::get().uri(uri).to_request();
+	
+	let response = actix_web::test::call_service(&app, get("/health")).await;
+	assert!(response.status().is_success());
+	assert_eq!(actix_web::test::read_body(response).await, "OK");
+	
+	let tag = serde_json::to_string(&api_user_tag("cookie", "PL", "VIEW")).unwrap();
+	let request = actix_web::test::TestRequest::post().uri("/user_tags").set_payload(tag).to_request();
+	assert!(actix_web::test::call_service(&app, request).await.status().is_success());
+	
+	let response: serde_json::Value = actix_web::test::call_and_read_body_json(&app, get("/ready")).await;
+	let sizes: Vec<&serde_json::Value> = response["dictionaries"].as_array().unwrap().iter().map(|fill| &fill["size"]).collect();
+	assert_eq!(sizes, vec![1; 5]);
+	
+	let response: serde_json::Value = actix_web::test::call_and_read_body_json(&app, get("/admin/status")).await;
+	assert_eq!(response["backend"], "local");
+	assert_eq!(response["cookies"], 1);
+	assert_eq!(response["aggregate_minutes"]["first"], "2022-03-22T12:15:00.000Z");
+	assert_eq!(response["aggregate_minutes"]["last"], "2022-03-22T12:15:00.000Z");
+	assert_eq!((&response["pending_writes"], &response["lost_writes"]), (&serde_json::json!(0), &serde_json::json!(0)));
+}